@@ -109,7 +109,7 @@ impl SimpleComponent for AppModel {
 				.launch(AlertSettings {
 					text: String::from("Интернет недоступен"),
 					secondary_text: Some(String::from(
-						"Часть функций может работать некорректно. Пожалуйста, проверьте подключение к интернету.",
+						"Лаунчер работает в автономном режиме, доступны только загруженные файлы. Пожалуйста, проверьте подключение к интернету.",
 					)),
 					confirm_label: String::from("Закрыть"),
 					cancel_label: None,
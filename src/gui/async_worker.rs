@@ -9,19 +9,22 @@
 //!
 //! It's controlled by [`super::app::AppModel`].
 
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use std::time::{Duration, Instant};
 
+use crate::storage::quota::quota_from_env;
+use crate::storage::scheduler::{DownloadMode, DownloadScheduler, JobError, Priority};
+use crate::storage::StorageError;
 use crate::structures::asset_index::{AssetIndex, AssetIndexError};
+use crate::utils::crypto::ContentHash;
+use crate::utils::progress::ProgressTracker;
 use crate::{storage::Storage, utils::net::NetClient};
 
 use super::app::AppMsg;
 use relm4::{ComponentSender, Worker};
 use tokio::runtime::Runtime;
 use tokio::task::JoinHandle;
-use tokio::task::JoinSet;
 
 /// Async worker model.
 ///
@@ -32,7 +35,8 @@ use tokio::task::JoinSet;
 /// It's controlled by [`super::app::AppModel`].
 pub struct AsyncWorkerModel {
 	client: Arc<NetClient>,
-	storage: Arc<Storage>,
+	scheduler: DownloadScheduler,
+	progress: Arc<ProgressTracker>,
 	runtime: Runtime,
 	download_assets_handle: Option<JoinHandle<Result<(), AssetIndexError>>>,
 }
@@ -42,16 +46,15 @@ pub struct AsyncWorkerModel {
 pub enum AsyncWorkerMsg {
 	/// Check connection to the internet.
 	///
-	/// Sends [`AppMsg::InternetUnavailable`] if connection is not available.
+	/// Switches the client to offline mode and sends
+	/// [`AppMsg::InternetUnavailable`] if connection is not available.
 	CheckConnection,
 	/// Download assets.
 	///
 	/// Sends [`AppMsg::SetProgressBarFraction`] and [`AppMsg::HideProgressBar`]
 	DownloadAssets,
-	/// Hello world command. Used for testing.
-	///
-	/// Sleeps for 1 second and then prints "Hello world from async worker".
-	HelloWorld,
+	/// Cancel all running and queued downloads.
+	CancelDownloads,
 }
 
 impl AsyncWorkerModel {
@@ -61,27 +64,60 @@ impl AsyncWorkerModel {
 		let result = client.get("https://ipfs.frsqr.xyz/").send().await;
 		if result.is_err() {
 			info!("Internet is unavailable");
+			client.set_offline(true);
 			let _ = sender.output(AppMsg::InternetUnavailable);
 		} else {
 			debug!("Internet is available");
+			client.set_offline(false);
 		}
 	}
 
 	/// Download assets.
 	async fn download_assets(
 		sender: ComponentSender<Self>,
-		storage: Arc<Storage>,
+		scheduler: DownloadScheduler,
+		progress: Arc<ProgressTracker>,
 	) -> Result<(), AssetIndexError> {
+		let storage = scheduler.storage();
 		// Download asset index
 		let hash = "0b32008ac3174dae0df463fc31f693b55c6deefc".to_string();
-		let index = AssetIndex::download_if_invalid(
-			&storage,
+		let index = AssetIndex::get_if_invalid(
+			&scheduler,
 			&hash,
 			"bafkreifpqxcl7lfwhpalqlxd7g4i5wpxtgu6ljxlapdistgm422qt2s3wa",
+			None,
 		)
 		.await?;
 		// Save asset index to object storage
-		index.save(&storage, &hash).await?;
+		index.save(storage, &hash).await?;
+		// Keep the index and assets from being evicted to satisfy the quota
+		storage
+			.add_pinned(
+				index
+					.get_assets()
+					.map(|asset| ContentHash::sha1(&asset.hash))
+					.chain([ContentHash::sha1(&hash)]),
+			)
+			.await?;
+
+		// Fail early if assets can't be downloaded
+		if storage.is_offline() {
+			let missing = index.missing_assets(storage).await?;
+			if !missing.is_empty() {
+				error!("{} assets are not available offline", missing.len());
+				let _ = sender.output(AppMsg::SetProgressBarText(Some(format!(
+					"{} assets are not available offline",
+					missing.len()
+				))));
+				let _ = sender.output(AppMsg::ShowProgressBar);
+				return Err(AssetIndexError::Assets(
+					missing
+						.into_iter()
+						.map(|hash| (hash.clone(), StorageError::MissingObject(hash).into()))
+						.collect(),
+				));
+			}
+		}
 
 		// Get total length of assets (for progress bar)
 		let length = index.objects.len() as f64;
@@ -93,24 +129,28 @@ impl AsyncWorkerModel {
 		))));
 		let _ = sender.output(AppMsg::ShowProgressBar);
 
-		let mut download_tasks = JoinSet::<()>::new();
+		progress.reset();
 
 		let mut last_bar_update = Instant::now();
 		let download_started = Instant::now();
 
-		let downloaded_assets_count = Arc::new(AtomicUsize::new(0));
+		let mut downloaded_assets_count = 0;
+		let mut failed: Vec<(String, JobError)> = Vec::new();
 
-		let mut try_update_bar = || {
+		let mut try_update_bar = |downloaded: usize| {
 			if last_bar_update.elapsed() > Duration::from_millis(10) {
 				// Update progress bar text
+				let snapshot = progress.snapshot();
 				let _ = sender.output(AppMsg::SetProgressBarText(Some(format!(
-					"Downloaded asset ({}/{})",
-					downloaded_assets_count.load(Ordering::SeqCst),
-					length as u64
+					"Downloaded asset ({}/{}), {:.1} MiB at {:.1} MiB/s",
+					downloaded,
+					length as u64,
+					snapshot.received as f64 / 1024.0 / 1024.0,
+					snapshot.throughput / 1024.0 / 1024.0
 				))));
 
 				// Update progress bar
-				let fraction = (downloaded_assets_count.load(Ordering::SeqCst) as f64) / length;
+				let fraction = (downloaded as f64) / length;
 				let _ = sender.output(AppMsg::SetProgressBarFraction(fraction));
 
 				// Renew last update time
@@ -118,36 +158,37 @@ impl AsyncWorkerModel {
 			}
 		};
 
-		// Iterate over assets
-		for asset in index.get_assets() {
-			// Spawn new task
-			let cloned_storage = storage.clone();
-			let cloned_downloaded_assets = downloaded_assets_count.clone();
-			// If there is alredy a lot of tasks, wait one for completing
-			if download_tasks.len() >= num_cpus::get() {
-				download_tasks.join_next().await.unwrap().unwrap();
-			}
-			download_tasks.spawn(async move {
-				let mut retries = 0;
-				while let Err(e) = asset.download_if_invalid(&cloned_storage).await {
-					retries += 1;
-					if retries > 10 {
-						error!("Failed to download {} asset. Error: {e}", asset.hash);
-						break;
-					}
-					debug!("Failed to download {} asset, retrying in 10ms. Retry: {retries}. Error: {e}", asset.hash);
-					tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-				}
-				cloned_downloaded_assets.fetch_add(1, Ordering::SeqCst);
-			});
+		// Submit all assets, the scheduler limits concurrent downloads
+		let handles: Vec<_> = index
+			.get_assets()
+			.map(|asset| {
+				scheduler.submit(
+					asset
+						.job()
+						.priority(Priority::Low)
+						.mode(DownloadMode::IfInvalid),
+				)
+			})
+			.collect();
 
-			try_update_bar();
+		// Wait for all jobs to finish
+		for handle in handles {
+			let hash = handle.hash().hex.clone();
+			// Failed downloads are already retried by the storage
+			match handle.wait().await {
+				Ok(_) => downloaded_assets_count += 1,
+				Err(e) => failed.push((hash, e)),
+			}
+			try_update_bar(downloaded_assets_count);
 		}
 
-		// Wait for all tasks to finish
-		while let Some(res) = download_tasks.join_next().await {
-			res.unwrap();
-			try_update_bar();
+		if !failed.is_empty() {
+			error!("Failed to download {} assets", failed.len());
+			let _ = sender.output(AppMsg::SetProgressBarText(Some(format!(
+				"Failed to download {} assets",
+				failed.len()
+			))));
+			return Err(AssetIndexError::Assets(failed));
 		}
 
 		info!(
@@ -168,11 +209,23 @@ impl Worker for AsyncWorkerModel {
 	type Output = AppMsg;
 
 	fn init(_init: Self::Init, _sender: ComponentSender<Self>) -> Self {
-		let client = Arc::new(NetClient::new());
+		let progress = Arc::new(ProgressTracker::new());
+		let runtime = Runtime::new().expect("Failed to create tokio runtime");
+		let mut client = NetClient::new();
+		client.set_progress_handler(Some(progress.clone()));
+		let client = Arc::new(client);
+		// Downloads use gateways until the local IPFS node is detected
+		runtime.spawn({
+			let client = client.clone();
+			async move { client.detect_kubo().await }
+		});
+		let mut storage = Storage::new(client.clone(), None);
+		storage.set_quota(quota_from_env());
 		Self {
-			client: client.clone(),
-			storage: Arc::new(Storage::new(client, None)),
-			runtime: Runtime::new().expect("Failed to create tokio runtime"),
+			client,
+			scheduler: DownloadScheduler::new(Arc::new(storage)),
+			progress,
+			runtime,
 			download_assets_handle: None,
 		}
 	}
@@ -198,16 +251,16 @@ impl Worker for AsyncWorkerModel {
 					None => {}
 				}
 				if self.download_assets_handle.is_none() {
-					self.download_assets_handle = Some(self.runtime.spawn(
-						AsyncWorkerModel::download_assets(sender, self.storage.clone()),
-					));
+					self.download_assets_handle =
+						Some(self.runtime.spawn(AsyncWorkerModel::download_assets(
+							sender,
+							self.scheduler.clone(),
+							self.progress.clone(),
+						)));
 				}
 			}
-			AsyncWorkerMsg::HelloWorld => {
-				self.runtime.spawn(async move {
-					tokio::time::sleep(Duration::from_secs(1)).await;
-					println!("Hello world from async worker");
-				});
+			AsyncWorkerMsg::CancelDownloads => {
+				self.scheduler.cancel_all();
 			}
 		}
 	}
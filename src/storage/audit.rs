@@ -0,0 +1,299 @@
+//! Integrity audit and repair of the object store.
+//!
+//! [`Storage::check_object`] verifies one object on request.
+//! [`Storage::audit`] verifies every object in the storage at once and
+//! [`Storage::repair`] downloads objects found corrupted or missing again.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::task::JoinSet;
+
+use super::scheduler::{DownloadJob, DownloadMode, DownloadScheduler, JobError};
+use super::{hash_file, Storage, StorageError};
+use crate::utils::crypto::ContentHash;
+
+/// Result of the storage audit.
+#[derive(Debug, Default, Clone)]
+pub struct AuditReport {
+	/// Objects which are valid and referenced by a saved index or version.
+	pub ok: Vec<ContentHash>,
+	/// Objects which contents don't match their names.
+	///
+	/// If quarantine was requested, these objects were moved to `quarantine/`.
+	pub corrupt: Vec<ContentHash>,
+	/// Objects which are valid, but not referenced by any saved index or version.
+	///
+	/// They can be removed by [`Storage::collect_garbage`].
+	pub orphan: Vec<ContentHash>,
+	/// Objects which are referenced by a saved index or version, but missing.
+	pub missing: Vec<ContentHash>,
+}
+
+impl AuditReport {
+	/// Check if no corrupted or missing objects were found.
+	pub fn is_healthy(&self) -> bool {
+		self.corrupt.is_empty() && self.missing.is_empty()
+	}
+}
+
+/// Result of the storage repair.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+	/// Objects which were downloaded again.
+	pub repaired: Vec<ContentHash>,
+	/// Objects which failed to download.
+	pub failed: Vec<(ContentHash, JobError)>,
+	/// Objects which can't be downloaded, because their IPFS path is unknown.
+	pub unknown_source: Vec<ContentHash>,
+}
+
+impl Storage {
+	/// Get quarantine path of the given object.
+	pub fn get_quarantine_path(&self, hash: &ContentHash) -> PathBuf {
+		self.storage_dir
+			.join("quarantine")
+			.join(hash.algorithm.name())
+			.join(&hash.hex)
+	}
+
+	/// Verify every object in the storage.
+	///
+	/// Objects are hashed in parallel, with at most one task per CPU.
+	/// If `quarantine` is `true`, corrupted objects are moved to `quarantine/`,
+	/// so they are not used anymore, but still available for inspection.
+	///
+	/// Returns [`StorageError::Unsupported`] if the backend doesn't keep objects
+	/// as local files.
+	pub async fn audit(&self, quarantine: bool) -> Result<AuditReport, StorageError> {
+		self.require_local("audit")?;
+		let references = self.referenced_objects().await?;
+		let mut report = AuditReport::default();
+		let mut found: HashSet<ContentHash> = HashSet::new();
+
+		let mut tasks: JoinSet<(ContentHash, PathBuf, Result<String, StorageError>)> =
+			JoinSet::new();
+		for (hash, path, _) in self.list_objects().await? {
+			// If there is already a lot of tasks, wait one for completing
+			if tasks.len() >= num_cpus::get() {
+				let (hash, path, result) = tasks.join_next().await.unwrap().unwrap();
+				if !self
+					.audit_object(&mut report, quarantine, hash.clone(), path, result)
+					.await
+				{
+					found.remove(&hash);
+				}
+			}
+			found.insert(hash.clone());
+			tasks.spawn(async move {
+				let result = hash_file(&path, hash.algorithm).await;
+				(hash, path, result)
+			});
+		}
+		while let Some(res) = tasks.join_next().await {
+			let (hash, path, result) = res.unwrap();
+			if !self
+				.audit_object(&mut report, quarantine, hash.clone(), path, result)
+				.await
+			{
+				found.remove(&hash);
+			}
+		}
+
+		for hash in references.keys() {
+			if !found.contains(hash) {
+				report.missing.push(hash.clone());
+			}
+		}
+		// Only valid objects are reported as orphans
+		for hash in std::mem::take(&mut report.ok) {
+			match references.contains_key(&hash) {
+				true => report.ok.push(hash),
+				false => report.orphan.push(hash),
+			}
+		}
+
+		info!(
+			"Storage audit finished: {} ok, {} corrupt, {} orphan, {} missing",
+			report.ok.len(),
+			report.corrupt.len(),
+			report.orphan.len(),
+			report.missing.len()
+		);
+		Ok(report)
+	}
+
+	/// Record result of hashing a single object in the report.
+	///
+	/// Objects which can't be read are recorded as corrupted. Returns `false`
+	/// if the object was removed while it was audited, so it's missing now.
+	async fn audit_object(
+		&self,
+		report: &mut AuditReport,
+		quarantine: bool,
+		hash: ContentHash,
+		path: PathBuf,
+		result: Result<String, StorageError>,
+	) -> bool {
+		match result {
+			Ok(hex) if hex == hash.hex => {
+				report.ok.push(hash);
+				return true;
+			}
+			Ok(_) => warn!("Object is corrupted: {}", hash),
+			Err(StorageError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+				debug!("Object was removed during audit: {}", hash);
+				return false;
+			}
+			Err(e) => warn!("Failed to read object {}: {}", hash, e),
+		}
+		if quarantine {
+			if let Err(e) = self.quarantine_object(&hash, &path).await {
+				warn!("Failed to quarantine object {}: {}", hash, e);
+			}
+		}
+		report.corrupt.push(hash);
+		true
+	}
+
+	/// Move the object file to `quarantine/`.
+	async fn quarantine_object(&self, hash: &ContentHash, path: &Path) -> std::io::Result<()> {
+		let quarantine_path = self.get_quarantine_path(hash);
+		tokio::fs::create_dir_all(quarantine_path.parent().unwrap()).await?;
+		tokio::fs::rename(path, &quarantine_path).await?;
+		self.invalidate_usage().await;
+		Ok(())
+	}
+
+	/// Download corrupted and missing objects from the audit report again.
+	///
+	/// IPFS paths and sizes of objects are taken from saved indexes and
+	/// versions.
+	/// Objects are downloaded in parallel with [`DownloadScheduler`].
+	pub async fn repair(
+		self: &Arc<Self>,
+		report: &AuditReport,
+	) -> Result<RepairReport, StorageError> {
+		let references = self.referenced_objects().await?;
+		let scheduler = DownloadScheduler::new(self.clone());
+		let mut repair_report = RepairReport::default();
+		let mut handles = Vec::new();
+		for hash in report.corrupt.iter().chain(report.missing.iter()) {
+			match references.get(hash) {
+				Some(Some((path, size))) => handles.push(scheduler.submit(
+					DownloadJob::new(hash.clone(), path, Some(*size)).mode(DownloadMode::Always),
+				)),
+				_ => repair_report.unknown_source.push(hash.clone()),
+			}
+		}
+		for handle in handles {
+			let hash = handle.hash().clone();
+			match handle.wait().await {
+				Ok(_) => repair_report.repaired.push(hash),
+				Err(e) => repair_report.failed.push((hash, e)),
+			}
+		}
+		Ok(repair_report)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage_with_client};
+	use crate::structures::asset_index::{AssetIndex, AssetIndexEntry};
+	use crate::utils::crypto::sha1_digest;
+	use crate::utils::net::NetClient;
+	use crate::utils::test_server::{Route, TestServer};
+	use std::collections::HashMap;
+
+	#[tokio::test]
+	async fn test_audit_and_repair() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/corrupt", Route::ok(b"corrupt"));
+		server.route("/ipfs/missing", Route::ok(b"missing"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let (_dir, storage) = temp_storage_with_client(client);
+		let storage = Arc::new(storage);
+
+		let ok = put_object(&storage, b"ok");
+		let orphan = put_object(&storage, b"orphan");
+		let corrupt = sha1_digest(b"corrupt");
+		std::fs::create_dir_all(storage.get_asset_path(&corrupt).parent().unwrap()).unwrap();
+		std::fs::write(storage.get_asset_path(&corrupt), b"garbage").unwrap();
+		let missing = sha1_digest(b"missing");
+
+		let mut objects = HashMap::new();
+		for (name, hash) in [("ok", &ok), ("corrupt", &corrupt), ("missing", &missing)] {
+			objects.insert(
+				name.to_string(),
+				AssetIndexEntry {
+					hash: hash.clone(),
+					path: name.to_string(),
+					size: name.len() as u64,
+				},
+			);
+		}
+		let index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+		let index_hash = put_object(&storage, serde_json::to_string(&index).unwrap().as_bytes());
+		index.save(&storage, &index_hash).await.unwrap();
+
+		let mut report = storage.audit(true).await.unwrap();
+		report.ok.sort_by(|a, b| a.hex.cmp(&b.hex));
+		let mut expected_ok = vec![ContentHash::sha1(&ok), ContentHash::sha1(&index_hash)];
+		expected_ok.sort_by(|a, b| a.hex.cmp(&b.hex));
+		assert_eq!(report.ok, expected_ok);
+		assert_eq!(report.orphan, vec![ContentHash::sha1(&orphan)]);
+		assert_eq!(report.corrupt, vec![ContentHash::sha1(&corrupt)]);
+		assert_eq!(report.missing, vec![ContentHash::sha1(&missing)]);
+		assert!(!report.is_healthy());
+		assert!(!storage.get_asset_path(&corrupt).exists());
+		assert!(storage
+			.get_quarantine_path(&ContentHash::sha1(&corrupt))
+			.exists());
+
+		let repair_report = storage.repair(&report).await.unwrap();
+		assert_eq!(repair_report.repaired.len(), 2);
+		assert!(repair_report.failed.is_empty());
+		assert!(storage.audit(false).await.unwrap().is_healthy());
+	}
+
+	#[tokio::test]
+	async fn test_audit_unreadable_object() {
+		let (_dir, storage) = temp_storage_with_client(NetClient::new());
+		let mut report = AuditReport::default();
+		let hash = ContentHash::sha1(&sha1_digest(b"object"));
+		let path = storage.get_object_path(&hash);
+
+		let vanished = std::io::Error::from(std::io::ErrorKind::NotFound);
+		assert!(
+			!storage
+				.audit_object(
+					&mut report,
+					true,
+					hash.clone(),
+					path.clone(),
+					Err(vanished.into())
+				)
+				.await
+		);
+		let unreadable = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+		assert!(
+			storage
+				.audit_object(
+					&mut report,
+					true,
+					hash.clone(),
+					path,
+					Err(unreadable.into())
+				)
+				.await
+		);
+		assert_eq!(report.corrupt, vec![hash]);
+	}
+}
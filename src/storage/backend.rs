@@ -0,0 +1,337 @@
+//! Storage backends.
+//!
+//! [`StorageBackend`] abstracts where objects are kept. [`FsBackend`] stores
+//! them as files in the content-addressed layout and is used by default,
+//! [`MemoryBackend`] keeps them in memory and is mostly useful for tests.
+//!
+//! Features which work with object files directly (resumable downloads, quota,
+//! garbage collection, audit, materialization and bundles) require objects to
+//! be available as local files, see [`StorageBackend::local_path`].
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::lock::{lock_exclusive, object_lock_path};
+use super::{hash_file, validate_hash, write_file_atomic, StorageError};
+use crate::utils::crypto::{ContentHash, HashAlgorithm};
+
+/// Object store used by [`Storage`](super::Storage).
+///
+/// Backends don't verify hashes on [`put`](StorageBackend::put), the caller is
+/// responsible for that.
+#[async_trait]
+pub trait StorageBackend: Debug + Send + Sync {
+	/// Read the whole object.
+	///
+	/// Returns [`StorageError::MissingObject`] if the object doesn't exist.
+	async fn get(&self, hash: &ContentHash) -> Result<Vec<u8>, StorageError>;
+
+	/// Store the object, replacing the existing one.
+	async fn put(&self, hash: &ContentHash, data: &[u8]) -> Result<(), StorageError>;
+
+	/// Check if the object exists.
+	async fn exists(&self, hash: &ContentHash) -> Result<bool, StorageError>;
+
+	/// Check if the object exists and has the correct hash.
+	async fn verify(&self, hash: &ContentHash) -> Result<bool, StorageError>;
+
+	/// List hash and size of all objects.
+	async fn list(&self) -> Result<Vec<(ContentHash, u64)>, StorageError>;
+
+	/// Get path of the object file, if the backend keeps objects as local files.
+	///
+	/// The file doesn't have to exist.
+	fn local_path(&self, _hash: &ContentHash) -> Option<PathBuf> {
+		None
+	}
+
+	/// Check if the backend keeps objects as local files.
+	///
+	/// Must return `true` if [`local_path`](StorageBackend::local_path)
+	/// returns paths.
+	fn is_local(&self) -> bool {
+		false
+	}
+}
+
+/// Backend storing objects as files in a local directory.
+///
+/// SHA-1 objects are stored directly in the root directory, like in the vanilla
+/// launcher. Objects hashed with other algorithms are stored in
+/// `<algorithm>/` subdirectories.
+///
+/// Objects are written to uniquely named temporary files, which are synced and
+/// renamed into place. If the backend has a locks directory, objects are
+/// written only while holding their lock files, like
+/// [`Storage`](super::Storage) does, see [`FsBackend::with_locks_dir`].
+#[derive(Debug, Clone)]
+pub struct FsBackend {
+	objects_dir: PathBuf,
+	locks_dir: Option<PathBuf>,
+}
+
+impl FsBackend {
+	/// Creates a new backend in the given directory.
+	pub fn new(objects_dir: PathBuf) -> Self {
+		Self {
+			objects_dir,
+			locks_dir: None,
+		}
+	}
+
+	/// Sets directory with object lock files, shared with the storage and
+	/// other processes.
+	pub fn with_locks_dir(mut self, locks_dir: PathBuf) -> Self {
+		self.locks_dir = Some(locks_dir);
+		self
+	}
+
+	/// Get directory with objects hashed with the given algorithm.
+	pub fn objects_dir(&self, algorithm: HashAlgorithm) -> PathBuf {
+		match algorithm {
+			HashAlgorithm::Sha1 => self.objects_dir.clone(),
+			_ => self.objects_dir.join(algorithm.name()),
+		}
+	}
+
+	/// Get object path.
+	///
+	/// The hash must be valid, see [`ContentHash::is_valid`]. Otherwise the
+	/// path may point outside of the directory. Methods of
+	/// [`StorageBackend`] return [`StorageError::InvalidHash`] for such hashes.
+	pub fn path(&self, hash: &ContentHash) -> PathBuf {
+		self.objects_dir(hash.algorithm)
+			.join(hash.hex.get(0..2).unwrap_or_default())
+			.join(&hash.hex)
+	}
+
+	/// List hash, path and size of all object files.
+	///
+	/// Partially downloaded objects are skipped.
+	pub async fn list_files(&self) -> Result<Vec<(ContentHash, PathBuf, u64)>, StorageError> {
+		let mut objects = Vec::new();
+		for algorithm in HashAlgorithm::ALL {
+			let objects_dir = self.objects_dir(algorithm);
+			if !objects_dir.exists() {
+				continue;
+			}
+			let mut shards = tokio::fs::read_dir(objects_dir).await?;
+			while let Some(shard) = shards.next_entry().await? {
+				// Skip directories of other algorithms
+				if !shard.file_type().await?.is_dir() || shard.file_name().len() != 2 {
+					continue;
+				}
+				let mut files = tokio::fs::read_dir(shard.path()).await?;
+				while let Some(file) = files.next_entry().await? {
+					let metadata = file.metadata().await?;
+					let hash = ContentHash::new(algorithm, &file.file_name().to_string_lossy());
+					if !metadata.is_file() || !hash.is_valid() {
+						continue;
+					}
+					objects.push((hash, file.path(), metadata.len()));
+				}
+			}
+		}
+		Ok(objects)
+	}
+}
+
+#[async_trait]
+impl StorageBackend for FsBackend {
+	async fn get(&self, hash: &ContentHash) -> Result<Vec<u8>, StorageError> {
+		validate_hash(hash)?;
+		match tokio::fs::read(self.path(hash)).await {
+			Ok(data) => Ok(data),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				Err(StorageError::MissingObject(hash.to_string()))
+			}
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	async fn put(&self, hash: &ContentHash, data: &[u8]) -> Result<(), StorageError> {
+		validate_hash(hash)?;
+		let path = self.path(hash);
+		tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+		let _lock = match &self.locks_dir {
+			Some(locks_dir) => {
+				let lock_path = object_lock_path(locks_dir, hash);
+				let (file, _) = tokio::task::spawn_blocking(move || lock_exclusive(&lock_path))
+					.await
+					.unwrap()?;
+				Some(file)
+			}
+			None => None,
+		};
+		write_file_atomic(&path, data).await?;
+		Ok(())
+	}
+
+	async fn exists(&self, hash: &ContentHash) -> Result<bool, StorageError> {
+		validate_hash(hash)?;
+		Ok(self.path(hash).exists())
+	}
+
+	async fn verify(&self, hash: &ContentHash) -> Result<bool, StorageError> {
+		validate_hash(hash)?;
+		let path = self.path(hash);
+		if !path.exists() {
+			return Ok(false);
+		}
+		Ok(hash_file(&path, hash.algorithm).await? == hash.hex)
+	}
+
+	async fn list(&self) -> Result<Vec<(ContentHash, u64)>, StorageError> {
+		Ok(self
+			.list_files()
+			.await?
+			.into_iter()
+			.map(|(hash, _, size)| (hash, size))
+			.collect())
+	}
+
+	fn local_path(&self, hash: &ContentHash) -> Option<PathBuf> {
+		Some(self.path(hash))
+	}
+
+	fn is_local(&self) -> bool {
+		true
+	}
+}
+
+/// Backend keeping objects in memory.
+///
+/// Objects are lost when the backend is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+	objects: Mutex<HashMap<ContentHash, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+	/// Creates a new empty backend.
+	pub fn new() -> Self {
+		Self::default()
+	}
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+	async fn get(&self, hash: &ContentHash) -> Result<Vec<u8>, StorageError> {
+		validate_hash(hash)?;
+		self.objects
+			.lock()
+			.unwrap()
+			.get(hash)
+			.cloned()
+			.ok_or_else(|| StorageError::MissingObject(hash.to_string()))
+	}
+
+	async fn put(&self, hash: &ContentHash, data: &[u8]) -> Result<(), StorageError> {
+		validate_hash(hash)?;
+		self.objects
+			.lock()
+			.unwrap()
+			.insert(hash.clone(), data.to_vec());
+		Ok(())
+	}
+
+	async fn exists(&self, hash: &ContentHash) -> Result<bool, StorageError> {
+		validate_hash(hash)?;
+		Ok(self.objects.lock().unwrap().contains_key(hash))
+	}
+
+	async fn verify(&self, hash: &ContentHash) -> Result<bool, StorageError> {
+		validate_hash(hash)?;
+		Ok(match self.objects.lock().unwrap().get(hash) {
+			Some(data) => ContentHash::digest(hash.algorithm, data) == *hash,
+			None => false,
+		})
+	}
+
+	async fn list(&self) -> Result<Vec<(ContentHash, u64)>, StorageError> {
+		Ok(self
+			.objects
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(hash, data)| (hash.clone(), data.len() as u64))
+			.collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::fs::tests::TempDir;
+
+	async fn check_backend(backend: &dyn StorageBackend) {
+		let hash = ContentHash::digest(HashAlgorithm::Sha256, b"object");
+		assert!(!backend.exists(&hash).await.unwrap());
+		assert!(!backend.verify(&hash).await.unwrap());
+		assert!(matches!(
+			backend.get(&hash).await,
+			Err(StorageError::MissingObject(_))
+		));
+
+		backend.put(&hash, b"object").await.unwrap();
+		assert!(backend.exists(&hash).await.unwrap());
+		assert!(backend.verify(&hash).await.unwrap());
+		assert_eq!(backend.get(&hash).await.unwrap(), b"object");
+		assert_eq!(backend.list().await.unwrap(), vec![(hash.clone(), 6)]);
+
+		backend.put(&hash, b"corrupted").await.unwrap();
+		assert!(backend.exists(&hash).await.unwrap());
+		assert!(!backend.verify(&hash).await.unwrap());
+
+		let invalid = ContentHash::sha1("../../evil");
+		assert!(matches!(
+			backend.put(&invalid, b"evil").await,
+			Err(StorageError::InvalidHash(_))
+		));
+		assert!(matches!(
+			backend.get(&invalid).await,
+			Err(StorageError::InvalidHash(_))
+		));
+		assert!(matches!(
+			backend.exists(&invalid).await,
+			Err(StorageError::InvalidHash(_))
+		));
+		assert!(matches!(
+			backend.verify(&invalid).await,
+			Err(StorageError::InvalidHash(_))
+		));
+		assert_eq!(backend.list().await.unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_memory_backend() {
+		let backend = MemoryBackend::new();
+		check_backend(&backend).await;
+		assert!(!backend.is_local());
+	}
+
+	#[tokio::test]
+	async fn test_fs_backend() {
+		let dir = TempDir::new();
+		let backend = FsBackend::new(dir.path().to_path_buf());
+		check_backend(&backend).await;
+		let hash = ContentHash::digest(HashAlgorithm::Sha256, b"object");
+		assert!(backend
+			.local_path(&hash)
+			.unwrap()
+			.starts_with(dir.join("sha256")));
+		drop(dir);
+
+		let dir = TempDir::new();
+		let backend = FsBackend::new(dir.join("objects")).with_locks_dir(dir.join("locks"));
+		check_backend(&backend).await;
+		assert!(dir.join("locks").read_dir().unwrap().next().is_some());
+		// Only the object is left in its directory
+		let path = backend.local_path(&hash).unwrap();
+		assert_eq!(path.parent().unwrap().read_dir().unwrap().count(), 1);
+	}
+}
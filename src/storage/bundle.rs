@@ -0,0 +1,361 @@
+//! Offline bundles.
+//!
+//! Bundle is a zip archive with everything one version needs: the version
+//! manifest, the asset index, all assets, libraries and the main jar. It's
+//! used to install versions on machines without internet access.
+//!
+//! Bundle layout:
+//!
+//! - `manifest.json` - version manifest.
+//! - `objects/<sha1>` - objects, named by their SHA-1 hash.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::{validate_hash, write_file_atomic, Storage, StorageError};
+use crate::structures::asset_index::AssetIndex;
+use crate::structures::version_manifest::VersionManifest;
+use crate::utils::crypto::{generate_random_string, ContentHash};
+
+/// Name of the version manifest file in the bundle.
+const MANIFEST_NAME: &str = "manifest.json";
+/// Directory with objects in the bundle.
+const OBJECTS_DIR: &str = "objects/";
+
+impl Storage {
+	/// Export the version with all its objects to the bundle at `dest`.
+	///
+	/// The asset index, main jar, assets and libraries required on the current
+	/// OS must be in the storage. Native libraries of other OSes are included
+	/// only if they are stored.
+	///
+	/// Returns number of exported objects.
+	///
+	/// It does blocking IO, so call it with [`tokio::task::spawn_blocking`]
+	/// from async code.
+	pub fn export_bundle(
+		&self,
+		manifest: &VersionManifest,
+		dest: &Path,
+	) -> Result<usize, StorageError> {
+		let required = self.required_objects(manifest)?;
+		let optional: HashSet<String> = manifest
+			.all_artifacts()
+			.into_iter()
+			.map(|artifact| artifact.sha1)
+			.filter(|hash| !required.contains(hash))
+			.collect();
+
+		let mut zip = zip::ZipWriter::new(std::fs::File::create(dest)?);
+		let options = zip::write::FileOptions::default().large_file(true);
+		zip.start_file(MANIFEST_NAME, options)?;
+		zip.write_all(serde_json::to_string(manifest)?.as_bytes())?;
+
+		let mut exported = 0;
+		for (hash, is_required) in required
+			.iter()
+			.map(|hash| (hash, true))
+			.chain(optional.iter().map(|hash| (hash, false)))
+		{
+			let path = self.get_asset_path(hash);
+			if !path.exists() {
+				if is_required {
+					return Err(StorageError::MissingObject(hash.clone()));
+				}
+				continue;
+			}
+			zip.start_file(format!("{OBJECTS_DIR}{hash}"), options)?;
+			io::copy(&mut std::fs::File::open(path)?, &mut zip)?;
+			exported += 1;
+		}
+		zip.finish()?;
+
+		info!("Exported {} objects to {}", exported, dest.display());
+		Ok(exported)
+	}
+
+	/// Import the bundle at `bundle` into the storage.
+	///
+	/// Hash of every object is verified before it's stored. The asset index is
+	/// parsed, and every object the version needs on the current OS must be
+	/// either in the bundle or already in the storage. If any object is
+	/// corrupted or missing, or the manifest has a product UID or version which
+	/// can't be used as a file name, nothing from the bundle is saved.
+	///
+	/// The version manifest and the asset index are saved, so the imported
+	/// objects are not garbage collected.
+	pub async fn import_bundle(&self, bundle: &Path) -> Result<VersionManifest, StorageError> {
+		let bundle = bundle.to_path_buf();
+		let unpack_dir = self
+			.storage_dir
+			.join("tmp")
+			.join(generate_random_string(16));
+		let result = self.import_unpacked(bundle, &unpack_dir).await;
+		if unpack_dir.exists() {
+			tokio::fs::remove_dir_all(&unpack_dir).await?;
+		}
+		let (manifest, index, imported) = result?;
+
+		if let (Some(artifact), Some(index)) = (&manifest.asset_index, index) {
+			write_file_atomic(&self.get_index_path(&artifact.sha1), &index).await?;
+		}
+		let manifest_path = self.get_version_path(&manifest.product_uid, &manifest.version);
+		tokio::fs::create_dir_all(manifest_path.parent().unwrap()).await?;
+		write_file_atomic(&manifest_path, serde_json::to_string(&manifest)?.as_bytes()).await?;
+
+		info!(
+			"Imported {} {} with {} objects",
+			manifest.product_uid, manifest.version, imported
+		);
+		Ok(manifest)
+	}
+
+	/// Unpack the bundle into `unpack_dir`, check it and move verified objects
+	/// into place.
+	///
+	/// Returns the manifest, the asset index and number of imported objects.
+	async fn import_unpacked(
+		&self,
+		bundle: PathBuf,
+		unpack_dir: &Path,
+	) -> Result<(VersionManifest, Option<Vec<u8>>, usize), StorageError> {
+		let dir = unpack_dir.to_path_buf();
+		let (manifest, objects) = tokio::task::spawn_blocking(move || unpack_bundle(&bundle, &dir))
+			.await
+			.unwrap()?;
+		let index = self.check_bundle(&manifest, &objects).await?;
+
+		for (hash, path) in &objects {
+			let dest_path = self.get_object_path(hash);
+			tokio::fs::create_dir_all(dest_path.parent().unwrap()).await?;
+			let _lock = self.lock_object(hash).await?;
+			self.commit_object(path, &dest_path).await?;
+		}
+		Ok((manifest, index, objects.len()))
+	}
+
+	/// Check that the unpacked bundle is complete and can be saved.
+	///
+	/// Returns contents of the asset index, if the version has one.
+	async fn check_bundle(
+		&self,
+		manifest: &VersionManifest,
+		objects: &[(ContentHash, PathBuf)],
+	) -> Result<Option<Vec<u8>>, StorageError> {
+		for name in [&manifest.product_uid, &manifest.version] {
+			if !is_file_name(name) {
+				return Err(StorageError::InvalidName(name.clone()));
+			}
+		}
+		let unpacked: HashMap<&str, &Path> = objects
+			.iter()
+			.map(|(hash, path)| (hash.hex.as_str(), path.as_path()))
+			.collect();
+		let mut required: HashSet<String> = manifest
+			.get_artifacts()
+			.into_iter()
+			.map(|artifact| artifact.sha1)
+			.collect();
+
+		let mut index = None;
+		if let Some(artifact) = &manifest.asset_index {
+			let hash = ContentHash::sha1(&artifact.sha1);
+			validate_hash(&hash)?;
+			let data = match unpacked.get(hash.hex.as_str()) {
+				Some(path) => tokio::fs::read(path).await?,
+				None => self.read_object(&hash).await?,
+			};
+			let asset_index: AssetIndex = serde_json::from_slice(&data)?;
+			required.extend(asset_index.get_assets().map(|asset| asset.hash));
+			index = Some(data);
+		}
+
+		for hash in required {
+			let object = ContentHash::sha1(&hash);
+			validate_hash(&object)?;
+			if !unpacked.contains_key(hash.as_str()) && !self.backend.exists(&object).await? {
+				return Err(StorageError::MissingObject(hash));
+			}
+		}
+		Ok(index)
+	}
+}
+
+/// Check that the name can be used as a file or directory name.
+fn is_file_name(name: &str) -> bool {
+	!name.is_empty() && name != "." && !name.contains("..") && !name.contains(['/', '\\', '\0'])
+}
+
+/// Unpack objects from the bundle to `unpack_dir`, verifying their hashes.
+///
+/// Objects are not moved into the storage here, so nothing is stored if any
+/// of them is corrupted.
+fn unpack_bundle(
+	bundle: &Path,
+	unpack_dir: &Path,
+) -> Result<(VersionManifest, Vec<(ContentHash, PathBuf)>), StorageError> {
+	let mut archive = zip::ZipArchive::new(std::fs::File::open(bundle)?)?;
+	let manifest: VersionManifest = serde_json::from_reader(archive.by_name(MANIFEST_NAME)?)?;
+	std::fs::create_dir_all(unpack_dir)?;
+
+	let mut objects = Vec::new();
+	for i in 0..archive.len() {
+		let mut file = archive.by_index(i)?;
+		let hash = match file.name().strip_prefix(OBJECTS_DIR) {
+			Some(name) => ContentHash::sha1(name),
+			None => continue,
+		};
+		if !hash.is_valid() {
+			return Err(StorageError::InvalidHash(file.name().to_string()));
+		}
+		let path = unpack_dir.join(&hash.hex);
+		let mut writer = std::fs::File::create(&path)?;
+		let mut hasher = hash.algorithm.hasher();
+		let mut buffer = vec![0; 128 * 1024];
+		loop {
+			let n = file.read(&mut buffer)?;
+			if n == 0 {
+				break;
+			}
+			hasher.update(&buffer[..n]);
+			writer.write_all(&buffer[..n])?;
+		}
+		writer.sync_all()?;
+		let actual = hasher.finalize();
+		if actual != hash.hex {
+			return Err(StorageError::HashMismatch(hash.hex, actual));
+		}
+		objects.push((hash, path));
+	}
+	Ok((manifest, objects))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage, test_manifest};
+	use crate::structures::asset_index::{AssetIndex, AssetIndexEntry};
+	use crate::utils::crypto::sha1_digest;
+	use std::collections::HashMap;
+
+	#[tokio::test]
+	async fn test_export_import_bundle() {
+		let (_source_dir, source) = temp_storage();
+		let sound = put_object(&source, b"sound");
+		let mut objects = HashMap::new();
+		objects.insert(
+			"minecraft/sounds/step.ogg".to_string(),
+			AssetIndexEntry {
+				hash: sound.clone(),
+				path: "CID".to_string(),
+				size: 5,
+			},
+		);
+		let asset_index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+		let asset_index_hash = put_object(
+			&source,
+			serde_json::to_string(&asset_index).unwrap().as_bytes(),
+		);
+		let client = put_object(&source, b"client");
+		let library = put_object(&source, b"library");
+		let manifest = test_manifest(&asset_index_hash, &client, &library);
+
+		let bundle = source.storage_dir().join("bundle.zip");
+		assert_eq!(source.export_bundle(&manifest, &bundle).unwrap(), 4);
+
+		let (_target_dir, target) = temp_storage();
+		let imported = target.import_bundle(&bundle).await.unwrap();
+		assert_eq!(imported.version, "1.12.2");
+		for hash in [&sound, &asset_index_hash, &client, &library] {
+			assert!(target.check_asset(hash).await.unwrap());
+		}
+		assert!(target.get_index_path(&asset_index_hash).exists());
+		assert!(target.get_version_path("net.minecraft", "1.12.2").exists());
+		// Imported objects are referenced by the saved manifest and index
+		assert!(target
+			.collect_garbage(true)
+			.await
+			.unwrap()
+			.removed
+			.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_import_corrupted_bundle() {
+		let (_dir, storage) = temp_storage();
+		let client = sha1_digest(b"client");
+		let manifest = test_manifest(&sha1_digest(b"index"), &client, &client);
+
+		let bundle = storage.storage_dir().join("bundle.zip");
+		let mut zip = zip::ZipWriter::new(std::fs::File::create(&bundle).unwrap());
+		let options = zip::write::FileOptions::default();
+		zip.start_file(MANIFEST_NAME, options).unwrap();
+		zip.write_all(serde_json::to_string(&manifest).unwrap().as_bytes())
+			.unwrap();
+		zip.start_file(format!("{OBJECTS_DIR}{client}"), options)
+			.unwrap();
+		zip.write_all(b"tampered").unwrap();
+		zip.finish().unwrap();
+
+		let result = storage.import_bundle(&bundle).await;
+		assert!(matches!(result, Err(StorageError::HashMismatch(_, _))));
+		assert!(storage.list_objects().await.unwrap().is_empty());
+		let tmp_dir = storage.storage_dir().join("tmp");
+		assert!(tmp_dir.read_dir().unwrap().next().is_none());
+	}
+
+	/// Write the bundle with the given manifest and objects.
+	fn write_bundle(path: &Path, manifest: &VersionManifest, objects: &[&[u8]]) {
+		let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+		let options = zip::write::FileOptions::default();
+		zip.start_file(MANIFEST_NAME, options).unwrap();
+		zip.write_all(serde_json::to_string(manifest).unwrap().as_bytes())
+			.unwrap();
+		for data in objects {
+			zip.start_file(format!("{OBJECTS_DIR}{}", sha1_digest(data)), options)
+				.unwrap();
+			zip.write_all(data).unwrap();
+		}
+		zip.finish().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_import_invalid_bundle() {
+		let (_dir, storage) = temp_storage();
+		let bundle = storage.storage_dir().join("bundle.zip");
+		let index = br#"{"objects": {}}"#;
+		let client = sha1_digest(b"client");
+		let library = sha1_digest(b"library");
+		let objects: [&[u8]; 3] = [index, b"client", b"library"];
+
+		let mut manifest = test_manifest(&sha1_digest(index), &client, &library);
+		manifest.product_uid = "../../evil".to_string();
+		write_bundle(&bundle, &manifest, &objects);
+		let result = storage.import_bundle(&bundle).await;
+		assert!(matches!(result, Err(StorageError::InvalidName(_))));
+
+		let manifest = test_manifest("../../evil", &client, &library);
+		write_bundle(&bundle, &manifest, &objects);
+		let result = storage.import_bundle(&bundle).await;
+		assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+
+		// Asset index is missing
+		let manifest = test_manifest(&sha1_digest(index), &client, &library);
+		write_bundle(&bundle, &manifest, &objects[1..]);
+		let result = storage.import_bundle(&bundle).await;
+		assert!(matches!(result, Err(StorageError::MissingObject(_))));
+
+		// Asset index can't be parsed
+		let manifest = test_manifest(&sha1_digest(b"index"), &client, &library);
+		write_bundle(&bundle, &manifest, &[b"index", b"client", b"library"]);
+		let result = storage.import_bundle(&bundle).await;
+		assert!(matches!(result, Err(StorageError::ParseError(_))));
+
+		assert!(storage.list_objects().await.unwrap().is_empty());
+		assert!(!storage.get_version_path("net.minecraft", "1.12.2").exists());
+	}
+}
@@ -0,0 +1,666 @@
+//! Import of IPFS DAGs from CAR archives.
+//!
+//! A whole directory of objects, like all assets of an asset index, can be
+//! downloaded from a gateway in one request as a CAR archive, or loaded from a
+//! local `.car` file. Every block is verified against its CID, and every file
+//! found in the DAG is stored by its SHA-1 hash.
+//!
+//! Files are reassembled while the archive is received, so blocks must be in
+//! depth-first order starting with the root: every directory before its
+//! entries and every file node before its chunks. Gateways are asked for this
+//! order, and `ipfs dag export` produces it too. Blocks which are not linked
+//! from already received ones are not reachable from the root, and are
+//! ignored.
+//!
+//! Received data of incomplete files is held in memory until it can be
+//! written in order. Files of local backends are written to a temporary file in
+//! `tmp/` once [`SPILL_SIZE`] bytes can be written, so only chunks received out
+//! of order are held, up to [`MAX_PENDING_SIZE`] bytes.
+
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{Storage, StorageError};
+use crate::utils::car::{Block, CarDecoder, CarError, NodeKind, UnixFsNode};
+use crate::utils::cid::{Cid, DAG_PB, RAW};
+use crate::utils::crypto::{generate_random_string, ContentHash, HashAlgorithm, Hasher};
+use crate::utils::net::{part_path, NetworkError};
+
+/// Result of the CAR import.
+#[derive(Debug, Default, Clone)]
+pub struct CarImportReport {
+	/// Hashes of imported objects.
+	pub imported: Vec<ContentHash>,
+	/// Hashes of objects which were already in the storage.
+	pub skipped: Vec<ContentHash>,
+	/// Number of files which chunks are missing from the archive.
+	pub incomplete: usize,
+}
+
+/// Maximum size of data held in memory for files which are not complete yet.
+pub const MAX_PENDING_SIZE: usize = 256 * 1024 * 1024;
+
+/// Size of data which can be written in order from which a file is written to
+/// disk while it's received.
+pub const SPILL_SIZE: usize = 4 * 1024 * 1024;
+
+/// Part of a file being reassembled.
+#[derive(Debug)]
+enum Part {
+	/// Received data.
+	Data(Vec<u8>),
+	/// Block which is not received yet.
+	Pending(Cid),
+}
+
+/// File being reassembled.
+#[derive(Debug, Default)]
+struct PendingFile {
+	parts: Vec<Part>,
+	/// Size of the received data held in memory.
+	size: usize,
+}
+
+impl PendingFile {
+	/// Size of the data received at the start of the file, which can be
+	/// written.
+	fn ready_size(&self) -> usize {
+		self.parts
+			.iter()
+			.map_while(|part| match part {
+				Part::Data(data) => Some(data.len()),
+				Part::Pending(_) => None,
+			})
+			.sum()
+	}
+}
+
+/// Temporary file removed when dropped, unless it was moved in the meantime.
+#[derive(Debug)]
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		match std::fs::remove_file(&self.0) {
+			Ok(()) => {}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => warn!("Failed to remove {}: {}", self.0.display(), e),
+		}
+	}
+}
+
+/// File being written to disk while it's received, see [`SPILL_SIZE`].
+#[derive(Debug)]
+struct Spill {
+	path: TempFile,
+	file: tokio::fs::File,
+	hasher: Hasher,
+}
+
+impl Spill {
+	/// Create a new temporary file in `dir`.
+	async fn create(dir: &Path) -> std::io::Result<Self> {
+		tokio::fs::create_dir_all(dir).await?;
+		let path = dir.join(format!("car-{}.part", generate_random_string(16)));
+		let file = tokio::fs::File::create(&path).await?;
+		Ok(Self {
+			path: TempFile(path),
+			file,
+			hasher: HashAlgorithm::Sha1.hasher(),
+		})
+	}
+
+	/// Append data to the file.
+	async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+		self.hasher.update(data);
+		self.file.write_all(data).await
+	}
+}
+
+/// State of the import of a single archive.
+#[derive(Debug, Default)]
+struct CarImport {
+	decoder: CarDecoder,
+	/// Expected root of the DAG, any root from the header if `None`.
+	root: Option<Cid>,
+	/// Whether the first block was received.
+	started: bool,
+	/// Blocks which are whole files or directories: the root and entries of
+	/// received directories.
+	entries: HashSet<Cid>,
+	/// Files which chunks are not received yet.
+	files: HashMap<usize, PendingFile>,
+	/// Ids of files waiting for each block.
+	waiting: HashMap<Cid, Vec<usize>>,
+	next_id: usize,
+	/// Total size of data held in memory for incomplete files.
+	pending_size: usize,
+	/// Files which are written to disk.
+	spills: HashMap<usize, Spill>,
+	report: CarImportReport,
+}
+
+impl CarImport {
+	/// Creates the import of the DAG with the given root.
+	fn new(root: Option<Cid>) -> Self {
+		Self {
+			root,
+			..Default::default()
+		}
+	}
+
+	/// Check that the first block is the root of the DAG.
+	fn start(&mut self, block: &Block) -> Result<(), CarError> {
+		let roots = self.decoder.roots().unwrap_or_default();
+		let is_root = match &self.root {
+			Some(root) if !roots.contains(root) => {
+				return Err(CarError::UnexpectedRoot(root.to_string()))
+			}
+			Some(root) => *root == block.cid,
+			None => roots.contains(&block.cid),
+		};
+		if !is_root {
+			return Err(CarError::RootNotFirst(block.cid.to_string()));
+		}
+		self.started = true;
+		self.entries.insert(block.cid.clone());
+		Ok(())
+	}
+
+	/// Handle the block and return ids of files it completes.
+	///
+	/// Data of the completed files must be taken with
+	/// [`CarImport::take_ready`].
+	fn add_block(&mut self, block: Block) -> Result<Vec<usize>, CarError> {
+		if !self.started {
+			self.start(&block)?;
+		}
+		let mut ids = self.waiting.remove(&block.cid).unwrap_or_default();
+		let is_entry = self.entries.remove(&block.cid);
+		if ids.is_empty() && !is_entry {
+			debug!(
+				"Skipping CAR block unreachable from the root: {}",
+				block.cid
+			);
+			return Ok(Vec::new());
+		}
+
+		let (data, links) = match block.cid.codec {
+			RAW => (block.data, Vec::new()),
+			DAG_PB => {
+				let node = UnixFsNode::decode(&block.data)?;
+				match node.kind {
+					NodeKind::File | NodeKind::Raw => (node.data, node.links),
+					NodeKind::Directory | NodeKind::HamtShard => {
+						if is_entry {
+							self.entries.extend(node.links);
+						}
+						return Ok(Vec::new());
+					}
+					_ => return Ok(Vec::new()),
+				}
+			}
+			_ => return Ok(Vec::new()),
+		};
+		if is_entry {
+			let id = self.next_id;
+			self.next_id += 1;
+			let file = PendingFile {
+				parts: vec![Part::Pending(block.cid.clone())],
+				size: 0,
+			};
+			self.files.insert(id, file);
+			ids.push(id);
+		}
+		let parts = || {
+			std::iter::once(Part::Data(data.clone()))
+				.chain(links.iter().cloned().map(Part::Pending))
+				.collect::<Vec<_>>()
+		};
+
+		// File may contain the same chunk several times
+		ids.sort_unstable();
+		ids.dedup();
+		let mut completed = Vec::new();
+		for id in ids {
+			let file = self.files.get_mut(&id).unwrap();
+			let mut received = 0;
+			file.parts = file
+				.parts
+				.drain(..)
+				.flat_map(|part| match part {
+					Part::Pending(cid) if cid == block.cid => {
+						received += data.len();
+						parts()
+					}
+					part => vec![part],
+				})
+				.collect();
+			file.size += received;
+			self.pending_size += received;
+			for link in &links {
+				self.waiting.entry(link.clone()).or_default().push(id);
+			}
+			if file.parts.iter().all(|part| matches!(part, Part::Data(_))) {
+				completed.push(id);
+			}
+		}
+		Ok(completed)
+	}
+
+	/// Take data received at the start of the file, which can be written.
+	///
+	/// Completed files are forgotten.
+	fn take_ready(&mut self, id: usize) -> Vec<u8> {
+		let file = self.files.get_mut(&id).unwrap();
+		let count = file
+			.parts
+			.iter()
+			.take_while(|part| matches!(part, Part::Data(_)))
+			.count();
+		let data: Vec<u8> = file
+			.parts
+			.drain(..count)
+			.flat_map(|part| match part {
+				Part::Data(data) => data,
+				Part::Pending(_) => unreachable!(),
+			})
+			.collect();
+		file.size -= data.len();
+		self.pending_size -= data.len();
+		if file.parts.is_empty() {
+			self.files.remove(&id);
+		}
+		data
+	}
+
+	/// Get ids of files with data which should be written to disk.
+	fn spillable(&self) -> Vec<usize> {
+		self.files
+			.iter()
+			.filter(|(id, file)| {
+				let ready = file.ready_size();
+				ready >= SPILL_SIZE || (ready > 0 && self.spills.contains_key(id))
+			})
+			.map(|(id, _)| *id)
+			.collect()
+	}
+
+	/// Check that the data held in memory doesn't exceed [`MAX_PENDING_SIZE`].
+	fn check_size(&self) -> Result<(), CarError> {
+		if self.pending_size > MAX_PENDING_SIZE {
+			return Err(CarError::TooLarge(MAX_PENDING_SIZE));
+		}
+		Ok(())
+	}
+}
+
+impl Storage {
+	/// Import all files from the local CAR archive at `path`.
+	///
+	/// `cid` must be the root of the DAG, like for [`Storage::download_car`].
+	/// Archives of other DAGs are rejected with [`CarError::UnexpectedRoot`].
+	///
+	/// See [module docs](self) for details.
+	pub async fn import_car(
+		&self,
+		path: &Path,
+		cid: &str,
+	) -> Result<CarImportReport, StorageError> {
+		let root: Cid = cid.parse().map_err(CarError::from)?;
+		let mut file = tokio::fs::File::open(path).await?;
+		let mut import = CarImport::new(Some(root.clone()));
+		let mut buffer = vec![0; 64 * 1024];
+		loop {
+			let n = file.read(&mut buffer).await?;
+			if n == 0 {
+				break;
+			}
+			import.decoder.push(&buffer[..n]);
+			self.import_blocks(&mut import).await?;
+		}
+		self.finish_import(import, Some(&root))
+	}
+
+	/// Download the IPFS DAG as a CAR archive and import all its files.
+	///
+	/// `cid` must be the root of the DAG. Failed download is retried according
+	/// to the client's [`RetryPolicy`](crate::utils::retry::RetryPolicy), files
+	/// imported by the previous attempts are skipped. The DAG is pinned on the
+	/// local IPFS node in the background, if the client has one, see
+	/// [`NetClient::pin_ipfs_in_background`](crate::utils::net::NetClient::pin_ipfs_in_background).
+	///
+	/// See [module docs](self) for details.
+	pub async fn download_car(&self, cid: &str) -> Result<CarImportReport, StorageError> {
+		if self.is_offline() {
+			return Err(NetworkError::Offline.into());
+		}
+		let root: Cid = cid.parse().map_err(CarError::from)?;
+		let report = self
+			.client
+			.retry_policy()
+			.retry(|| self.try_download_car(cid, &root))
+			.await?;
+		self.client.pin_ipfs_in_background(vec![cid.to_string()]);
+		Ok(report)
+	}
+
+	/// Single attempt of [`Storage::download_car`].
+	async fn try_download_car(
+		&self,
+		cid: &str,
+		root: &Cid,
+	) -> Result<CarImportReport, StorageError> {
+		debug!("Downloading CAR archive: {}", cid);
+		let mut stream = self.client.stream_ipfs_car(cid).await?;
+		let mut import = CarImport::new(Some(root.clone()));
+		while let Some(chunk) = stream.chunk().await? {
+			import.decoder.push(&chunk);
+			self.import_blocks(&mut import).await?;
+		}
+		self.finish_import(import, Some(root))
+	}
+
+	/// Store files completed by the decoded blocks.
+	async fn import_blocks(&self, import: &mut CarImport) -> Result<(), StorageError> {
+		while let Some(block) = import.decoder.next_block()? {
+			for id in import.add_block(block)? {
+				let data = import.take_ready(id);
+				let (hash, imported) = match import.spills.remove(&id) {
+					Some(mut spill) => {
+						spill.write(&data).await?;
+						self.store_spill(spill).await?
+					}
+					None => {
+						let hash = ContentHash::digest(HashAlgorithm::Sha1, &data);
+						let imported = self.store_object(&hash, &data).await?;
+						(hash, imported)
+					}
+				};
+				match imported {
+					true => import.report.imported.push(hash),
+					false => import.report.skipped.push(hash),
+				}
+			}
+			// Other backends take whole objects anyway
+			if self.backend.is_local() {
+				for id in import.spillable() {
+					let data = import.take_ready(id);
+					let spill = match import.spills.entry(id) {
+						Entry::Occupied(entry) => entry.into_mut(),
+						Entry::Vacant(entry) => {
+							entry.insert(Spill::create(&self.storage_dir.join("tmp")).await?)
+						}
+					};
+					spill.write(&data).await?;
+				}
+			}
+			import.check_size()?;
+		}
+		Ok(())
+	}
+
+	/// Check that the archive is complete and has the expected root.
+	fn finish_import(
+		&self,
+		import: CarImport,
+		root: Option<&Cid>,
+	) -> Result<CarImportReport, StorageError> {
+		import.decoder.finish()?;
+		if let Some(root) = root {
+			let roots = import.decoder.roots().unwrap_or_default();
+			if !roots.contains(root) {
+				return Err(CarError::UnexpectedRoot(root.to_string()).into());
+			}
+		}
+		let mut report = import.report;
+		report.incomplete = import.files.len();
+		if report.incomplete > 0 {
+			warn!("{} files are incomplete in CAR archive", report.incomplete);
+		}
+		info!(
+			"Imported {} objects from CAR archive, {} skipped",
+			report.imported.len(),
+			report.skipped.len()
+		);
+		Ok(report)
+	}
+
+	/// Store verified file contents as an object.
+	///
+	/// Returns `false` if the object is already stored.
+	async fn store_object(&self, hash: &ContentHash, data: &[u8]) -> Result<bool, StorageError> {
+		if self.backend.exists(hash).await? {
+			return Ok(false);
+		}
+		let lock = self.lock_object(hash).await?;
+		if lock.waited() && self.backend.exists(hash).await? {
+			return Ok(false);
+		}
+		let dest_path = match self.backend.local_path(hash) {
+			Some(dest_path) => dest_path,
+			None => {
+				self.backend.put(hash, data).await?;
+				return Ok(true);
+			}
+		};
+		tokio::fs::create_dir_all(dest_path.parent().unwrap()).await?;
+		let part_path = part_path(&dest_path);
+		let mut file = tokio::fs::File::create(&part_path).await?;
+		file.write_all(data).await?;
+		file.sync_all().await?;
+		self.commit_object(&part_path, &dest_path).await?;
+		Ok(true)
+	}
+
+	/// Store a file written to disk as an object.
+	///
+	/// Returns its hash and `false` if the object is already stored.
+	async fn store_spill(&self, spill: Spill) -> Result<(ContentHash, bool), StorageError> {
+		let Spill { path, file, hasher } = spill;
+		file.sync_all().await?;
+		drop(file);
+		let hash = ContentHash::sha1(&hasher.finalize());
+		if self.backend.exists(&hash).await? {
+			return Ok((hash, false));
+		}
+		let lock = self.lock_object(&hash).await?;
+		if lock.waited() && self.backend.exists(&hash).await? {
+			return Ok((hash, false));
+		}
+		match self.backend.local_path(&hash) {
+			Some(dest_path) => {
+				tokio::fs::create_dir_all(dest_path.parent().unwrap()).await?;
+				self.commit_object(&path.0, &dest_path).await?;
+			}
+			None => {
+				let data = tokio::fs::read(&path.0).await?;
+				self.backend.put(&hash, &data).await?;
+			}
+		}
+		Ok((hash, true))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage, temp_storage_with_client};
+	use crate::utils::car::tests::{car, cid, unixfs_node};
+	use crate::utils::crypto::sha1_digest;
+	use crate::utils::net::NetClient;
+	use crate::utils::test_server::{Route, TestServer};
+	use std::sync::Arc;
+
+	/// Build blocks of a directory with a small raw file and a file of two
+	/// chunks, in depth-first order.
+	fn directory() -> (Cid, Vec<(Cid, Vec<u8>)>) {
+		let small = b"small".to_vec();
+		let small_cid = cid(RAW, &small);
+		let chunks = [b"big ".to_vec(), b"file".to_vec()];
+		let chunk_cids: Vec<Cid> = chunks.iter().map(|chunk| cid(RAW, chunk)).collect();
+		let big = unixfs_node(2, b"", &chunk_cids);
+		let big_cid = cid(DAG_PB, &big);
+		let dir = unixfs_node(1, b"", &[small_cid.clone(), big_cid.clone()]);
+		let dir_cid = cid(DAG_PB, &dir);
+		let blocks = vec![
+			(dir_cid.clone(), dir),
+			(small_cid, small),
+			(big_cid, big),
+			(chunk_cids[0].clone(), chunks[0].clone()),
+			(chunk_cids[1].clone(), chunks[1].clone()),
+		];
+		(dir_cid, blocks)
+	}
+
+	#[tokio::test]
+	async fn test_import_car() {
+		let (_dir, storage) = temp_storage();
+		let (root, blocks) = directory();
+		let archive = car(&root, &blocks);
+		let path = storage.storage_dir().join("assets.car");
+		std::fs::write(&path, &archive).unwrap();
+
+		let report = storage.import_car(&path, &root.to_string()).await.unwrap();
+		assert_eq!(report.imported.len(), 2);
+		assert_eq!(report.incomplete, 0);
+		assert!(storage.check_asset(&sha1_digest(b"small")).await.unwrap());
+		assert!(storage
+			.check_asset(&sha1_digest(b"big file"))
+			.await
+			.unwrap());
+		// Chunks are not stored separately
+		assert!(!storage.check_asset(&sha1_digest(b"big ")).await.unwrap());
+
+		let report = storage.import_car(&path, &root.to_string()).await.unwrap();
+		assert_eq!(report.skipped.len(), 2);
+
+		std::fs::write(&path, car(&root, &blocks[..4])).unwrap();
+		let report = storage.import_car(&path, &root.to_string()).await.unwrap();
+		assert_eq!(report.incomplete, 1);
+
+		std::fs::write(&path, &archive[..archive.len() - 1]).unwrap();
+		assert!(matches!(
+			storage.import_car(&path, &root.to_string()).await,
+			Err(StorageError::CarError(CarError::Truncated))
+		));
+
+		// Archive of another DAG
+		std::fs::write(&path, &archive).unwrap();
+		assert!(matches!(
+			storage
+				.import_car(&path, &cid(RAW, b"other").to_string())
+				.await,
+			Err(StorageError::CarError(CarError::UnexpectedRoot(_)))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_import_unreachable_blocks() {
+		let (_dir, storage) = temp_storage();
+		let (root, mut blocks) = directory();
+		let path = storage.storage_dir().join("assets.car");
+
+		// Root is not the first block
+		blocks.swap(0, 1);
+		std::fs::write(&path, car(&root, &blocks)).unwrap();
+		assert!(matches!(
+			storage.import_car(&path, &root.to_string()).await,
+			Err(StorageError::CarError(CarError::RootNotFirst(_)))
+		));
+		assert!(!storage.check_asset(&sha1_digest(b"small")).await.unwrap());
+
+		// Blocks not linked from the root are ignored
+		blocks.swap(0, 1);
+		let extra = b"extra".to_vec();
+		blocks.push((cid(RAW, &extra), extra));
+		std::fs::write(&path, car(&root, &blocks)).unwrap();
+		let report = storage.import_car(&path, &root.to_string()).await.unwrap();
+		assert_eq!(report.imported.len(), 2);
+		assert!(!storage.check_asset(&sha1_digest(b"extra")).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_download_car() {
+		let (root, blocks) = directory();
+		let archive = car(&root, &blocks);
+		let server = TestServer::start().await;
+		server.route(&format!("/ipfs/{root}?format=car"), Route::ok(&archive));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let (_dir, storage) = temp_storage_with_client(client);
+
+		let report = storage.download_car(&root.to_string()).await.unwrap();
+		assert_eq!(report.imported.len(), 2);
+		assert!(storage
+			.check_asset(&sha1_digest(b"big file"))
+			.await
+			.unwrap());
+		let requests = server.requests();
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].headers["accept"].starts_with("application/vnd.ipld.car"));
+
+		// Archive of another DAG
+		let other = cid(RAW, b"other");
+		server.route(&format!("/ipfs/{other}?format=car"), Route::ok(&archive));
+		assert!(matches!(
+			storage.download_car(&other.to_string()).await,
+			Err(StorageError::CarError(CarError::UnexpectedRoot(_)))
+		));
+	}
+
+	#[tokio::test]
+	async fn test_import_large_file() {
+		let (_dir, storage) = temp_storage();
+		let chunks: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; SPILL_SIZE / 2 + 1]).collect();
+		let chunk_cids: Vec<Cid> = chunks.iter().map(|chunk| cid(RAW, chunk)).collect();
+		let file = unixfs_node(2, b"", &chunk_cids);
+		let root = cid(DAG_PB, &file);
+		let mut blocks = vec![(root.clone(), file)];
+		blocks.extend(chunk_cids.into_iter().zip(chunks.iter().cloned()));
+		let path = storage.storage_dir().join("assets.car");
+		std::fs::write(&path, car(&root, &blocks)).unwrap();
+
+		let report = storage.import_car(&path, &root.to_string()).await.unwrap();
+		assert_eq!(report.imported.len(), 1);
+		assert!(storage
+			.check_asset(&sha1_digest(&chunks.concat()))
+			.await
+			.unwrap());
+		// Temporary file is moved into place
+		let tmp = storage.storage_dir().join("tmp");
+		assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 0);
+
+		// Temporary file of an incomplete file is removed
+		std::fs::write(&path, car(&root, &blocks[..3])).unwrap();
+		let report = storage.import_car(&path, &root.to_string()).await.unwrap();
+		assert_eq!(report.incomplete, 1);
+		assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_import_stored_while_waiting() {
+		let (_dir, storage) = temp_storage();
+		let storage = Arc::new(storage);
+		let (root, blocks) = directory();
+		let path = storage.storage_dir().join("assets.car");
+		std::fs::write(&path, car(&root, &blocks)).unwrap();
+
+		// Another task is writing the object
+		let hash = ContentHash::sha1(&sha1_digest(b"small"));
+		let lock = storage.lock_object(&hash).await.unwrap();
+		let import = {
+			let storage = storage.clone();
+			let root = root.to_string();
+			tokio::spawn(async move { storage.import_car(&path, &root).await })
+		};
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		put_object(&storage, b"small");
+		drop(lock);
+		let report = import.await.unwrap().unwrap();
+		assert_eq!(report.skipped, vec![hash]);
+		assert_eq!(report.imported.len(), 1);
+	}
+}
@@ -0,0 +1,264 @@
+//! Garbage collection for the object store.
+//!
+//! Objects are only ever added to the storage, so objects which are no longer
+//! referenced by any saved asset index or version manifest pile up over time.
+//! [`Storage::collect_garbage`] removes them.
+//!
+//! Indexes and manifests reference objects only by SHA-1 hash, so objects of
+//! other algorithms are never removed.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use super::{Storage, StorageError};
+use crate::structures::{asset_index::AssetIndex, version_manifest::VersionManifest};
+use crate::utils::crypto::{ContentHash, HashAlgorithm};
+
+/// Result of the garbage collection.
+#[derive(Debug, Default, Clone)]
+pub struct GcReport {
+	/// Hashes of removed objects.
+	///
+	/// In dry-run mode these objects are not removed, only reported.
+	pub removed: Vec<ContentHash>,
+	/// Number of bytes reclaimed (or that would be reclaimed in dry-run mode).
+	pub reclaimed_bytes: u64,
+	/// Number of objects which are still referenced and were kept.
+	pub kept: usize,
+	/// Whether this was a dry run.
+	pub dry_run: bool,
+}
+
+impl Storage {
+	/// Get all objects referenced by saved asset indexes and version manifests.
+	///
+	/// Returns mapping of object hash to its IPFS path and size, if they are
+	/// known. Asset indexes saved in `indexes/` are referenced by their own
+	/// hash too.
+	pub async fn referenced_objects(
+		&self,
+	) -> Result<HashMap<ContentHash, Option<(String, u64)>>, StorageError> {
+		let mut references: HashMap<ContentHash, Option<(String, u64)>> = HashMap::new();
+
+		for path in list_json_files(self.storage_dir.join("indexes"), 1).await? {
+			let hash = match path.file_stem() {
+				Some(stem) => stem.to_string_lossy().to_string(),
+				None => continue,
+			};
+			let index: AssetIndex = serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?;
+			for entry in index.objects.into_values() {
+				references.insert(
+					ContentHash::sha1(&entry.hash),
+					Some((entry.path, entry.size)),
+				);
+			}
+			references.entry(ContentHash::sha1(&hash)).or_insert(None);
+		}
+
+		for path in list_json_files(self.storage_dir.join("versions"), 2).await? {
+			let manifest: VersionManifest =
+				serde_json::from_str(&tokio::fs::read_to_string(&path).await?)?;
+			for artifact in manifest.all_artifacts() {
+				references.insert(
+					ContentHash::sha1(&artifact.sha1),
+					Some((artifact.path, artifact.size)),
+				);
+			}
+		}
+
+		Ok(references)
+	}
+
+	/// Remove all objects which are not referenced by any saved asset index or
+	/// version manifest.
+	///
+	/// If `dry_run` is `true`, nothing is removed, but the report contains
+	/// objects which would be removed.
+	///
+	/// Collection is aborted if any saved index or manifest can't be parsed,
+	/// because otherwise objects referenced by it would be removed.
+	///
+	/// Versions are referenced only by manifests saved when they are
+	/// installed, see [`VersionManifest::install`]. Pinned objects are kept
+	/// too, see [`Storage::add_pinned`].
+	///
+	/// Objects are removed only if no other process has the storage open, even
+	/// an idle one, because it may be downloading objects it hasn't saved
+	/// references to yet. Otherwise [`StorageError::Busy`] is returned.
+	///
+	/// Objects can be removed only if the backend keeps them as local files and
+	/// the storage was created with [`Storage::new`], which locks the storage
+	/// directory. Otherwise [`StorageError::Unsupported`] is returned. Dry run
+	/// works with any storage.
+	pub async fn collect_garbage(&self, dry_run: bool) -> Result<GcReport, StorageError> {
+		if dry_run {
+			self.sweep(true).await
+		} else {
+			self.require_local("garbage collection")?;
+			self.with_exclusive_lock(self.sweep(false)).await
+		}
+	}
+
+	/// Find and, unless `dry_run` is `true`, remove unreferenced objects.
+	async fn sweep(&self, dry_run: bool) -> Result<GcReport, StorageError> {
+		let references = self.referenced_objects().await?;
+		let pinned = self.all_pinned().await?;
+		let mut report = GcReport {
+			dry_run,
+			..Default::default()
+		};
+		for (hash, path, size) in self.list_objects().await? {
+			if hash.algorithm != HashAlgorithm::Sha1
+				|| references.contains_key(&hash)
+				|| pinned.contains(&hash)
+			{
+				report.kept += 1;
+				continue;
+			}
+			if !dry_run {
+				debug!("Removing unreferenced object: {}", hash);
+				tokio::fs::remove_file(&path).await?;
+			}
+			report.removed.push(hash);
+			report.reclaimed_bytes += size;
+		}
+		if !dry_run {
+			self.invalidate_usage().await;
+		}
+		info!(
+			"Garbage collection finished: {} objects ({} bytes) {}",
+			report.removed.len(),
+			report.reclaimed_bytes,
+			if dry_run { "can be removed" } else { "removed" }
+		);
+		Ok(report)
+	}
+}
+
+/// List `.json` files located exactly `depth` levels below `dir`.
+///
+/// Missing `dir` is treated as empty.
+async fn list_json_files(dir: PathBuf, depth: usize) -> Result<Vec<PathBuf>, StorageError> {
+	if !dir.exists() {
+		return Ok(Vec::new());
+	}
+	let mut dirs = vec![dir];
+	for _ in 1..depth {
+		let mut next = Vec::new();
+		for dir in dirs {
+			let mut entries = tokio::fs::read_dir(dir).await?;
+			while let Some(entry) = entries.next_entry().await? {
+				if entry.file_type().await?.is_dir() {
+					next.push(entry.path());
+				}
+			}
+		}
+		dirs = next;
+	}
+	let mut files = Vec::new();
+	for dir in dirs {
+		let mut entries = tokio::fs::read_dir(dir).await?;
+		while let Some(entry) = entries.next_entry().await? {
+			let path = entry.path();
+			if entry.file_type().await?.is_file() && path.extension() == Some(OsStr::new("json")) {
+				files.push(path);
+			}
+		}
+	}
+	Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{
+		install_version, memory_storage, put_object, temp_storage, test_manifest,
+	};
+	use crate::structures::asset_index::AssetIndexEntry;
+	use crate::utils::net::NetClient;
+
+	#[tokio::test]
+	async fn test_collect_garbage() {
+		let (_dir, storage) = temp_storage();
+		let used = put_object(&storage, b"used");
+		let unused = put_object(&storage, b"unused");
+		let sha256 = ContentHash::digest(HashAlgorithm::Sha256, b"unused");
+		let sha256_path = storage.get_object_path(&sha256);
+		std::fs::create_dir_all(sha256_path.parent().unwrap()).unwrap();
+		std::fs::write(&sha256_path, b"unused").unwrap();
+
+		let mut objects = HashMap::new();
+		objects.insert(
+			"minecraft/used".to_string(),
+			AssetIndexEntry {
+				hash: used.clone(),
+				path: "CID".to_string(),
+				size: 4,
+			},
+		);
+		let index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+		let index_hash = put_object(&storage, serde_json::to_string(&index).unwrap().as_bytes());
+		index.save(&storage, &index_hash).await.unwrap();
+
+		let report = storage.collect_garbage(true).await.unwrap();
+		assert_eq!(report.removed, vec![ContentHash::sha1(&unused)]);
+		assert_eq!(report.reclaimed_bytes, 6);
+		assert_eq!(report.kept, 3);
+		assert!(storage.get_asset_path(&unused).exists());
+
+		let report = storage.collect_garbage(false).await.unwrap();
+		assert_eq!(report.removed, vec![ContentHash::sha1(&unused)]);
+		assert!(!storage.get_asset_path(&unused).exists());
+		assert!(storage.get_asset_path(&used).exists());
+		assert!(storage.get_asset_path(&index_hash).exists());
+		assert!(sha256_path.exists());
+	}
+
+	#[tokio::test]
+	async fn test_collect_installed_version() {
+		let (_dir, storage) = temp_storage();
+		let index = put_object(&storage, b"{\"objects\": {}}");
+		let client = put_object(&storage, b"client");
+		let library = put_object(&storage, b"library");
+		let pinned = put_object(&storage, b"pinned");
+		let unused = put_object(&storage, b"unused");
+		let storage = install_version(storage, &test_manifest(&index, &client, &library)).await;
+		assert!(storage
+			.pinned()
+			.await
+			.contains(&ContentHash::sha1(&library)));
+		storage
+			.add_pinned([ContentHash::sha1(&pinned)])
+			.await
+			.unwrap();
+
+		let report = storage.collect_garbage(false).await.unwrap();
+		assert_eq!(report.removed, vec![ContentHash::sha1(&unused)]);
+		for hash in [&index, &client, &library, &pinned] {
+			assert!(storage.get_asset_path(hash).exists());
+		}
+	}
+
+	#[tokio::test]
+	async fn test_collect_garbage_unsupported() {
+		let storage = memory_storage(NetClient::new());
+		storage
+			.backend()
+			.put(
+				&ContentHash::digest(HashAlgorithm::Sha1, b"unused"),
+				b"unused",
+			)
+			.await
+			.unwrap();
+
+		let report = storage.collect_garbage(true).await.unwrap();
+		assert_eq!(report.removed.len(), 1);
+		let result = storage.collect_garbage(false).await;
+		assert!(matches!(result, Err(StorageError::Unsupported(_))));
+		assert_eq!(storage.backend().list().await.unwrap().len(), 1);
+	}
+}
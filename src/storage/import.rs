@@ -0,0 +1,364 @@
+//! Import of an existing vanilla Minecraft installation.
+//!
+//! The vanilla launcher keeps assets in the same hashed layout as the storage,
+//! while libraries, client jars and asset indexes are stored by their names.
+//! Their hashes are taken from version manifests in `versions/`, so every
+//! imported file is verified before it's stored.
+//!
+//! Vanilla asset indexes don't have IPFS paths, so they are imported as plain
+//! objects and are not saved to `indexes/`. Until a version using imported
+//! objects is installed, they are not referenced and can be removed by
+//! [`Storage::collect_garbage`].
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::{hash_file, Storage, StorageError};
+use crate::utils::crypto::ContentHash;
+use crate::utils::net::part_path;
+
+/// How files are transferred from the imported installation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImportMode {
+	/// Files are moved. The installation can't be used afterwards.
+	Move,
+	/// Files are reflinked (copy-on-write clone), or copied if reflinks aren't
+	/// supported.
+	///
+	/// This is the default: the storage and the installation don't share
+	/// contents which either of them may modify.
+	#[default]
+	Reflink,
+	/// Files are hardlinked, or copied if hardlinks aren't supported.
+	///
+	/// Hardlinked objects share contents with files of the installation. If
+	/// the vanilla launcher or the user modifies such a file, the object is
+	/// corrupted too, until [`Storage::audit`] finds it. Prefer
+	/// [`ImportMode::Reflink`] unless the copy fallback would take too much
+	/// disk space.
+	Hardlink,
+	/// Files are copied.
+	Copy,
+}
+
+/// Result of the import.
+#[derive(Debug, Default, Clone)]
+pub struct ImportReport {
+	/// Hashes of imported objects.
+	pub imported: Vec<ContentHash>,
+	/// Files which were already in the storage.
+	pub skipped: Vec<PathBuf>,
+	/// Files which contents don't match their expected hashes.
+	pub corrupt: Vec<PathBuf>,
+}
+
+/// Vanilla version manifest, only the fields needed to find file hashes.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VanillaVersion {
+	asset_index: Option<VanillaArtifact>,
+	#[serde(default)]
+	downloads: HashMap<String, VanillaArtifact>,
+	#[serde(default)]
+	libraries: Vec<VanillaLibrary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VanillaLibrary {
+	downloads: Option<VanillaLibraryDownloads>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VanillaLibraryDownloads {
+	artifact: Option<VanillaArtifact>,
+	#[serde(default)]
+	classifiers: HashMap<String, VanillaArtifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VanillaArtifact {
+	sha1: String,
+	id: Option<String>,
+	path: Option<String>,
+}
+
+/// Get default location of the vanilla Minecraft installation.
+pub fn default_minecraft_dir() -> Option<PathBuf> {
+	if cfg!(target_os = "linux") {
+		dirs::home_dir().map(|dir| dir.join(".minecraft"))
+	} else if cfg!(target_os = "macos") {
+		dirs::data_dir().map(|dir| dir.join("minecraft"))
+	} else {
+		dirs::data_dir().map(|dir| dir.join(".minecraft"))
+	}
+}
+
+impl Storage {
+	/// Import assets, asset indexes, libraries and client jars from the vanilla
+	/// Minecraft installation at `minecraft_dir`.
+	///
+	/// Every file is verified against its expected SHA-1 hash. Corrupted files
+	/// are reported and left in place. Files already in the storage are
+	/// skipped.
+	pub async fn import_minecraft(
+		&self,
+		minecraft_dir: &Path,
+		mode: ImportMode,
+	) -> Result<ImportReport, StorageError> {
+		let mut report = ImportReport::default();
+
+		let objects_dir = minecraft_dir.join("assets").join("objects");
+		for path in list_asset_objects(&objects_dir).await? {
+			let hash = ContentHash::sha1(&path.file_name().unwrap().to_string_lossy());
+			self.import_file(&mut report, &path, &hash, mode).await?;
+		}
+
+		for (path, sha1_hash) in expected_hashes(minecraft_dir).await? {
+			let hash = ContentHash::sha1(&sha1_hash);
+			if !path.is_file() || !hash.is_valid() {
+				continue;
+			}
+			self.import_file(&mut report, &path, &hash, mode).await?;
+		}
+
+		info!(
+			"Imported {} objects from {}, {} skipped, {} corrupt",
+			report.imported.len(),
+			minecraft_dir.display(),
+			report.skipped.len(),
+			report.corrupt.len()
+		);
+		Ok(report)
+	}
+
+	/// Verify the file and transfer it into the storage.
+	async fn import_file(
+		&self,
+		report: &mut ImportReport,
+		path: &Path,
+		hash: &ContentHash,
+		mode: ImportMode,
+	) -> Result<(), StorageError> {
+		let dest_path = self.get_object_path(hash);
+		if dest_path.exists() {
+			report.skipped.push(path.to_path_buf());
+			return Ok(());
+		}
+		if hash_file(path, hash.algorithm).await? != hash.hex {
+			warn!(
+				"Corrupted file in the imported installation: {}",
+				path.display()
+			);
+			report.corrupt.push(path.to_path_buf());
+			return Ok(());
+		}
+
+		tokio::fs::create_dir_all(dest_path.parent().unwrap()).await?;
+		let _lock = self.lock_object(hash).await?;
+		let part_path = part_path(&dest_path);
+		let _ = tokio::fs::remove_file(&part_path).await;
+		let transferred = match mode {
+			ImportMode::Move => tokio::fs::rename(path, &part_path).await,
+			ImportMode::Reflink => {
+				let (src, dest) = (path.to_path_buf(), part_path.clone());
+				tokio::task::spawn_blocking(move || reflink_copy::reflink(src, dest))
+					.await
+					.unwrap()
+			}
+			ImportMode::Hardlink => tokio::fs::hard_link(path, &part_path).await,
+			ImportMode::Copy => Err(std::io::ErrorKind::Unsupported.into()),
+		};
+		if transferred.is_err() {
+			// Different filesystems or no reflink or hardlink support
+			tokio::fs::copy(path, &part_path).await?;
+			if mode == ImportMode::Move {
+				tokio::fs::remove_file(path).await?;
+			}
+		}
+		// Installation file may have been modified after it was verified
+		if mode != ImportMode::Move && hash_file(&part_path, hash.algorithm).await? != hash.hex {
+			tokio::fs::remove_file(&part_path).await?;
+			warn!(
+				"File of the imported installation was modified: {}",
+				path.display()
+			);
+			report.corrupt.push(path.to_path_buf());
+			return Ok(());
+		}
+		self.commit_object(&part_path, &dest_path).await?;
+		report.imported.push(hash.clone());
+		Ok(())
+	}
+}
+
+/// List files in the vanilla `assets/objects` directory.
+async fn list_asset_objects(objects_dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+	let mut objects = Vec::new();
+	if !objects_dir.exists() {
+		return Ok(objects);
+	}
+	let mut shards = tokio::fs::read_dir(objects_dir).await?;
+	while let Some(shard) = shards.next_entry().await? {
+		if !shard.file_type().await?.is_dir() {
+			continue;
+		}
+		let mut files = tokio::fs::read_dir(shard.path()).await?;
+		while let Some(file) = files.next_entry().await? {
+			let hash = ContentHash::sha1(&file.file_name().to_string_lossy());
+			if file.file_type().await?.is_file() && hash.is_valid() {
+				objects.push(file.path());
+			}
+		}
+	}
+	Ok(objects)
+}
+
+/// Collect expected SHA-1 hashes of asset indexes, client jars and libraries
+/// from all version manifests of the installation.
+///
+/// Manifests which can't be parsed are skipped.
+async fn expected_hashes(minecraft_dir: &Path) -> Result<HashMap<PathBuf, String>, StorageError> {
+	let mut hashes = HashMap::new();
+	let versions_dir = minecraft_dir.join("versions");
+	if !versions_dir.exists() {
+		return Ok(hashes);
+	}
+	let mut versions = tokio::fs::read_dir(versions_dir).await?;
+	while let Some(version_dir) = versions.next_entry().await? {
+		let name = version_dir.file_name().to_string_lossy().to_string();
+		let manifest_path = version_dir.path().join(format!("{name}.json"));
+		let version: VanillaVersion = match tokio::fs::read(&manifest_path).await {
+			Ok(data) => match serde_json::from_slice(&data) {
+				Ok(version) => version,
+				Err(e) => {
+					warn!("Failed to parse {}: {}", manifest_path.display(), e);
+					continue;
+				}
+			},
+			Err(_) => continue,
+		};
+
+		if let Some(asset_index) = version.asset_index {
+			if let Some(id) = asset_index.id {
+				let path = minecraft_dir
+					.join("assets")
+					.join("indexes")
+					.join(format!("{id}.json"));
+				hashes.insert(path, asset_index.sha1);
+			}
+		}
+		if let Some(client) = version.downloads.get("client") {
+			let path = version_dir.path().join(format!("{name}.jar"));
+			hashes.insert(path, client.sha1.clone());
+		}
+		let libraries_dir = minecraft_dir.join("libraries");
+		for downloads in version
+			.libraries
+			.into_iter()
+			.filter_map(|lib| lib.downloads)
+		{
+			let artifacts = downloads
+				.artifact
+				.into_iter()
+				.chain(downloads.classifiers.into_values());
+			for artifact in artifacts {
+				if let Some(path) = artifact.path {
+					hashes.insert(libraries_dir.join(path), artifact.sha1);
+				}
+			}
+		}
+	}
+	Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage};
+	use crate::utils::crypto::sha1_digest;
+
+	/// Write file, creating parent directories.
+	fn write(path: &Path, data: &[u8]) {
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		std::fs::write(path, data).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_import_minecraft() {
+		let (_dir, storage) = temp_storage();
+		let minecraft_dir = storage.storage_dir().join(".minecraft");
+		let object_path = |hash: &str| {
+			minecraft_dir
+				.join("assets/objects")
+				.join(&hash[0..2])
+				.join(hash)
+		};
+
+		let sound = sha1_digest(b"sound");
+		write(&object_path(&sound), b"sound");
+		let stored = put_object(&storage, b"stored");
+		write(&object_path(&stored), b"stored");
+		let corrupt = sha1_digest(b"texture");
+		write(&object_path(&corrupt), b"corrupted");
+
+		let index = br#"{"objects": {}}"#;
+		write(&minecraft_dir.join("assets/indexes/1.19.json"), index);
+		write(&minecraft_dir.join("versions/1.19/1.19.jar"), b"client");
+		write(
+			&minecraft_dir.join("libraries/org/lwjgl/lwjgl/3.3.1/lwjgl-3.3.1.jar"),
+			b"library",
+		);
+		let version = serde_json::json!({
+			"assetIndex": {"id": "1.19", "sha1": sha1_digest(index), "size": 15},
+			"downloads": {"client": {"sha1": sha1_digest(b"client"), "size": 6}},
+			"libraries": [{
+				"name": "org.lwjgl:lwjgl:3.3.1",
+				"downloads": {"artifact": {
+					"path": "org/lwjgl/lwjgl/3.3.1/lwjgl-3.3.1.jar",
+					"sha1": sha1_digest(b"library"),
+					"size": 7
+				}}
+			}]
+		});
+		write(
+			&minecraft_dir.join("versions/1.19/1.19.json"),
+			version.to_string().as_bytes(),
+		);
+
+		let report = storage
+			.import_minecraft(&minecraft_dir, ImportMode::Move)
+			.await
+			.unwrap();
+		assert_eq!(report.imported.len(), 4);
+		assert_eq!(report.skipped, vec![object_path(&stored)]);
+		assert_eq!(report.corrupt, vec![object_path(&corrupt)]);
+		for data in [&b"sound"[..], index, b"client", b"library"] {
+			assert!(storage.check_asset(&sha1_digest(data)).await.unwrap());
+		}
+		assert!(!object_path(&sound).exists());
+		assert!(object_path(&corrupt).exists());
+	}
+
+	#[tokio::test]
+	async fn test_import_minecraft_reflink() {
+		let (_dir, storage) = temp_storage();
+		let minecraft_dir = storage.storage_dir().join(".minecraft");
+		let sound = sha1_digest(b"sound");
+		let path = minecraft_dir
+			.join("assets/objects")
+			.join(&sound[0..2])
+			.join(&sound);
+		write(&path, b"sound");
+
+		let report = storage
+			.import_minecraft(&minecraft_dir, ImportMode::default())
+			.await
+			.unwrap();
+		assert_eq!(report.imported.len(), 1);
+		// Object doesn't share contents with the installation file
+		std::fs::write(&path, b"SOUND").unwrap();
+		assert!(storage.check_asset(&sound).await.unwrap());
+	}
+}
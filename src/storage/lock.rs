@@ -0,0 +1,407 @@
+//! Cross-process locking of the storage directory.
+//!
+//! Several processes (e.g. two launchers, or the launcher and a script) can use
+//! the same storage directory at once. They are coordinated with advisory file
+//! locks:
+//!
+//! - Every [`Storage`] takes a shared lock on `storage.lock` before it writes
+//!   the first object and keeps it for its whole lifetime.
+//! - Operations which remove objects, like the garbage collection, first take
+//!   an exclusive lock on `gc.lock` and then temporarily upgrade their lock on
+//!   `storage.lock` to an exclusive one. They fail with [`StorageError::Busy`]
+//!   if any other process has the storage open, even if it's idle. Within the
+//!   process they also wait until all objects being written are stored, and
+//!   block new writes until they finish.
+//! - A [`Storage`] takes its shared lock on `storage.lock` only while holding a
+//!   shared lock on `gc.lock`. So it waits while another process collects
+//!   garbage, and the collecting process can always downgrade its lock again.
+//! - An object is written only while holding an exclusive lock on its lock
+//!   file in `locks/`. A process which had to wait for the lock checks whether
+//!   the object was stored in the meantime before downloading it itself.
+//!
+//! There is a lock file per object. Nobody holds them while the storage
+//! directory is locked exclusively, so they are removed then.
+
+use std::fs::{File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::{OwnedMutexGuard, OwnedRwLockReadGuard};
+
+use super::{Storage, StorageError};
+use crate::utils::crypto::ContentHash;
+
+/// Name of the storage directory lock file.
+const DIR_LOCK_NAME: &str = "storage.lock";
+/// Name of the lock file held exclusively while removing objects.
+const GC_LOCK_NAME: &str = "gc.lock";
+
+/// Lock of a single object, held while the object is written.
+///
+/// Locks are released when this is dropped.
+#[derive(Debug)]
+pub(crate) struct ObjectLock {
+	_writer: Option<OwnedRwLockReadGuard<()>>,
+	_guard: OwnedMutexGuard<()>,
+	_file: Option<File>,
+	waited: bool,
+}
+
+impl ObjectLock {
+	/// Whether another task or process held the lock when it was requested.
+	///
+	/// If so, the object may have been stored in the meantime.
+	#[inline]
+	pub(crate) fn waited(&self) -> bool {
+		self.waited
+	}
+}
+
+/// Downgrades the storage directory lock back to a shared one when dropped,
+/// even if the exclusive section was cancelled.
+struct Downgrade<'a>(&'a File);
+
+impl Drop for Downgrade<'_> {
+	fn drop(&mut self) {
+		// Fails if the exclusive lock wasn't taken, which is fine
+		let _ = self.0.unlock();
+		// Other processes can't hold an exclusive lock, so this doesn't block
+		if let Err(e) = self.0.lock_shared() {
+			error!("Failed to lock storage directory: {}", e);
+		}
+	}
+}
+
+impl Storage {
+	/// Take a shared lock on the storage directory.
+	///
+	/// If another process is collecting garbage, this blocks until it's
+	/// finished when `wait` is `true`, and returns `None` otherwise.
+	pub(crate) fn lock_dir(storage_dir: &Path, wait: bool) -> io::Result<Option<File>> {
+		let gc_lock = open_lock_file(&storage_dir.join(GC_LOCK_NAME))?;
+		match gc_lock.try_lock_shared() {
+			Ok(()) => {}
+			Err(TryLockError::WouldBlock) if wait => {
+				debug!("Waiting for garbage collection: {}", storage_dir.display());
+				gc_lock.lock_shared()?
+			}
+			Err(TryLockError::WouldBlock) => return Ok(None),
+			Err(TryLockError::Error(e)) => return Err(e),
+		}
+		// Exclusive lock is taken only while holding the GC lock exclusively
+		let file = open_lock_file(&storage_dir.join(DIR_LOCK_NAME))?;
+		file.lock_shared()?;
+		Ok(Some(file))
+	}
+
+	/// Get the shared lock on the storage directory, taking it if needed.
+	///
+	/// Returns `None` for storages created with [`Storage::with_backend`],
+	/// which don't lock the directory.
+	async fn dir_lock(&self) -> Result<Option<&File>, StorageError> {
+		let cell = match &self.dir_lock {
+			Some(cell) => cell,
+			None => return Ok(None),
+		};
+		let file = cell
+			.get_or_try_init(|| {
+				let storage_dir = self.storage_dir.clone();
+				async move {
+					tokio::task::spawn_blocking(move || Self::lock_dir(&storage_dir, true))
+						.await
+						.unwrap()
+						.map(|file| file.expect("lock is taken when waiting"))
+				}
+			})
+			.await?;
+		Ok(Some(file))
+	}
+
+	/// Run `f` while holding an exclusive lock on the storage directory.
+	///
+	/// Returns [`StorageError::Busy`] if the storage is used by another process
+	/// and [`StorageError::Unsupported`] if the directory isn't locked at all.
+	pub(crate) async fn with_exclusive_lock<F, T>(&self, f: F) -> Result<T, StorageError>
+	where
+		F: std::future::Future<Output = Result<T, StorageError>>,
+	{
+		// Objects being written by this process must not be removed
+		let _writers = self.writers.write().await;
+		let file = match self.dir_lock().await? {
+			Some(file) => file,
+			None => {
+				return Err(StorageError::Unsupported(
+					"exclusive access without a storage directory lock",
+				))
+			}
+		};
+		let gc_lock = open_lock_file(&self.storage_dir.join(GC_LOCK_NAME))?;
+		match gc_lock.try_lock() {
+			Ok(()) => {}
+			Err(TryLockError::WouldBlock) => return Err(StorageError::Busy),
+			Err(TryLockError::Error(e)) => return Err(e.into()),
+		}
+		// Shared lock can't be upgraded in place on every platform
+		file.unlock()?;
+		let _downgrade = Downgrade(file);
+		match file.try_lock() {
+			Ok(()) => {}
+			Err(TryLockError::WouldBlock) => return Err(StorageError::Busy),
+			Err(TryLockError::Error(e)) => return Err(e.into()),
+		}
+		let result = f.await?;
+		self.remove_object_locks().await?;
+		Ok(result)
+	}
+
+	/// Remove lock files of all objects.
+	///
+	/// Must be called only while the storage directory is locked exclusively.
+	async fn remove_object_locks(&self) -> Result<(), StorageError> {
+		let mut entries = match tokio::fs::read_dir(self.storage_dir.join("locks")).await {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		};
+		while let Some(entry) = entries.next_entry().await? {
+			tokio::fs::remove_file(entry.path()).await?;
+		}
+		Ok(())
+	}
+
+	/// Lock the given object for writing.
+	///
+	/// Concurrent downloads of the same object would write to the same `.part`
+	/// file, so they must be serialized both within this process and between
+	/// processes sharing the storage directory.
+	pub(crate) async fn lock_object(&self, hash: &ContentHash) -> Result<ObjectLock, StorageError> {
+		self.dir_lock().await?;
+		let writer = self.writers.clone().read_owned().await;
+		let lock = self.object_mutex(hash);
+		let (guard, mut waited) = match lock.clone().try_lock_owned() {
+			Ok(guard) => (guard, false),
+			Err(_) => (lock.lock_owned().await, true),
+		};
+
+		// Objects of non-local backends are not shared with other processes
+		if self.backend.local_path(hash).is_none() {
+			return Ok(ObjectLock {
+				_writer: Some(writer),
+				_guard: guard,
+				_file: None,
+				waited,
+			});
+		}
+		let path = self.get_object_lock_path(hash);
+		let (file, file_waited) = tokio::task::spawn_blocking(move || lock_exclusive(&path))
+			.await
+			.unwrap()?;
+		waited |= file_waited;
+		Ok(ObjectLock {
+			_writer: Some(writer),
+			_guard: guard,
+			_file: Some(file),
+			waited,
+		})
+	}
+
+	/// Lock the given object for removal without waiting.
+	///
+	/// Returns `None` if the object is being written. Unlike
+	/// [`Storage::lock_object`], this doesn't block the garbage collection, so
+	/// it can be called while another object is locked.
+	pub(crate) fn try_lock_object(
+		&self,
+		hash: &ContentHash,
+	) -> Result<Option<ObjectLock>, StorageError> {
+		let guard = match self.object_mutex(hash).try_lock_owned() {
+			Ok(guard) => guard,
+			Err(_) => return Ok(None),
+		};
+		let file = match self.backend.local_path(hash) {
+			Some(_) => {
+				let file = open_lock_file(&self.get_object_lock_path(hash))?;
+				match file.try_lock() {
+					Ok(()) => Some(file),
+					Err(TryLockError::WouldBlock) => return Ok(None),
+					Err(TryLockError::Error(e)) => return Err(e.into()),
+				}
+			}
+			None => None,
+		};
+		Ok(Some(ObjectLock {
+			_writer: None,
+			_guard: guard,
+			_file: file,
+			waited: false,
+		}))
+	}
+
+	/// Get the in-process mutex of the given object.
+	fn object_mutex(&self, hash: &ContentHash) -> Arc<tokio::sync::Mutex<()>> {
+		let mut locks = self.locks.lock().unwrap();
+		// Drop locks which are not held by anyone
+		locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+		locks.entry(hash.clone()).or_default().clone()
+	}
+
+	/// Get path of the lock file guarding the given object.
+	fn get_object_lock_path(&self, hash: &ContentHash) -> PathBuf {
+		object_lock_path(&self.storage_dir.join("locks"), hash)
+	}
+}
+
+/// Get path of the lock file in `locks_dir` guarding the given object.
+pub(crate) fn object_lock_path(locks_dir: &Path, hash: &ContentHash) -> PathBuf {
+	locks_dir.join(format!("{}-{}.lock", hash.algorithm, hash.hex))
+}
+
+/// Open lock file, creating it and its parent directories if needed.
+pub(super) fn open_lock_file(path: &Path) -> io::Result<File> {
+	std::fs::create_dir_all(path.parent().unwrap())?;
+	File::options()
+		.create(true)
+		.truncate(false)
+		.write(true)
+		.open(path)
+}
+
+/// Take an exclusive lock on the given file, blocking until it's available.
+///
+/// Returns the locked file and whether it was locked by someone else.
+pub(crate) fn lock_exclusive(path: &Path) -> io::Result<(File, bool)> {
+	let file = open_lock_file(path)?;
+	match file.try_lock() {
+		Ok(()) => Ok((file, false)),
+		Err(TryLockError::WouldBlock) => {
+			debug!("Waiting for lock: {}", path.display());
+			file.lock()?;
+			Ok((file, true))
+		}
+		Err(TryLockError::Error(e)) => Err(e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage};
+	use crate::storage::FsBackend;
+	use crate::utils::crypto::HashAlgorithm;
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::net::NetClient;
+
+	#[tokio::test]
+	async fn test_object_lock() {
+		let (_dir, storage) = temp_storage();
+		let hash = ContentHash::digest(HashAlgorithm::Sha1, b"object");
+
+		let lock = storage.lock_object(&hash).await.unwrap();
+		assert!(!lock.waited());
+		// Lock file is held, like another process would see it
+		let path = storage.get_object_lock_path(&hash);
+		assert!(matches!(
+			open_lock_file(&path).unwrap().try_lock(),
+			Err(TryLockError::WouldBlock)
+		));
+		let storage = Arc::new(storage);
+		let waiter = {
+			let storage = storage.clone();
+			let hash = hash.clone();
+			tokio::spawn(async move { storage.lock_object(&hash).await.unwrap().waited() })
+		};
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		drop(lock);
+		assert!(waiter.await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_gc_waits_for_writers() {
+		let (_dir, storage) = temp_storage();
+		let storage = Arc::new(storage);
+		put_object(&storage, b"unused");
+		let hash = ContentHash::digest(HashAlgorithm::Sha1, b"object");
+
+		let lock = storage.lock_object(&hash).await.unwrap();
+		let gc = {
+			let storage = storage.clone();
+			tokio::spawn(async move { storage.collect_garbage(false).await })
+		};
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		assert!(!gc.is_finished());
+		drop(lock);
+		assert_eq!(gc.await.unwrap().unwrap().removed.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_gc_busy() {
+		let (_dir, storage) = temp_storage();
+		put_object(&storage, b"unused");
+		// Another process opens the same storage directory, GC is refused even
+		// though it doesn't write anything
+		let other = Storage::lock_dir(storage.storage_dir(), false).unwrap();
+		assert!(other.is_some());
+
+		let result = storage.collect_garbage(false).await;
+		assert!(matches!(result, Err(StorageError::Busy)));
+		drop(other);
+		assert_eq!(
+			storage.collect_garbage(false).await.unwrap().removed.len(),
+			1
+		);
+		// Both locks are released or shared again
+		assert!(Storage::lock_dir(storage.storage_dir(), false)
+			.unwrap()
+			.is_some());
+		assert!(storage.collect_garbage(false).await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_object_lock_per_object() {
+		let (_dir, storage) = temp_storage();
+		let first = ContentHash::sha1(&format!("ab{}", "0".repeat(38)));
+		let second = ContentHash::sha1(&format!("ab{}", "1".repeat(38)));
+
+		let _first = storage.lock_object(&first).await.unwrap();
+		// Objects with the same prefix don't share lock files
+		assert!(!storage.lock_object(&second).await.unwrap().waited());
+	}
+
+	#[tokio::test]
+	async fn test_lock_dir_waits_for_gc() {
+		let dir = TempDir::new();
+		// Another process is collecting garbage
+		let gc_lock = open_lock_file(&dir.join(GC_LOCK_NAME)).unwrap();
+		gc_lock.lock().unwrap();
+
+		// Doesn't block even on a current-thread runtime
+		let storage = Arc::new(Storage::new(
+			Arc::new(NetClient::new()),
+			Some(dir.path().to_path_buf()),
+		));
+		let hash = ContentHash::digest(HashAlgorithm::Sha1, b"object");
+		let writer = {
+			let storage = storage.clone();
+			tokio::spawn(async move { storage.lock_object(&hash).await.map(|_| ()) })
+		};
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		assert!(!writer.is_finished());
+		drop(gc_lock);
+		writer.await.unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_exclusive_lock_unsupported() {
+		let dir = TempDir::new();
+		let backend = Arc::new(FsBackend::new(dir.join("objects")));
+		let storage = Storage::with_backend(
+			Arc::new(NetClient::new()),
+			dir.path().to_path_buf(),
+			backend,
+		);
+
+		let result = storage.collect_garbage(false).await;
+		assert!(matches!(result, Err(StorageError::Unsupported(_))));
+	}
+}
@@ -0,0 +1,431 @@
+//! Materialization of objects into game directories.
+//!
+//! The game expects assets and libraries in specific layouts, while the
+//! storage keeps them content-addressed. [`Storage::materialize`] builds
+//! `assets/`, `libraries/` and `natives/` trees of an instance from stored
+//! objects. Versions before 1.7 read assets by their names instead, so for
+//! them the named asset tree is built too (see
+//! [`AssetIndex::get_named_assets_dir`]).
+//!
+//! Files are reflinked if the filesystem supports it, hardlinked otherwise and
+//! copied as the last resort, so an instance costs almost no extra disk space.
+
+use std::io::{self, Read};
+use std::path::{Component, Path};
+
+use super::{validate_hash, Storage, StorageError};
+use crate::structures::{
+	asset_index::AssetIndex,
+	version_manifest::{maven_path, Extract, VersionManifest},
+};
+use crate::utils::crypto::ContentHash;
+
+/// How a file was materialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMethod {
+	/// File already existed and was left as is.
+	Unchanged,
+	/// File was reflinked (copy-on-write clone).
+	Reflink,
+	/// File was hardlinked.
+	Hardlink,
+	/// File was copied.
+	Copy,
+}
+
+/// Result of the materialization.
+#[derive(Debug, Default, Clone)]
+pub struct MaterializeReport {
+	/// Number of files which already existed.
+	pub unchanged: usize,
+	/// Number of reflinked files.
+	pub reflinked: usize,
+	/// Number of hardlinked files.
+	pub hardlinked: usize,
+	/// Number of copied files.
+	pub copied: usize,
+	/// Number of files extracted from native libraries.
+	pub extracted: usize,
+}
+
+impl MaterializeReport {
+	fn record(&mut self, method: LinkMethod) {
+		match method {
+			LinkMethod::Unchanged => self.unchanged += 1,
+			LinkMethod::Reflink => self.reflinked += 1,
+			LinkMethod::Hardlink => self.hardlinked += 1,
+			LinkMethod::Copy => self.copied += 1,
+		}
+	}
+}
+
+impl Storage {
+	/// Build `assets/`, `libraries/` and `natives/` trees of the instance
+	/// located at `instance_dir` from stored objects.
+	///
+	/// Assets are also placed by their names if the asset index is `virtual`
+	/// or `map_to_resources`.
+	///
+	/// All objects must be already downloaded. This function can be called
+	/// again on the same directory, existing files with the right contents are
+	/// left untouched.
+	///
+	/// It does blocking IO, so call it with [`tokio::task::spawn_blocking`]
+	/// from async code.
+	pub fn materialize(
+		&self,
+		instance_dir: &Path,
+		manifest: &VersionManifest,
+		asset_index: Option<&AssetIndex>,
+	) -> Result<MaterializeReport, StorageError> {
+		let mut report = MaterializeReport::default();
+
+		let assets_dir = instance_dir.join("assets");
+		if let Some(artifact) = &manifest.asset_index {
+			let dest = assets_dir
+				.join("indexes")
+				.join(format!("{}.json", artifact.id));
+			report.record(self.link_object(&artifact.sha1, &dest)?);
+		}
+		if let Some(asset_index) = asset_index {
+			for asset in asset_index.get_assets() {
+				validate_hash(&ContentHash::sha1(&asset.hash))?;
+				let dest = assets_dir
+					.join("objects")
+					.join(&asset.hash[0..2])
+					.join(&asset.hash);
+				report.record(self.link_object(&asset.hash, &dest)?);
+			}
+
+			let id = match &manifest.asset_index {
+				Some(artifact) => artifact.id.as_str(),
+				None => "legacy",
+			};
+			if let Some(named_dir) = asset_index.get_named_assets_dir(instance_dir, id) {
+				for (name, asset) in &asset_index.objects {
+					let is_safe = Path::new(name)
+						.components()
+						.all(|component| matches!(component, Component::Normal(_)));
+					if !is_safe {
+						warn!("Skipping asset with invalid name: {}", name);
+						continue;
+					}
+					report.record(self.link_object(&asset.hash, &named_dir.join(name))?);
+				}
+			}
+		}
+
+		let libraries_dir = instance_dir.join("libraries");
+		if let Some(main_jar) = &manifest.main_jar {
+			if let (Some(artifact), Some(path)) = (
+				&main_jar.downloads.artifact,
+				maven_path(&main_jar.name, None),
+			) {
+				report.record(self.link_object(&artifact.sha1, &libraries_dir.join(path))?);
+			}
+		}
+		let natives_dir = instance_dir.join("natives");
+		for library in &manifest.libraries {
+			if !library.is_rules_satisfied() {
+				continue;
+			}
+			if let (Some(artifact), Some(path)) =
+				(&library.downloads.artifact, maven_path(&library.name, None))
+			{
+				report.record(self.link_object(&artifact.sha1, &libraries_dir.join(path))?);
+			}
+			if let (Some(artifact), Some(path)) = (
+				library.get_native_artifact(),
+				maven_path(&library.name, library.get_native_classifier()),
+			) {
+				let dest = libraries_dir.join(path);
+				report.record(self.link_object(&artifact.sha1, &dest)?);
+				report.extracted += extract_natives(&dest, &natives_dir, library.extract.as_ref())?;
+			}
+		}
+
+		info!(
+			"Instance materialized in {}: {:?}",
+			instance_dir.display(),
+			report
+		);
+		Ok(report)
+	}
+
+	/// Materialize the SHA-1 object at `dest`.
+	fn link_object(&self, sha1_hash: &str, dest: &Path) -> Result<LinkMethod, StorageError> {
+		validate_hash(&ContentHash::sha1(sha1_hash))?;
+		let src = self.get_asset_path(sha1_hash);
+		if !src.exists() {
+			return Err(StorageError::MissingObject(sha1_hash.to_string()));
+		}
+		Ok(link_file(&src, dest)?)
+	}
+}
+
+/// Materialize `src` file at `dest`, creating parent directories.
+///
+/// Tries reflink, then hardlink, then copy. If `dest` already exists and is
+/// the same file as `src` or has the same contents, it's left as is.
+pub fn link_file(src: &Path, dest: &Path) -> io::Result<LinkMethod> {
+	if std::fs::symlink_metadata(dest).is_ok() {
+		if is_same_file(src, dest)? {
+			return Ok(LinkMethod::Unchanged);
+		}
+		std::fs::remove_file(dest)?;
+	}
+	std::fs::create_dir_all(dest.parent().unwrap())?;
+	if reflink_copy::reflink(src, dest).is_ok() {
+		return Ok(LinkMethod::Reflink);
+	}
+	if std::fs::hard_link(src, dest).is_ok() {
+		return Ok(LinkMethod::Hardlink);
+	}
+	std::fs::copy(src, dest)?;
+	Ok(LinkMethod::Copy)
+}
+
+/// Check whether existing `dest` is a hardlink of `src` or has the same
+/// contents.
+fn is_same_file(src: &Path, dest: &Path) -> io::Result<bool> {
+	let src_metadata = std::fs::metadata(src)?;
+	let dest_metadata = std::fs::symlink_metadata(dest)?;
+	if !dest_metadata.is_file() || src_metadata.len() != dest_metadata.len() {
+		return Ok(false);
+	}
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::MetadataExt;
+		if src_metadata.dev() == dest_metadata.dev() && src_metadata.ino() == dest_metadata.ino() {
+			return Ok(true);
+		}
+	}
+
+	// Reflinked and copied files are compared by contents
+	let mut src = std::fs::File::open(src)?;
+	let mut dest = std::fs::File::open(dest)?;
+	let mut src_buf = vec![0; 64 * 1024];
+	let mut dest_buf = vec![0; 64 * 1024];
+	let mut remaining = src_metadata.len();
+	while remaining > 0 {
+		let len = remaining.min(src_buf.len() as u64) as usize;
+		src.read_exact(&mut src_buf[..len])?;
+		dest.read_exact(&mut dest_buf[..len])?;
+		if src_buf[..len] != dest_buf[..len] {
+			return Ok(false);
+		}
+		remaining -= len as u64;
+	}
+	Ok(true)
+}
+
+/// Extract native library jar into `natives_dir`.
+///
+/// Returns number of extracted files.
+fn extract_natives(
+	jar: &Path,
+	natives_dir: &Path,
+	extract: Option<&Extract>,
+) -> Result<usize, StorageError> {
+	let mut archive = zip::ZipArchive::new(std::fs::File::open(jar)?)?;
+	let mut extracted = 0;
+	for i in 0..archive.len() {
+		let mut file = archive.by_index(i)?;
+		let name = match file.enclosed_name() {
+			Some(name) => name.to_path_buf(),
+			None => continue,
+		};
+		let excluded = extract
+			.into_iter()
+			.flat_map(|extract| extract.exclude.iter())
+			.any(|exclude| file.name().starts_with(exclude.as_str()));
+		if file.is_dir() || excluded {
+			continue;
+		}
+		let dest = natives_dir.join(name);
+		std::fs::create_dir_all(dest.parent().unwrap())?;
+		io::copy(&mut file, &mut std::fs::File::create(dest)?)?;
+		extracted += 1;
+	}
+	Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage};
+	use crate::structures::asset_index::AssetIndexEntry;
+	use std::collections::HashMap;
+	use std::io::Write;
+
+	#[test]
+	fn test_materialize() {
+		let (_dir, storage) = temp_storage();
+
+		let mut jar = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+		let options = zip::write::FileOptions::default();
+		jar.start_file("liblwjgl.so", options).unwrap();
+		jar.write_all(b"native").unwrap();
+		jar.start_file("META-INF/MANIFEST.MF", options).unwrap();
+		jar.write_all(b"manifest").unwrap();
+		let jar = jar.finish().unwrap().into_inner();
+		let native = put_object(&storage, &jar);
+		let library = put_object(&storage, b"library");
+		let client = put_object(&storage, b"client");
+		let sound = put_object(&storage, b"sound");
+
+		let os = if cfg!(windows) {
+			"windows"
+		} else if cfg!(target_os = "macos") {
+			"osx"
+		} else {
+			"linux"
+		};
+		let manifest: VersionManifest = serde_json::from_value(serde_json::json!({
+			"+traits": [],
+			"formatVersion": 1,
+			"mainJar": {
+				"name": "com.mojang:minecraft:1.12.2:client",
+				"downloads": {"artifact": {"sha1": client, "size": 6, "path": "CID"}}
+			},
+			"libraries": [{
+				"name": "org.lwjgl:lwjgl:3.2.2",
+				"downloads": {
+					"artifact": {"sha1": library, "size": 7, "path": "CID"},
+					"classifiers": {
+						format!("natives-{os}"): {"sha1": native, "size": jar.len(), "path": "CID"}
+					}
+				},
+				"natives": {os: format!("natives-{os}")},
+				"extract": {"exclude": ["META-INF/"]}
+			}],
+			"version": "1.12.2",
+			"type": "release",
+			"releaseTime": "2017-09-18T08:39:46+00:00",
+			"name": "Minecraft",
+			"productUid": "net.minecraft"
+		}))
+		.unwrap();
+		let mut objects = HashMap::new();
+		objects.insert(
+			"minecraft/sounds/step.ogg".to_string(),
+			AssetIndexEntry {
+				hash: sound.clone(),
+				path: "CID".to_string(),
+				size: 5,
+			},
+		);
+		let asset_index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+
+		let instance_dir = storage.storage_dir().join("instance");
+		let report = storage
+			.materialize(&instance_dir, &manifest, Some(&asset_index))
+			.unwrap();
+		assert_eq!(report.unchanged, 0);
+		assert_eq!(report.extracted, 1);
+		let objects_dir = instance_dir.join("assets").join("objects");
+		assert_eq!(
+			std::fs::read(objects_dir.join(&sound[0..2]).join(&sound)).unwrap(),
+			b"sound"
+		);
+		let libraries_dir = instance_dir.join("libraries");
+		assert_eq!(
+			std::fs::read(libraries_dir.join("org/lwjgl/lwjgl/3.2.2/lwjgl-3.2.2.jar")).unwrap(),
+			b"library"
+		);
+		assert_eq!(
+			std::fs::read(
+				libraries_dir.join("com/mojang/minecraft/1.12.2/minecraft-1.12.2-client.jar")
+			)
+			.unwrap(),
+			b"client"
+		);
+		let natives_dir = instance_dir.join("natives");
+		assert_eq!(
+			std::fs::read(natives_dir.join("liblwjgl.so")).unwrap(),
+			b"native"
+		);
+		assert!(!natives_dir.join("META-INF").exists());
+
+		// Second run doesn't change anything
+		let report = storage
+			.materialize(&instance_dir, &manifest, Some(&asset_index))
+			.unwrap();
+		assert_eq!(report.unchanged, 4);
+
+		// File of the same size but with other contents is replaced
+		let library_path = libraries_dir.join("org/lwjgl/lwjgl/3.2.2/lwjgl-3.2.2.jar");
+		std::fs::remove_file(&library_path).unwrap();
+		std::fs::write(&library_path, b"LIBRARY").unwrap();
+		let report = storage
+			.materialize(&instance_dir, &manifest, Some(&asset_index))
+			.unwrap();
+		assert_eq!(report.unchanged, 3);
+		assert_eq!(std::fs::read(&library_path).unwrap(), b"library");
+
+		// Invalid hashes are rejected instead of building paths from them
+		let mut objects = HashMap::new();
+		objects.insert(
+			"minecraft/sounds/bad.ogg".to_string(),
+			AssetIndexEntry {
+				hash: "a".to_string(),
+				path: "CID".to_string(),
+				size: 5,
+			},
+		);
+		let asset_index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+		assert!(matches!(
+			storage.materialize(&instance_dir, &manifest, Some(&asset_index)),
+			Err(StorageError::InvalidHash(_))
+		));
+	}
+
+	#[test]
+	fn test_materialize_legacy_assets() {
+		let (_dir, storage) = temp_storage();
+		let sound = put_object(&storage, b"sound");
+		let manifest: VersionManifest = serde_json::from_value(serde_json::json!({
+			"+traits": [],
+			"formatVersion": 1,
+			"version": "1.5.2",
+			"type": "release",
+			"releaseTime": "2013-04-25T15:45:00+00:00",
+			"name": "Minecraft",
+			"productUid": "net.minecraft"
+		}))
+		.unwrap();
+		let mut asset_index: AssetIndex = serde_json::from_value(serde_json::json!({
+			"map_to_resources": true,
+			"objects": {
+				"sound/step/grass1.ogg": {"hash": sound, "path": "CID", "size": 5},
+				"../outside.ogg": {"hash": sound, "path": "CID", "size": 5}
+			}
+		}))
+		.unwrap();
+		assert!(asset_index.map_to_resources);
+		let instance_dir = storage.storage_dir().join("instance");
+		storage
+			.materialize(&instance_dir, &manifest, Some(&asset_index))
+			.unwrap();
+		assert_eq!(
+			std::fs::read(instance_dir.join("resources/sound/step/grass1.ogg")).unwrap(),
+			b"sound"
+		);
+		assert!(!storage.storage_dir().join("outside.ogg").exists());
+
+		asset_index.map_to_resources = false;
+		asset_index.is_virtual = true;
+		storage
+			.materialize(&instance_dir, &manifest, Some(&asset_index))
+			.unwrap();
+		assert!(instance_dir
+			.join("assets/virtual/legacy/sound/step/grass1.ogg")
+			.exists());
+	}
+}
@@ -0,0 +1,727 @@
+//! This module contains [`Storage`] struct, which is used to verify and download
+//! static assets.
+
+use dirs::data_dir;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+
+use thiserror::Error;
+
+use crate::utils::crypto::{ContentHash, HashAlgorithm};
+use crate::utils::net::{part_path, NetClient};
+use crate::utils::retry::Retryable;
+
+pub mod audit;
+pub mod backend;
+pub mod bundle;
+pub mod car;
+pub mod gc;
+pub mod import;
+mod lock;
+pub mod materialize;
+mod offline;
+mod pin;
+pub mod quota;
+pub mod scheduler;
+
+pub use self::audit::{AuditReport, RepairReport};
+pub use self::backend::{FsBackend, MemoryBackend, StorageBackend};
+pub use self::gc::GcReport;
+pub use self::import::{ImportMode, ImportReport};
+pub use self::materialize::{LinkMethod, MaterializeReport};
+pub use self::scheduler::{DownloadJob, DownloadMode, DownloadScheduler, JobError, Priority};
+
+pub(crate) use crate::utils::fs::write_file_atomic;
+
+/// Storage error.
+#[derive(Error, Debug)]
+pub enum StorageError {
+	/// IO error.
+	#[error("IO error: {0}")]
+	IOError(#[from] std::io::Error),
+	/// Network error.
+	#[error("Network error: {0}")]
+	NetworkError(#[from] crate::utils::net::NetworkError),
+	/// Hash mismatch error.
+	#[error("Hash mismatch: {0} (expected) != {1} (actual)")]
+	HashMismatch(String, String),
+	/// Storage quota exceeded.
+	#[error("Quota exceeded: {0} bytes needed, only {1} bytes can be evicted")]
+	QuotaExceeded(u64, u64),
+	/// Object is not in the storage.
+	#[error("Object is missing: {0}")]
+	MissingObject(String),
+	/// Zip archive error.
+	#[error("Zip error: {0}")]
+	ZipError(#[from] zip::result::ZipError),
+	/// Invalid object hash.
+	#[error("Invalid hash: {0}")]
+	InvalidHash(String),
+	/// Invalid name of a version or product, which can't be used in paths.
+	#[error("Invalid name: {0}")]
+	InvalidName(String),
+	/// Storage is used by another process.
+	#[error("Storage is used by another process")]
+	Busy,
+	/// Failed to parse saved index or version manifest.
+	#[error("Parse error: {0}")]
+	ParseError(#[from] serde_json::Error),
+	/// Invalid CAR archive.
+	#[error("CAR error: {0}")]
+	CarError(#[from] crate::utils::car::CarError),
+	/// Operation isn't supported by this storage, e.g. because it requires
+	/// objects to be local files, see [`StorageBackend::is_local`].
+	#[error("Not supported by this storage: {0}")]
+	Unsupported(&'static str),
+}
+
+impl Retryable for StorageError {
+	fn is_retryable(&self) -> bool {
+		match self {
+			Self::NetworkError(e) => e.is_retryable(),
+			// Transfer was corrupted, next attempt will likely succeed
+			Self::HashMismatch(_, _) | Self::CarError(_) => true,
+			_ => false,
+		}
+	}
+}
+
+/// Asset storage.
+#[derive(Debug)]
+pub struct Storage {
+	client: Arc<NetClient>,
+	storage_dir: PathBuf,
+	files: FsBackend,
+	backend: Arc<dyn StorageBackend>,
+	locks: Mutex<HashMap<ContentHash, Arc<tokio::sync::Mutex<()>>>>,
+	writers: Arc<tokio::sync::RwLock<()>>,
+	dir_lock: Option<tokio::sync::OnceCell<std::fs::File>>,
+	quota: Option<u64>,
+	pins: tokio::sync::Mutex<pin::Pins>,
+	accessed: Mutex<HashMap<ContentHash, SystemTime>>,
+	usage: tokio::sync::Mutex<Option<u64>>,
+	reserved: AtomicU64,
+}
+
+impl Storage {
+	/// Creates a new storage.
+	///
+	/// This function will create all required directories if they don't exist.
+	///
+	/// The storage directory is locked for the lifetime of the storage, so
+	/// other processes know it's in use. If another process is running the
+	/// garbage collection, the lock is taken later, before the first object is
+	/// written, so this function never blocks. Failure to take the lock is
+	/// returned from the operations which need it.
+	pub fn new(client: Arc<NetClient>, storage_dir_opt: Option<PathBuf>) -> Self {
+		let storage_dir = match storage_dir_opt {
+			Some(dir) => dir,
+			None => data_dir().unwrap().join("FireLaunch"),
+		};
+
+		// Create directories if they don't exist
+		let create_dirs: [PathBuf; 4] = [
+			storage_dir.join("objects"),
+			storage_dir.join("indexes"),
+			storage_dir.join("libraries"),
+			storage_dir.join("versions"),
+		];
+		for dir in create_dirs.iter() {
+			if !dir.exists() && std::fs::create_dir_all(dir).is_err() {
+				error!("Failed to create directory: {}", dir.display());
+			}
+		}
+
+		let dir_lock = match Self::lock_dir(&storage_dir, false) {
+			Ok(file) => tokio::sync::OnceCell::new_with(file),
+			Err(e) => {
+				error!("Failed to lock storage directory: {}", e);
+				tokio::sync::OnceCell::new()
+			}
+		};
+
+		let backend = Arc::new(
+			FsBackend::new(storage_dir.join("objects")).with_locks_dir(storage_dir.join("locks")),
+		);
+		Self {
+			dir_lock: Some(dir_lock),
+			..Self::with_backend(client, storage_dir, backend)
+		}
+	}
+
+	/// Creates a new storage keeping objects in the given backend.
+	///
+	/// `storage_dir` is used for asset indexes and version manifests. Unlike
+	/// [`Storage::new`], this function doesn't create any directories.
+	///
+	/// Paths returned by the storage point into `storage_dir/objects` and exist
+	/// only if the backend keeps objects there, like [`Storage::new`] does.
+	pub fn with_backend(
+		client: Arc<NetClient>,
+		storage_dir: PathBuf,
+		backend: Arc<dyn StorageBackend>,
+	) -> Self {
+		Self {
+			files: FsBackend::new(storage_dir.join("objects")),
+			backend,
+			storage_dir,
+			client,
+			locks: Mutex::new(HashMap::new()),
+			writers: Arc::new(tokio::sync::RwLock::new(())),
+			dir_lock: None,
+			quota: None,
+			pins: tokio::sync::Mutex::new(pin::Pins::default()),
+			accessed: Mutex::new(HashMap::new()),
+			usage: tokio::sync::Mutex::new(None),
+			reserved: AtomicU64::new(0),
+		}
+	}
+
+	/// Get storage root directory.
+	#[inline]
+	pub fn storage_dir(&self) -> &Path {
+		&self.storage_dir
+	}
+
+	/// Get storage backend.
+	#[inline]
+	pub fn backend(&self) -> &dyn StorageBackend {
+		self.backend.as_ref()
+	}
+
+	/// Return [`StorageError::Unsupported`] if the backend doesn't keep objects
+	/// as local files, which the `operation` requires.
+	pub(crate) fn require_local(&self, operation: &'static str) -> Result<(), StorageError> {
+		match self.backend.is_local() {
+			true => Ok(()),
+			false => Err(StorageError::Unsupported(operation)),
+		}
+	}
+
+	/// Get directory with objects hashed with the given algorithm.
+	///
+	/// SHA-1 objects are stored directly in `objects/`, like in the vanilla
+	/// launcher. Objects hashed with other algorithms are stored in
+	/// `objects/<algorithm>/`.
+	#[inline]
+	pub fn get_objects_dir(&self, algorithm: HashAlgorithm) -> PathBuf {
+		self.files.objects_dir(algorithm)
+	}
+
+	/// Get object path.
+	#[inline]
+	pub fn get_object_path(&self, hash: &ContentHash) -> PathBuf {
+		self.files.path(hash)
+	}
+
+	/// Get asset path.
+	#[inline]
+	pub fn get_asset_path(&self, sha1_hash: &str) -> PathBuf {
+		self.get_object_path(&ContentHash::sha1(sha1_hash))
+	}
+
+	/// Get index path.
+	pub fn get_index_path(&self, sha1_hash: &str) -> PathBuf {
+		self.storage_dir
+			.join("indexes")
+			.join(format!("{sha1_hash}.json"))
+	}
+
+	/// Get saved version manifest path.
+	pub fn get_version_path(&self, uid: &str, version: &str) -> PathBuf {
+		self.storage_dir
+			.join("versions")
+			.join(uid)
+			.join(format!("{version}.json"))
+	}
+
+	/// List all objects in the storage.
+	///
+	/// Returns hash, path and size of every object of every algorithm, as
+	/// listed by the backend. Partially downloaded objects are skipped.
+	///
+	/// Paths exist only if the backend keeps objects as local files, see
+	/// [`Storage::get_object_path`].
+	pub async fn list_objects(&self) -> Result<Vec<(ContentHash, PathBuf, u64)>, StorageError> {
+		Ok(self
+			.backend
+			.list()
+			.await?
+			.into_iter()
+			.map(|(hash, size)| {
+				let path = self.get_object_path(&hash);
+				(hash, path, size)
+			})
+			.collect())
+	}
+
+	/// Download object from the given IPFS path.
+	///
+	/// This function will also verify the hash of the downloaded object.
+	///
+	/// Object is downloaded to a `.part` file next to it and renamed into place
+	/// only after its hash is verified, so a partially downloaded or corrupted
+	/// object never appears under its content-addressed name. Interrupted
+	/// download is resumed from the `.part` file.
+	///
+	/// If the same object is being downloaded by another task or process, this
+	/// function waits for it and doesn't download the object again.
+	///
+	/// If the storage has a quota, least recently used objects are evicted to
+	/// make room for the new one.
+	///
+	/// If the backend doesn't keep objects as local files, the object is
+	/// downloaded into memory and put into the backend instead.
+	///
+	/// Failed download is retried according to the client's
+	/// [`RetryPolicy`](crate::utils::retry::RetryPolicy).
+	///
+	/// In offline mode the stored object is returned if it's valid, or
+	/// [`StorageError::MissingObject`] otherwise.
+	///
+	/// Progress is reported to the client's
+	/// [`ProgressHandler`](crate::utils::progress::ProgressHandler) with `path`
+	/// as the download id.
+	///
+	/// If `size` is given, the download is aborted as soon as the object is
+	/// known to have a different size, with
+	/// [`NetworkError::SizeMismatch`](crate::utils::net::NetworkError::SizeMismatch).
+	pub async fn download_object(
+		&self,
+		hash: &ContentHash,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		if self.is_offline() {
+			// Only stored objects can be served
+			return match self.check_object(hash).await? {
+				true => Ok(self.get_object_path(hash)),
+				false => Err(StorageError::MissingObject(hash.to_string())),
+			};
+		}
+		self.client
+			.retry_policy()
+			.retry(|| self.try_download_object(hash, path, size))
+			.await
+	}
+
+	/// Single attempt of [`Storage::download_object`].
+	async fn try_download_object(
+		&self,
+		hash: &ContentHash,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		debug!("Downloading object: {}", hash);
+		validate_hash(hash)?;
+		let dest_path = match self.backend.local_path(hash) {
+			Some(dest_path) => dest_path,
+			None => {
+				self.download_object_to_backend(hash, path, size).await?;
+				return Ok(self.get_object_path(hash));
+			}
+		};
+		tokio::fs::create_dir_all(dest_path.parent().unwrap()).await?;
+		let lock = self.lock_object(hash).await?;
+		if lock.waited() && self.backend.verify(hash).await? {
+			debug!("Object was downloaded by another task: {}", hash);
+			return Ok(dest_path);
+		}
+		let reservation = match size {
+			Some(size) => self.reserve_space(size).await?,
+			None => None,
+		};
+		let downloaded_hash = self
+			.client
+			.download_ipfs_part_with(path, &dest_path, hash.algorithm, size, hash.as_sha1())
+			.await?;
+		drop(reservation);
+		let part_path = part_path(&dest_path);
+		if hash.hex != downloaded_hash {
+			tokio::fs::remove_file(&part_path).await?;
+			return Err(StorageError::HashMismatch(
+				hash.hex.clone(),
+				downloaded_hash,
+			));
+		}
+		self.commit_object(&part_path, &dest_path).await?;
+		Ok(dest_path)
+	}
+
+	/// Download object into memory and put it into the backend.
+	///
+	/// Used for backends which don't keep objects as local files.
+	async fn download_object_to_backend(
+		&self,
+		hash: &ContentHash,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<(), StorageError> {
+		if self.quota.is_some() {
+			self.require_local("quota")?;
+		}
+		let lock = self.lock_object(hash).await?;
+		if lock.waited() && self.backend.verify(hash).await? {
+			return Ok(());
+		}
+		let data = self
+			.client
+			.download_ipfs_bytes(path, size, hash.as_sha1())
+			.await?;
+		let downloaded_hash = ContentHash::digest(hash.algorithm, &data);
+		if *hash != downloaded_hash {
+			return Err(StorageError::HashMismatch(
+				hash.hex.clone(),
+				downloaded_hash.hex,
+			));
+		}
+		self.backend.put(hash, &data).await
+	}
+
+	/// Download object if it doesn't exist.
+	///
+	/// If the object already exists, this function will return the path to the
+	/// existing object without downloading or verifying it again.
+	/// If the object doesn't exist, this function will download it and verify
+	/// its hash.
+	pub async fn download_object_if_not_exists(
+		&self,
+		hash: &ContentHash,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		validate_hash(hash)?;
+		let dest_path = self.get_object_path(hash);
+		if self.backend.exists(hash).await? {
+			self.touch(hash);
+		} else {
+			debug!("Object doesn't exist, downloading: {}", hash);
+			self.download_object(hash, path, size).await?;
+		}
+		Ok(dest_path)
+	}
+
+	/// Download object if it doesn't exist or has the wrong hash.
+	pub async fn download_object_if_invalid(
+		&self,
+		hash: &ContentHash,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		validate_hash(hash)?;
+		let dest_path = self.get_object_path(hash);
+		if !self.check_object(hash).await? {
+			debug!(
+				"Object doesn't exist or has wrong hash, downloading: {}",
+				hash
+			);
+			self.download_object(hash, path, size).await?;
+		}
+		Ok(dest_path)
+	}
+
+	/// Check if the given object exists and has the correct hash.
+	///
+	/// This function will return `true` if the object exists and has the correct
+	/// hash, `false` if the object doesn't exist or has the wrong hash.
+	pub async fn check_object(&self, hash: &ContentHash) -> Result<bool, StorageError> {
+		validate_hash(hash)?;
+		let valid = self.backend.verify(hash).await?;
+		if valid {
+			self.touch(hash);
+		}
+		Ok(valid)
+	}
+
+	/// Read the whole object.
+	///
+	/// Returns [`StorageError::MissingObject`] if the object is not stored.
+	pub async fn read_object(&self, hash: &ContentHash) -> Result<Vec<u8>, StorageError> {
+		validate_hash(hash)?;
+		let data = self.backend.get(hash).await?;
+		self.touch(hash);
+		Ok(data)
+	}
+
+	/// Download SHA-1 object from the given IPFS path.
+	///
+	/// Proxy for [`Storage::download_object`].
+	#[inline]
+	pub async fn download_asset(
+		&self,
+		sha1_hash: &str,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		self.download_object(&ContentHash::sha1(sha1_hash), path, size)
+			.await
+	}
+
+	/// Download SHA-1 object if it doesn't exist.
+	///
+	/// Proxy for [`Storage::download_object_if_not_exists`].
+	#[inline]
+	pub async fn download_asset_if_not_exists(
+		&self,
+		sha1_hash: &str,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		self.download_object_if_not_exists(&ContentHash::sha1(sha1_hash), path, size)
+			.await
+	}
+
+	/// Download SHA-1 object if it doesn't exist or has the wrong hash.
+	///
+	/// Proxy for [`Storage::download_object_if_invalid`].
+	#[inline]
+	pub async fn download_asset_if_invalid(
+		&self,
+		sha1_hash: &str,
+		path: &str,
+		size: Option<u64>,
+	) -> Result<PathBuf, StorageError> {
+		self.download_object_if_invalid(&ContentHash::sha1(sha1_hash), path, size)
+			.await
+	}
+
+	/// Check if the given SHA-1 object exists and has the correct hash.
+	///
+	/// Proxy for [`Storage::check_object`].
+	#[inline]
+	pub async fn check_asset(&self, sha1_hash: &str) -> Result<bool, StorageError> {
+		self.check_object(&ContentHash::sha1(sha1_hash)).await
+	}
+
+	/// Read the whole SHA-1 object.
+	///
+	/// Proxy for [`Storage::read_object`].
+	#[inline]
+	pub async fn read_asset(&self, sha1_hash: &str) -> Result<Vec<u8>, StorageError> {
+		self.read_object(&ContentHash::sha1(sha1_hash)).await
+	}
+}
+
+/// Return [`StorageError::InvalidHash`] if the hash is malformed.
+///
+/// Malformed hashes must not be used in object paths, because they may point
+/// outside of the objects directory.
+pub(crate) fn validate_hash(hash: &ContentHash) -> Result<(), StorageError> {
+	match hash.is_valid() {
+		true => Ok(()),
+		false => Err(StorageError::InvalidHash(hash.to_string())),
+	}
+}
+
+/// Calculate hex encoded hash of the given file.
+pub(crate) async fn hash_file(
+	path: &Path,
+	algorithm: HashAlgorithm,
+) -> Result<String, StorageError> {
+	let mut hasher = algorithm.hasher();
+	let mut reader = tokio::fs::File::open(path).await?;
+	let mut buffer = vec![0; 128 * 1024];
+	loop {
+		let n = reader.read(&mut buffer).await?;
+		if n == 0 {
+			break;
+		}
+		hasher.update(&buffer[..n]);
+	}
+	Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::structures::version_manifest::VersionManifest;
+	use crate::utils::crypto::{generate_random_string, sha1_digest};
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::retry::RetryPolicy;
+	use crate::utils::test_server::{Route, TestServer};
+	use std::time::Duration;
+
+	/// Create storage in a fresh temporary directory.
+	///
+	/// The directory is removed when the returned guard is dropped.
+	pub(crate) fn temp_storage() -> (TempDir, Storage) {
+		temp_storage_with_client(NetClient::new())
+	}
+
+	/// Create storage with the given client in a fresh temporary directory.
+	pub(crate) fn temp_storage_with_client(client: NetClient) -> (TempDir, Storage) {
+		let dir = TempDir::new();
+		let storage = Storage::new(Arc::new(client), Some(dir.path().to_path_buf()));
+		(dir, storage)
+	}
+
+	/// Create storage keeping objects in memory.
+	///
+	/// Storage directory is never created.
+	pub(crate) fn memory_storage(client: NetClient) -> Storage {
+		let dir =
+			std::env::temp_dir().join(format!("firelaunch-test-{}", generate_random_string(16)));
+		Storage::with_backend(Arc::new(client), dir, Arc::new(MemoryBackend::new()))
+	}
+
+	/// Put given data into the storage as an object and return its hash.
+	pub(crate) fn put_object(storage: &Storage, data: &[u8]) -> String {
+		let hash = crate::utils::crypto::sha1_digest(data);
+		let path = storage.get_asset_path(&hash);
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		std::fs::write(path, data).unwrap();
+		hash
+	}
+
+	/// Create a version manifest with the given asset index, client and
+	/// library hashes.
+	pub(crate) fn test_manifest(asset_index: &str, client: &str, library: &str) -> VersionManifest {
+		serde_json::from_value(serde_json::json!({
+			"+traits": [],
+			"formatVersion": 1,
+			"assetIndex": {"sha1": asset_index, "size": 0, "path": "CID", "totalSize": 0, "id": "1.12"},
+			"mainJar": {
+				"name": "com.mojang:minecraft:1.12.2:client",
+				"downloads": {"artifact": {"sha1": client, "size": 6, "path": "CID"}}
+			},
+			"libraries": [{
+				"name": "org.lwjgl:lwjgl:3.2.2",
+				"downloads": {
+					"artifact": {"sha1": library, "size": 7, "path": "CID"},
+					"classifiers": {
+						"natives-other": {"sha1": sha1_digest(b"other"), "size": 5, "path": "CID"}
+					}
+				}
+			}],
+			"version": "1.12.2",
+			"type": "release",
+			"releaseTime": "2017-09-18T08:39:46+00:00",
+			"name": "Minecraft",
+			"productUid": "net.minecraft"
+		}))
+		.unwrap()
+	}
+
+	/// Install the version into the storage, downloading its artifacts with a
+	/// scheduler.
+	pub(crate) async fn install_version(
+		storage: Storage,
+		manifest: &VersionManifest,
+	) -> Arc<Storage> {
+		let storage = Arc::new(storage);
+		let scheduler = DownloadScheduler::new(storage.clone());
+		manifest.install(&scheduler).await.unwrap();
+		storage
+	}
+
+	#[tokio::test]
+	async fn test_check_asset() {
+		let (_dir, storage) = temp_storage();
+		let hash = put_object(&storage, b"Hello, world!");
+		assert!(storage.check_asset(&hash).await.unwrap());
+
+		std::fs::write(storage.get_asset_path(&hash), b"corrupted").unwrap();
+		assert!(!storage.check_asset(&hash).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_download_asset_hash_mismatch() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/good", Route::ok(b"good"));
+		server.route("/ipfs/bad", Route::ok(b"bad"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let policy = RetryPolicy {
+			max_attempts: 2,
+			initial_delay: Duration::from_millis(1),
+			..RetryPolicy::default()
+		};
+		client.set_retry_policy(policy);
+		let (_dir, storage) = temp_storage_with_client(client);
+		let hash = crate::utils::crypto::sha1_digest(b"good");
+
+		let result = storage.download_asset(&hash, "bad", None).await;
+		assert!(matches!(result, Err(StorageError::HashMismatch(_, _))));
+		// Corrupted transfer is retried
+		assert_eq!(server.requests().len(), 2);
+		let dest_path = storage.get_asset_path(&hash);
+		assert!(!dest_path.exists());
+		// Corrupted download is not kept for resuming
+		assert!(!part_path(&dest_path).exists());
+
+		let path = storage
+			.download_asset(&hash, "good", Some(4))
+			.await
+			.unwrap();
+		assert_eq!(path, dest_path);
+		assert!(storage.check_asset(&hash).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn test_download_object_sha512() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/mod", Route::ok(b"mod"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let (_dir, storage) = temp_storage_with_client(client);
+		let hash = ContentHash::digest(HashAlgorithm::Sha512, b"mod");
+		let sha1 = put_object(&storage, b"mod");
+
+		let path = storage
+			.download_object(&hash, "mod", Some(3))
+			.await
+			.unwrap();
+		assert!(path.starts_with(storage.storage_dir().join("objects").join("sha512")));
+		assert!(storage.check_object(&hash).await.unwrap());
+
+		let mut objects: Vec<ContentHash> = storage
+			.list_objects()
+			.await
+			.unwrap()
+			.into_iter()
+			.map(|(hash, _, _)| hash)
+			.collect();
+		objects.sort_by_key(|hash| hash.algorithm.hex_len());
+		assert_eq!(objects, vec![ContentHash::sha1(&sha1), hash]);
+
+		let result = storage
+			.download_object(&ContentHash::sha1("../../passwd"), "mod", None)
+			.await;
+		assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+	}
+
+	#[tokio::test]
+	async fn test_list_backend_objects() {
+		let storage = memory_storage(NetClient::new());
+		let hash = ContentHash::digest(HashAlgorithm::Sha256, b"object");
+		storage.backend().put(&hash, b"object").await.unwrap();
+		let objects = storage.list_objects().await.unwrap();
+		assert_eq!(
+			objects,
+			vec![(hash.clone(), storage.get_object_path(&hash), 6)]
+		);
+		assert!(!storage.storage_dir().exists());
+	}
+
+	#[tokio::test]
+	async fn test_invalid_hash() {
+		let (_dir, storage) = temp_storage();
+		for hex in ["", "a", "é", "../../../../../../../etc/passwd"] {
+			let hash = ContentHash::sha1(hex);
+			let result = storage.download_object_if_not_exists(&hash, "", None).await;
+			assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+			let result = storage.download_object_if_invalid(&hash, "", None).await;
+			assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+			let result = storage.check_object(&hash).await;
+			assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+			let result = storage.read_object(&hash).await;
+			assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+			let result = storage.backend().exists(&hash).await;
+			assert!(matches!(result, Err(StorageError::InvalidHash(_))));
+		}
+	}
+}
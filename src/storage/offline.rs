@@ -0,0 +1,168 @@
+//! Offline mode support.
+//!
+//! When the client is offline (see [`NetClient::set_offline`]), the storage
+//! serves only objects it already has: [`Storage::download_object`] returns a
+//! stored object if it's valid and [`StorageError::MissingObject`] otherwise.
+//! [`Storage::missing_objects`] lets the launcher check that a version can be
+//! started before it tries to.
+//!
+//! [`NetClient::set_offline`]: crate::utils::net::NetClient::set_offline
+
+use std::collections::HashSet;
+
+use super::{Storage, StorageError};
+use crate::structures::{asset_index::AssetIndex, version_manifest::VersionManifest};
+use crate::utils::crypto::ContentHash;
+
+impl Storage {
+	/// Check if the storage client is in offline mode.
+	#[inline]
+	pub fn is_offline(&self) -> bool {
+		self.client.is_offline()
+	}
+
+	/// Get SHA-1 hashes of objects the version needs to start on the current
+	/// OS.
+	///
+	/// These are the asset index with all its assets, the main jar and
+	/// libraries which rules are satisfied. The asset index must be stored,
+	/// otherwise [`StorageError::MissingObject`] is returned.
+	///
+	/// It does blocking IO, so call it with [`tokio::task::spawn_blocking`]
+	/// from async code.
+	pub fn required_objects(
+		&self,
+		manifest: &VersionManifest,
+	) -> Result<HashSet<String>, StorageError> {
+		let asset_index = match &manifest.asset_index {
+			Some(artifact) => Some(
+				AssetIndex::parse(&self.get_asset_path(&artifact.sha1))
+					.map_err(|_| StorageError::MissingObject(artifact.sha1.clone()))?,
+			),
+			None => None,
+		};
+		Ok(required_by(manifest, asset_index.as_ref()))
+	}
+
+	/// Get SHA-1 hashes of objects the version needs, but the storage doesn't
+	/// have.
+	///
+	/// If the result is empty, the version can be installed and started
+	/// without network. Objects are checked for existence only, use
+	/// [`Storage::audit`] to verify their contents.
+	pub async fn missing_objects(
+		&self,
+		manifest: &VersionManifest,
+	) -> Result<Vec<String>, StorageError> {
+		let asset_index = match &manifest.asset_index {
+			Some(artifact) => match AssetIndex::read(self, &artifact.sha1).await {
+				Ok(asset_index) => Some(asset_index),
+				// Assets are unknown without the asset index
+				Err(_) => return Ok(vec![artifact.sha1.clone()]),
+			},
+			None => None,
+		};
+		let mut missing = Vec::new();
+		for hash in required_by(manifest, asset_index.as_ref()) {
+			if !self.backend.exists(&ContentHash::sha1(&hash)).await? {
+				missing.push(hash);
+			}
+		}
+		missing.sort();
+		Ok(missing)
+	}
+}
+
+/// Get SHA-1 hashes of objects the version needs to start on the current OS,
+/// with assets of the given asset index.
+fn required_by(manifest: &VersionManifest, asset_index: Option<&AssetIndex>) -> HashSet<String> {
+	let mut required: HashSet<String> = HashSet::new();
+	if let Some(artifact) = &manifest.asset_index {
+		required.insert(artifact.sha1.clone());
+	}
+	if let Some(asset_index) = asset_index {
+		required.extend(asset_index.get_assets().map(|asset| asset.hash));
+	}
+	if let Some(main_jar) = &manifest.main_jar {
+		required.extend(main_jar.downloads.iter().map(|a| a.sha1.clone()));
+	}
+	for library in &manifest.libraries {
+		required.extend(library.get_artifacts().into_iter().map(|a| a.sha1));
+	}
+	required
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::{put_object, temp_storage_with_client};
+	use crate::structures::version_manifest::AssetIndexArtifact;
+	use crate::utils::crypto::sha1_digest;
+	use crate::utils::net::NetClient;
+	use crate::utils::test_server::{Route, TestServer};
+
+	#[tokio::test]
+	async fn test_offline_storage() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/missing", Route::ok(b"missing"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		client.set_offline(true);
+		let (_dir, storage) = temp_storage_with_client(client);
+		assert!(storage.is_offline());
+
+		let cached = put_object(&storage, b"cached");
+		let path = storage
+			.download_asset(&cached, "cached", Some(6))
+			.await
+			.unwrap();
+		assert_eq!(path, storage.get_asset_path(&cached));
+		let missing = sha1_digest(b"missing");
+		let result = storage
+			.download_asset_if_invalid(&missing, "missing", None)
+			.await;
+		assert!(matches!(result, Err(StorageError::MissingObject(_))));
+		assert!(server.requests().is_empty());
+
+		let manifest: VersionManifest = serde_json::from_value(serde_json::json!({
+			"+traits": [],
+			"formatVersion": 1,
+			"version": "1.0",
+			"type": "release",
+			"releaseTime": "2017-09-18T08:39:46+00:00",
+			"name": "Test",
+			"productUid": "test",
+			"libraries": [
+				{"name": "a:cached:1", "downloads": {"artifact": {"sha1": cached, "size": 6, "path": "cached"}}},
+				{"name": "a:missing:1", "downloads": {"artifact": {"sha1": missing, "size": 7, "path": "missing"}}}
+			]
+		}))
+		.unwrap();
+		assert_eq!(
+			storage.missing_objects(&manifest).await.unwrap(),
+			vec![missing.clone()]
+		);
+
+		// Assets are required too, once the asset index is stored
+		let index = br#"{"objects": {"sound.ogg": {"hash": "0123456789abcdef0123456789abcdef01234567", "path": "sound", "size": 5}}}"#;
+		let mut manifest = manifest;
+		manifest.asset_index = Some(AssetIndexArtifact {
+			sha1: sha1_digest(index),
+			size: index.len() as u64,
+			path: "index".to_string(),
+			total_size: 5,
+			id: "1.0".to_string(),
+		});
+		assert_eq!(
+			storage.missing_objects(&manifest).await.unwrap(),
+			vec![sha1_digest(index)]
+		);
+		put_object(&storage, index);
+		let mut expected = vec![
+			missing,
+			"0123456789abcdef0123456789abcdef01234567".to_string(),
+		];
+		expected.sort();
+		assert_eq!(storage.missing_objects(&manifest).await.unwrap(), expected);
+	}
+}
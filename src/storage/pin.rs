@@ -0,0 +1,255 @@
+//! Pinned objects.
+//!
+//! Pinned objects (e.g. objects of the selected version) are never evicted to
+//! satisfy the quota or removed by the garbage collection. Pins are additive,
+//! see [`Storage::add_pinned`] and [`Storage::unpin`], and not counted: an
+//! object pinned twice is unpinned by a single call.
+//!
+//! Pins of a storage created with [`Storage::new`] are shared with other
+//! processes using the same storage directory. Each storage writes its pins to
+//! `pins/<id>.json` and holds a lock on `pins/<id>.lock` for its lifetime.
+//! Pins whose lock isn't held belong to a process which exited without removing
+//! them, so they are ignored and removed.
+
+use std::collections::HashSet;
+use std::fs::{File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::lock::open_lock_file;
+use super::{write_file_atomic, Storage, StorageError};
+use crate::structures::{asset_index::AssetIndex, version_manifest::VersionManifest};
+use crate::utils::crypto::{generate_random_string, ContentHash};
+
+/// Objects pinned by a storage.
+#[derive(Debug, Default)]
+pub(crate) struct Pins {
+	hashes: HashSet<ContentHash>,
+	file: Option<PinFile>,
+}
+
+/// Pins published for other processes, removed when dropped.
+#[derive(Debug)]
+struct PinFile {
+	id: String,
+	dir: PathBuf,
+	_lock: File,
+}
+
+impl PinFile {
+	/// Create a new uniquely named pin file in `dir` and lock it.
+	fn create(dir: &Path) -> io::Result<Self> {
+		let id = generate_random_string(16);
+		let lock = open_lock_file(&dir.join(format!("{}.lock", id)))?;
+		lock.lock()?;
+		Ok(Self {
+			id,
+			dir: dir.to_path_buf(),
+			_lock: lock,
+		})
+	}
+
+	/// Get path of the file with pinned hashes.
+	fn path(&self) -> PathBuf {
+		self.dir.join(format!("{}.json", self.id))
+	}
+}
+
+impl Drop for PinFile {
+	fn drop(&mut self) {
+		for path in [self.path(), self.dir.join(format!("{}.lock", self.id))] {
+			if let Err(e) = std::fs::remove_file(&path) {
+				debug!("Failed to remove pin file {}: {}", path.display(), e);
+			}
+		}
+	}
+}
+
+impl Storage {
+	/// Pins the given objects.
+	///
+	/// Pinned objects are never evicted to satisfy the quota.
+	pub async fn add_pinned(
+		&self,
+		hashes: impl IntoIterator<Item = ContentHash>,
+	) -> Result<(), StorageError> {
+		let mut pins = self.pins.lock().await;
+		pins.hashes.extend(hashes);
+		self.publish_pins(&mut pins).await
+	}
+
+	/// Unpins the given objects.
+	pub async fn unpin(
+		&self,
+		hashes: impl IntoIterator<Item = ContentHash>,
+	) -> Result<(), StorageError> {
+		let mut pins = self.pins.lock().await;
+		for hash in hashes {
+			pins.hashes.remove(&hash);
+		}
+		self.publish_pins(&mut pins).await
+	}
+
+	/// Returns objects pinned by this storage.
+	pub async fn pinned(&self) -> HashSet<ContentHash> {
+		self.pins.lock().await.hashes.clone()
+	}
+
+	/// Pins all objects of the given version.
+	///
+	/// This includes artifacts of the version, the asset index and all assets
+	/// in `asset_index`. Artifacts and the asset index root are also pinned on
+	/// the local IPFS node in the background, if the client has one, see
+	/// [`NetClient::pin_ipfs_in_background`](crate::utils::net::NetClient::pin_ipfs_in_background).
+	///
+	/// This should be called when the version is installed or selected.
+	pub async fn pin_version(
+		&self,
+		manifest: &VersionManifest,
+		asset_index: Option<&AssetIndex>,
+	) -> Result<(), StorageError> {
+		let artifacts = manifest.all_artifacts();
+		let mut pinned: HashSet<ContentHash> = artifacts
+			.iter()
+			.map(|artifact| ContentHash::sha1(&artifact.sha1))
+			.collect();
+		let mut cids: Vec<String> = artifacts
+			.iter()
+			.map(|artifact| artifact.path.clone())
+			.collect();
+		if let Some(asset_index) = asset_index {
+			pinned.extend(
+				asset_index
+					.get_assets()
+					.map(|asset| ContentHash::sha1(&asset.hash)),
+			);
+			cids.extend(asset_index.root.clone());
+		}
+		self.add_pinned(pinned).await?;
+		self.client.pin_ipfs_in_background(cids);
+		Ok(())
+	}
+
+	/// Returns objects pinned by this storage and by other processes using
+	/// the same storage directory.
+	pub(crate) async fn all_pinned(&self) -> Result<HashSet<ContentHash>, StorageError> {
+		let pins = self.pins.lock().await;
+		let mut hashes = pins.hashes.clone();
+		if self.dir_lock.is_none() {
+			return Ok(hashes);
+		}
+		let own_id = pins.file.as_ref().map(|file| file.id.as_str());
+		let dir = self.get_pins_dir();
+		let mut entries = match tokio::fs::read_dir(&dir).await {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(hashes),
+			Err(e) => return Err(e.into()),
+		};
+		while let Some(entry) = entries.next_entry().await? {
+			let path = entry.path();
+			let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+				Some(id) if path.extension().is_some_and(|ext| ext == "json") => id,
+				_ => continue,
+			};
+			if Some(id) == own_id {
+				continue;
+			}
+			let lock_path = dir.join(format!("{}.lock", id));
+			if !is_locked(&lock_path)? {
+				debug!("Removing stale pins: {}", path.display());
+				let _ = tokio::fs::remove_file(&path).await;
+				let _ = tokio::fs::remove_file(&lock_path).await;
+				continue;
+			}
+			let data = match tokio::fs::read(&path).await {
+				Ok(data) => data,
+				// Removed by its owner in the meantime
+				Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e.into()),
+			};
+			match serde_json::from_slice::<Vec<ContentHash>>(&data) {
+				Ok(other) => hashes.extend(other),
+				Err(e) => warn!("Failed to parse pins {}: {}", path.display(), e),
+			}
+		}
+		Ok(hashes)
+	}
+
+	/// Write pins of this storage for other processes.
+	async fn publish_pins(&self, pins: &mut Pins) -> Result<(), StorageError> {
+		if self.dir_lock.is_none() {
+			return Ok(());
+		}
+		let file = match &mut pins.file {
+			Some(file) => file,
+			file => file.insert(PinFile::create(&self.get_pins_dir())?),
+		};
+		let data = serde_json::to_vec(&pins.hashes)?;
+		write_file_atomic(&file.path(), &data).await?;
+		Ok(())
+	}
+
+	/// Get directory with pins of all processes.
+	fn get_pins_dir(&self) -> PathBuf {
+		self.storage_dir.join("pins")
+	}
+}
+
+/// Check whether the given lock file is held by a running process.
+fn is_locked(path: &Path) -> io::Result<bool> {
+	let file = match File::open(path) {
+		Ok(file) => file,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+		Err(e) => return Err(e),
+	};
+	match file.try_lock_shared() {
+		Ok(()) => Ok(false),
+		Err(TryLockError::WouldBlock) => Ok(true),
+		Err(TryLockError::Error(e)) => Err(e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::temp_storage;
+	use crate::utils::crypto::HashAlgorithm;
+	use crate::utils::net::NetClient;
+	use std::sync::Arc;
+
+	#[tokio::test]
+	async fn test_pins() {
+		let (_dir, storage) = temp_storage();
+		let hash = |data: &str| ContentHash::digest(HashAlgorithm::Sha1, data.as_bytes());
+
+		storage.add_pinned([hash("a"), hash("b")]).await.unwrap();
+		storage.add_pinned([hash("c")]).await.unwrap();
+		storage.unpin([hash("b")]).await.unwrap();
+		assert_eq!(
+			storage.pinned().await,
+			HashSet::from([hash("a"), hash("c")])
+		);
+
+		// Another process using the same storage directory sees the pins
+		let other = Storage::new(
+			Arc::new(NetClient::new()),
+			Some(storage.storage_dir().to_path_buf()),
+		);
+		other.add_pinned([hash("d")]).await.unwrap();
+		assert_eq!(
+			storage.all_pinned().await.unwrap(),
+			HashSet::from([hash("a"), hash("c"), hash("d")])
+		);
+		drop(other);
+		assert_eq!(
+			storage.all_pinned().await.unwrap(),
+			HashSet::from([hash("a"), hash("c")])
+		);
+
+		// Pins of a process which exited without removing them
+		let stale = storage.get_pins_dir().join("stale.json");
+		std::fs::write(&stale, serde_json::to_vec(&[hash("e")]).unwrap()).unwrap();
+		assert!(!storage.all_pinned().await.unwrap().contains(&hash("e")));
+		assert!(!stale.exists());
+	}
+}
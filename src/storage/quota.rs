@@ -0,0 +1,341 @@
+//! Disk quota and LRU eviction for the object store.
+//!
+//! When a quota is set, objects are evicted in least-recently-used order
+//! whenever a new object wouldn't fit otherwise. If the size of an object is
+//! known, room is made before it's downloaded. Last access time of an object is
+//! tracked in memory every time the object is requested from the storage.
+//! Objects which weren't requested by this process are ordered by the time
+//! they were stored. Modification times are never updated, because hard linked
+//! objects share them with files of the game directory.
+//!
+//! Pinned objects (e.g. objects of the selected version) and objects being
+//! written are never evicted, see [`Storage::add_pinned`].
+//! [`VersionManifest::install`](crate::structures::version_manifest::VersionManifest::install)
+//! pins objects of the installed version.
+//!
+//! The launcher reads the quota from the [`QUOTA_ENV`] environment variable,
+//! see [`quota_from_env`].
+
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use super::{Storage, StorageError};
+use crate::utils::crypto::ContentHash;
+
+/// Environment variable with maximum total size of objects in MiB.
+pub const QUOTA_ENV: &str = "FIRELAUNCH_STORAGE_QUOTA";
+
+/// Read the quota in bytes from the [`QUOTA_ENV`] environment variable.
+///
+/// Returns `None` if the variable is not set or is not a number of MiB.
+pub fn quota_from_env() -> Option<u64> {
+	let value = std::env::var(QUOTA_ENV).ok()?;
+	match parse_quota(&value) {
+		Some(quota) => Some(quota),
+		None => {
+			warn!("Invalid {}: {}", QUOTA_ENV, value);
+			None
+		}
+	}
+}
+
+/// Parse the quota in MiB into bytes.
+fn parse_quota(value: &str) -> Option<u64> {
+	value.trim().parse::<u64>().ok()?.checked_mul(1024 * 1024)
+}
+
+impl Storage {
+	/// Sets maximum total size of objects in bytes.
+	///
+	/// `None` means that the storage size is not limited. This is the default.
+	///
+	/// Quota can be enforced only if the backend keeps objects as local files.
+	/// Downloads into other backends fail with [`StorageError::Unsupported`]
+	/// while a quota is set.
+	pub fn set_quota(&mut self, max_size: Option<u64>) {
+		self.quota = max_size;
+	}
+
+	/// Returns maximum total size of objects in bytes.
+	#[inline]
+	pub fn quota(&self) -> Option<u64> {
+		self.quota
+	}
+
+	/// Returns total size of all objects in bytes.
+	pub async fn usage(&self) -> Result<u64, StorageError> {
+		let mut usage = self.usage.lock().await;
+		self.cached_usage(&mut usage).await
+	}
+
+	/// Returns cached total size of objects, calculating it if needed.
+	async fn cached_usage(&self, usage: &mut Option<u64>) -> Result<u64, StorageError> {
+		if let Some(usage) = *usage {
+			return Ok(usage);
+		}
+		let total = self
+			.list_objects()
+			.await?
+			.iter()
+			.map(|(_, _, size)| size)
+			.sum();
+		*usage = Some(total);
+		Ok(total)
+	}
+
+	/// Forget cached total size of objects.
+	///
+	/// Must be called after objects are removed bypassing the quota accounting.
+	pub(crate) async fn invalidate_usage(&self) {
+		*self.usage.lock().await = None;
+	}
+
+	/// Make room for an object of `size` bytes before it's downloaded.
+	///
+	/// Room stays reserved for the object until the returned reservation is
+	/// dropped, so concurrent downloads don't exceed the quota together. It
+	/// must be dropped before the object is committed, see
+	/// [`Storage::commit_object`].
+	pub(crate) async fn reserve_space(
+		&self,
+		size: u64,
+	) -> Result<Option<Reservation<'_>>, StorageError> {
+		let quota = match self.quota {
+			Some(quota) => quota,
+			None => return Ok(None),
+		};
+		let mut usage = self.usage.lock().await;
+		let mut current = self.cached_usage(&mut usage).await?;
+		let reserved = self.reserved.load(Ordering::SeqCst);
+		let needed = current
+			.saturating_add(reserved)
+			.saturating_add(size)
+			.saturating_sub(quota);
+		if needed > 0 {
+			current = match self.evict(current, needed).await {
+				Ok(current) => current,
+				Err(e) => {
+					*usage = None;
+					return Err(e);
+				}
+			};
+			*usage = Some(current);
+		}
+		self.reserved.fetch_add(size, Ordering::SeqCst);
+		Ok(Some(Reservation {
+			storage: self,
+			size,
+		}))
+	}
+
+	/// Move a verified `.part` file into place, evicting objects if the quota
+	/// would be exceeded.
+	///
+	/// Room reserved for objects still being downloaded is kept free.
+	pub(crate) async fn commit_object(
+		&self,
+		part_path: &Path,
+		dest_path: &Path,
+	) -> Result<(), StorageError> {
+		let quota = match self.quota {
+			Some(quota) => quota,
+			None => {
+				tokio::fs::rename(part_path, dest_path).await?;
+				return Ok(());
+			}
+		};
+
+		let mut usage = self.usage.lock().await;
+		let mut current = self.cached_usage(&mut usage).await?;
+		let size = tokio::fs::metadata(part_path).await?.len();
+		// Object is replaced, so its old size is freed
+		if let Ok(metadata) = tokio::fs::metadata(dest_path).await {
+			current = current.saturating_sub(metadata.len());
+		}
+		let reserved = self.reserved.load(Ordering::SeqCst);
+		let needed = current
+			.saturating_add(reserved)
+			.saturating_add(size)
+			.saturating_sub(quota);
+		if needed > 0 {
+			current = match self.evict(current, needed).await {
+				Ok(current) => current,
+				Err(e) => {
+					*usage = None;
+					tokio::fs::remove_file(part_path).await?;
+					return Err(e);
+				}
+			};
+		}
+		tokio::fs::rename(part_path, dest_path).await?;
+		*usage = Some(current + size);
+		Ok(())
+	}
+
+	/// Evict least recently used unpinned objects until at least `needed` bytes
+	/// are freed.
+	///
+	/// Objects locked by other tasks or processes are being written, so they
+	/// are skipped. Returns new total size of objects.
+	async fn evict(&self, mut current: u64, needed: u64) -> Result<u64, StorageError> {
+		let pinned = self.all_pinned().await?;
+		let mut candidates = Vec::new();
+		for (hash, path, size) in self.list_objects().await? {
+			if pinned.contains(&hash) {
+				continue;
+			}
+			let accessed = self.accessed.lock().unwrap().get(&hash).copied();
+			let accessed = match accessed {
+				Some(accessed) => accessed,
+				None => tokio::fs::metadata(&path).await?.modified()?,
+			};
+			candidates.push((accessed, hash, path, size));
+		}
+		candidates.sort_by_key(|(accessed, _, _, _)| *accessed);
+
+		let available: u64 = candidates.iter().map(|(_, _, _, size)| size).sum();
+		if available < needed {
+			return Err(StorageError::QuotaExceeded(needed, available));
+		}
+
+		let mut freed = 0;
+		for (_, hash, path, size) in candidates {
+			if freed >= needed {
+				break;
+			}
+			let _lock = match self.try_lock_object(&hash)? {
+				Some(lock) => lock,
+				None => {
+					debug!("Not evicting object being written: {}", hash);
+					continue;
+				}
+			};
+			debug!("Evicting object: {}", hash);
+			match tokio::fs::remove_file(&path).await {
+				Ok(()) => {}
+				// Evicted by another process in the meantime
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(e) => return Err(e.into()),
+			}
+			self.accessed.lock().unwrap().remove(&hash);
+			freed += size;
+			current = current.saturating_sub(size);
+		}
+		if freed < needed {
+			return Err(StorageError::QuotaExceeded(needed, freed));
+		}
+		Ok(current)
+	}
+
+	/// Mark object as recently used.
+	pub(crate) fn touch(&self, hash: &ContentHash) {
+		self.accessed
+			.lock()
+			.unwrap()
+			.insert(hash.clone(), SystemTime::now());
+	}
+}
+
+/// Room reserved for an object being downloaded, see [`Storage::reserve_space`].
+///
+/// Reservation is released when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation<'a> {
+	storage: &'a Storage,
+	size: u64,
+}
+
+impl Drop for Reservation<'_> {
+	fn drop(&mut self) {
+		self.storage.reserved.fetch_sub(self.size, Ordering::SeqCst);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::temp_storage_with_client;
+	use crate::utils::crypto::HashAlgorithm;
+	use crate::utils::net::{part_path, NetClient};
+	use crate::utils::test_server::{Route, TestServer};
+	use std::path::PathBuf;
+	use std::sync::Arc;
+
+	#[test]
+	fn test_parse_quota() {
+		assert_eq!(parse_quota("10"), Some(10 * 1024 * 1024));
+		assert_eq!(parse_quota(" 0\n"), Some(0));
+		assert_eq!(parse_quota("10GB"), None);
+		assert_eq!(parse_quota(&u64::MAX.to_string()), None);
+	}
+
+	#[tokio::test]
+	async fn test_quota_eviction() {
+		let server = TestServer::start().await;
+		for name in ["aaaa", "bbbb", "cccc", "dddd", "eeee", "ffff"] {
+			server.route(&format!("/ipfs/{name}"), Route::ok(name.as_bytes()));
+		}
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let (_dir, mut storage) = temp_storage_with_client(client);
+		storage.set_quota(Some(10));
+		fn hash(data: &str) -> ContentHash {
+			ContentHash::digest(HashAlgorithm::Sha1, data.as_bytes())
+		}
+		async fn download(storage: &Storage, data: &str) -> Result<PathBuf, StorageError> {
+			storage.download_object(&hash(data), data, Some(4)).await
+		}
+
+		let a = download(&storage, "aaaa").await.unwrap();
+		let b = download(&storage, "bbbb").await.unwrap();
+		assert_eq!(storage.usage().await.unwrap(), 8);
+		// "aaaa" was used after "bbbb", which is the least recently used now
+		let modified = std::fs::metadata(&a).unwrap().modified().unwrap();
+		assert!(storage.check_object(&hash("aaaa")).await.unwrap());
+		assert_eq!(std::fs::metadata(&a).unwrap().modified().unwrap(), modified);
+		let c = download(&storage, "cccc").await.unwrap();
+		assert!(a.exists());
+		assert!(!b.exists());
+		assert!(c.exists());
+		assert_eq!(storage.usage().await.unwrap(), 8);
+
+		// Nothing can be evicted, so "dddd" isn't even requested
+		storage
+			.add_pinned([hash("aaaa"), hash("cccc")])
+			.await
+			.unwrap();
+		let d_path = storage.get_object_path(&hash("dddd"));
+		let result = download(&storage, "dddd").await;
+		assert!(matches!(result, Err(StorageError::QuotaExceeded(_, _))));
+		assert!(!d_path.exists());
+		assert!(!part_path(&d_path).exists());
+		assert!(!server
+			.requests()
+			.iter()
+			.any(|request| request.path == "/ipfs/dddd"));
+
+		storage.unpin([hash("cccc")]).await.unwrap();
+		download(&storage, "dddd").await.unwrap();
+		assert!(!c.exists());
+
+		// Objects being written are not evicted
+		storage.unpin([hash("aaaa")]).await.unwrap();
+		let lock = storage.lock_object(&hash("aaaa")).await.unwrap();
+		download(&storage, "eeee").await.unwrap();
+		assert!(a.exists());
+		assert!(!d_path.exists());
+		drop(lock);
+
+		// Pins of other processes are kept too
+		let other = Storage::new(
+			Arc::new(NetClient::new()),
+			Some(storage.storage_dir().to_path_buf()),
+		);
+		other.add_pinned([hash("aaaa")]).await.unwrap();
+		download(&storage, "ffff").await.unwrap();
+		assert!(a.exists());
+		assert!(!storage.get_object_path(&hash("eeee")).exists());
+		drop(other);
+	}
+}
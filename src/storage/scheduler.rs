@@ -0,0 +1,870 @@
+//! Download scheduler.
+//!
+//! [`DownloadScheduler`] runs object downloads of the [`Storage`] in the
+//! background. It limits the number of concurrent downloads from every host,
+//! counting all IPFS gateways as one host, starts more important jobs first, like the main jar and libraries before
+//! sounds, and downloads identical objects only once. Imports of CAR archives
+//! are scheduled the same way, see [`DownloadScheduler::submit_car`].
+//!
+//! Every submitted job returns a [`JobHandle`] (or [`CarJobHandle`] for CAR
+//! imports), which is used to wait for the result of the job or to cancel it.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use reqwest::Url;
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::car::CarImportReport;
+use super::{Storage, StorageError};
+use crate::utils::crypto::ContentHash;
+
+/// Error of a download job.
+///
+/// Result of a job may be shared by several requesters, so the storage error
+/// is wrapped in [`Arc`].
+#[derive(Error, Debug, Clone)]
+pub enum JobError {
+	/// Job was cancelled before it finished.
+	#[error("Download was cancelled")]
+	Cancelled,
+	/// Download failed.
+	#[error("{0}")]
+	Failed(Arc<StorageError>),
+	/// Job panicked before it finished.
+	#[error("Download task panicked")]
+	Panicked,
+}
+
+impl From<StorageError> for JobError {
+	fn from(error: StorageError) -> Self {
+		Self::Failed(Arc::new(error))
+	}
+}
+
+/// Result of a download job: path of the object or the error.
+pub type JobResult = Result<PathBuf, JobError>;
+
+/// Priority of a download job.
+///
+/// Jobs with higher priority are started first, jobs with the same priority
+/// are started in order of submission.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+	/// Jobs which may be finished last, like assets.
+	Low,
+	/// Default priority.
+	#[default]
+	Normal,
+	/// Jobs required to start the game, like the main jar and libraries.
+	High,
+}
+
+/// When the object is downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DownloadMode {
+	/// Always, see [`Storage::download_object`].
+	Always,
+	/// If it's not stored, see [`Storage::download_object_if_not_exists`].
+	#[default]
+	IfNotExists,
+	/// If it's not stored or corrupted, see
+	/// [`Storage::download_object_if_invalid`].
+	IfInvalid,
+}
+
+/// Download of a single object.
+#[derive(Debug, Clone)]
+pub struct DownloadJob {
+	/// Hash of the object.
+	pub hash: ContentHash,
+	/// IPFS path or URL of the object.
+	pub path: String,
+	/// Expected size of the object.
+	pub size: Option<u64>,
+	/// Priority of the job.
+	pub priority: Priority,
+	/// When the object is downloaded.
+	pub mode: DownloadMode,
+}
+
+impl DownloadJob {
+	/// Creates a job with normal priority, which downloads the object if it's
+	/// not stored.
+	pub fn new(hash: ContentHash, path: &str, size: Option<u64>) -> Self {
+		Self {
+			hash,
+			path: path.to_string(),
+			size,
+			priority: Priority::default(),
+			mode: DownloadMode::default(),
+		}
+	}
+
+	/// Creates a job downloading SHA-1 object.
+	///
+	/// See [`DownloadJob::new`].
+	#[inline]
+	pub fn asset(sha1_hash: &str, path: &str, size: Option<u64>) -> Self {
+		Self::new(ContentHash::sha1(sha1_hash), path, size)
+	}
+
+	/// Sets priority of the job.
+	pub fn priority(mut self, priority: Priority) -> Self {
+		self.priority = priority;
+		self
+	}
+
+	/// Sets when the object is downloaded.
+	pub fn mode(mut self, mode: DownloadMode) -> Self {
+		self.mode = mode;
+		self
+	}
+}
+
+/// Object downloaded in the given mode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ObjectKey {
+	hash: ContentHash,
+	mode: DownloadMode,
+}
+
+impl fmt::Display for ObjectKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "object {}", self.hash)
+	}
+}
+
+/// Job run by the scheduler.
+///
+/// Jobs with the same key are shared by all their requesters.
+#[async_trait]
+trait Job: fmt::Debug + Clone + Send + Sync + Sized + 'static {
+	/// What the job downloads.
+	type Key: fmt::Debug + fmt::Display + Clone + Eq + Hash + Send + Sync + 'static;
+	/// Result of the finished job.
+	type Output: fmt::Debug + Clone + Send + Sync + 'static;
+
+	/// Get the key of the job.
+	fn key(&self) -> Self::Key;
+
+	/// Get the IPFS path or URL the job downloads from.
+	fn source(&self, storage: &Storage) -> String;
+
+	/// Run the job.
+	async fn run(&self, storage: &Storage) -> Result<Self::Output, StorageError>;
+
+	/// Get submitted jobs of this type.
+	fn entries(state: &mut State) -> &mut HashMap<Self::Key, Entry<Self>>;
+
+	/// Get the queue entry of the job with the given key.
+	fn queued(key: Self::Key) -> Queued;
+}
+
+#[async_trait]
+impl Job for DownloadJob {
+	/// Jobs of the same object are shared only if they download it in the
+	/// same mode, so no requester gets a weaker check than it asked for.
+	type Key = ObjectKey;
+	type Output = PathBuf;
+
+	fn key(&self) -> ObjectKey {
+		ObjectKey {
+			hash: self.hash.clone(),
+			mode: self.mode,
+		}
+	}
+
+	fn source(&self, storage: &Storage) -> String {
+		storage.client.resolve(&self.path, self.hash.as_sha1())
+	}
+
+	async fn run(&self, storage: &Storage) -> Result<PathBuf, StorageError> {
+		match self.mode {
+			DownloadMode::Always => {
+				storage
+					.download_object(&self.hash, &self.path, self.size)
+					.await
+			}
+			DownloadMode::IfNotExists => {
+				storage
+					.download_object_if_not_exists(&self.hash, &self.path, self.size)
+					.await
+			}
+			DownloadMode::IfInvalid => {
+				storage
+					.download_object_if_invalid(&self.hash, &self.path, self.size)
+					.await
+			}
+		}
+	}
+
+	fn entries(state: &mut State) -> &mut HashMap<ObjectKey, Entry<Self>> {
+		&mut state.objects
+	}
+
+	fn queued(key: ObjectKey) -> Queued {
+		Queued::Object(key)
+	}
+}
+
+/// Import of the IPFS DAG from a CAR archive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CarJob {
+	/// Root CID of the DAG.
+	cid: String,
+}
+
+impl fmt::Display for CarJob {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "CAR archive {}", self.cid)
+	}
+}
+
+#[async_trait]
+impl Job for CarJob {
+	type Key = CarJob;
+	type Output = CarImportReport;
+
+	fn key(&self) -> CarJob {
+		self.clone()
+	}
+
+	fn source(&self, _storage: &Storage) -> String {
+		self.cid.clone()
+	}
+
+	async fn run(&self, storage: &Storage) -> Result<CarImportReport, StorageError> {
+		storage.download_car(&self.cid).await
+	}
+
+	fn entries(state: &mut State) -> &mut HashMap<CarJob, Entry<Self>> {
+		&mut state.cars
+	}
+
+	fn queued(key: CarJob) -> Queued {
+		Queued::Car(key)
+	}
+}
+
+/// Key of a queued job in the queue of its host.
+#[derive(Debug, Clone)]
+enum Queued {
+	Object(ObjectKey),
+	Car(CarJob),
+}
+
+/// Host of IPFS downloads, which is not a valid host name.
+const IPFS_HOST: &str = "<ipfs>";
+
+/// Key of a queued job: higher priority first, then older job first.
+type QueueKey = (Reverse<Priority>, u64);
+
+/// Result of a job shared with its handles.
+type Sender<T> = watch::Sender<Option<Result<T, JobError>>>;
+
+/// Submitted job.
+#[derive(Debug)]
+struct Entry<J: Job> {
+	job: J,
+	priority: Priority,
+	host: String,
+	/// Number of the submission, distinguishes jobs with the same key.
+	seq: u64,
+	/// Number of handles which didn't cancel the job.
+	requesters: usize,
+	sender: Sender<J::Output>,
+	/// Task of the running job.
+	task: Option<JoinHandle<()>>,
+}
+
+/// State of the scheduler.
+#[derive(Debug, Default)]
+struct State {
+	objects: HashMap<ObjectKey, Entry<DownloadJob>>,
+	cars: HashMap<CarJob, Entry<CarJob>>,
+	/// Queued jobs of every host.
+	queues: HashMap<String, BTreeMap<QueueKey, Queued>>,
+	/// Number of running jobs of every host.
+	running: HashMap<String, usize>,
+	next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+	storage: Arc<Storage>,
+	host_limit: usize,
+	state: Mutex<State>,
+}
+
+/// Scheduler of object downloads.
+///
+/// See [module docs](self) for details. Clones of the scheduler share its
+/// queue and limits.
+#[derive(Debug, Clone)]
+pub struct DownloadScheduler {
+	inner: Arc<Inner>,
+}
+
+impl DownloadScheduler {
+	/// Creates a scheduler with one concurrent download per CPU from every
+	/// host.
+	pub fn new(storage: Arc<Storage>) -> Self {
+		Self::with_host_limit(storage, num_cpus::get())
+	}
+
+	/// Creates a scheduler with the given number of concurrent downloads from
+	/// every host.
+	///
+	/// # Panics
+	///
+	/// Panics if `limit` is zero.
+	pub fn with_host_limit(storage: Arc<Storage>, limit: usize) -> Self {
+		assert!(limit > 0, "Host limit must be positive");
+		Self {
+			inner: Arc::new(Inner {
+				storage,
+				host_limit: limit,
+				state: Mutex::new(State::default()),
+			}),
+		}
+	}
+
+	/// Returns the storage objects are downloaded to.
+	#[inline]
+	pub fn storage(&self) -> &Storage {
+		&self.inner.storage
+	}
+
+	/// Submit the download job.
+	///
+	/// If a job downloading the same object in the same
+	/// [mode](DownloadJob::mode) is already queued or running, it's shared,
+	/// and its priority is raised to the priority of this job. Path and size
+	/// of the shared job are used, the object is verified by its hash anyway.
+	///
+	/// # Panics
+	///
+	/// Panics if called outside of the tokio runtime.
+	pub fn submit(&self, job: DownloadJob) -> JobHandle {
+		let hash = job.hash.clone();
+		let priority = job.priority;
+		JobHandle {
+			hash,
+			handle: self.submit_job(job, priority),
+		}
+	}
+
+	/// Submit the import of the IPFS DAG with the given root from a CAR
+	/// archive, see [`Storage::download_car`].
+	///
+	/// The import takes a single download slot of the gateway, and is shared
+	/// like object downloads, see [`DownloadScheduler::submit`].
+	///
+	/// # Panics
+	///
+	/// Panics if called outside of the tokio runtime.
+	pub fn submit_car(&self, cid: &str, priority: Priority) -> CarJobHandle {
+		let job = CarJob {
+			cid: cid.to_string(),
+		};
+		CarJobHandle {
+			handle: self.submit_job(job, priority),
+		}
+	}
+
+	/// Submit the job or share the queued or running job with the same key.
+	fn submit_job<J: Job>(&self, job: J, priority: Priority) -> Handle<J> {
+		let mut state = self.inner.state.lock().unwrap();
+		let key = job.key();
+		let (seq, receiver) = match J::entries(&mut state).get_mut(&key) {
+			Some(entry) => {
+				entry.requesters += 1;
+				let found = (entry.seq, entry.sender.subscribe());
+				if priority > entry.priority {
+					state.raise_priority::<J>(&key, priority);
+				}
+				found
+			}
+			None => {
+				let seq = state.next_seq;
+				state.next_seq += 1;
+				let host = self.host(&job);
+				let (sender, receiver) = watch::channel(None);
+				state
+					.queues
+					.entry(host.clone())
+					.or_default()
+					.insert((Reverse(priority), seq), J::queued(key.clone()));
+				J::entries(&mut state).insert(
+					key.clone(),
+					Entry {
+						job,
+						priority,
+						host,
+						seq,
+						requesters: 1,
+						sender,
+						task: None,
+					},
+				);
+				self.inner.dispatch(&mut state);
+				(seq, receiver)
+			}
+		};
+		Handle {
+			key,
+			seq,
+			receiver,
+			inner: self.inner.clone(),
+			cancelled: AtomicBool::new(false),
+		}
+	}
+
+	/// Cancel all queued and running jobs.
+	pub fn cancel_all(&self) {
+		let mut state = self.inner.state.lock().unwrap();
+		state.cancel_all::<DownloadJob>();
+		state.cancel_all::<CarJob>();
+	}
+
+	/// Get host the job downloads from.
+	///
+	/// IPFS downloads fail over between gateways while they run, so they are
+	/// counted for the whole gateway pool rather than the gateway which is the
+	/// best at the moment.
+	fn host<J: Job>(&self, job: &J) -> String {
+		let source = job.source(&self.inner.storage);
+		if !source.contains("://") {
+			return IPFS_HOST.to_string();
+		}
+		Url::parse(&source)
+			.ok()
+			.and_then(|url| url.host_str().map(str::to_string))
+			.unwrap_or_default()
+	}
+}
+
+impl Inner {
+	/// Start queued jobs of hosts which are below the limit, best jobs first.
+	fn dispatch(self: &Arc<Self>, state: &mut State) {
+		loop {
+			let best = state
+				.queues
+				.iter()
+				.filter(|(host, _)| {
+					state.running.get(*host).copied().unwrap_or(0) < self.host_limit
+				})
+				.filter_map(|(host, queue)| {
+					queue
+						.iter()
+						.next()
+						.map(|(queue_key, queued)| (*queue_key, host.clone(), queued.clone()))
+				})
+				.min_by_key(|(queue_key, _, _)| *queue_key);
+			let (queue_key, host, queued) = match best {
+				Some(best) => best,
+				None => break,
+			};
+			let queue = state.queues.get_mut(&host).unwrap();
+			queue.remove(&queue_key);
+			if queue.is_empty() {
+				state.queues.remove(&host);
+			}
+			*state.running.entry(host).or_default() += 1;
+
+			match queued {
+				Queued::Object(key) => self.start::<DownloadJob>(state, key),
+				Queued::Car(key) => self.start::<CarJob>(state, key),
+			}
+		}
+	}
+
+	/// Spawn the task of the dequeued job.
+	fn start<J: Job>(self: &Arc<Self>, state: &mut State, key: J::Key) {
+		let entry = J::entries(state).get_mut(&key).unwrap();
+		let job = entry.job.clone();
+		let seq = entry.seq;
+		let inner = self.clone();
+		entry.task = Some(tokio::spawn(async move {
+			let _guard = PanicGuard::<J> {
+				inner: inner.clone(),
+				key: key.clone(),
+				seq,
+			};
+			let result = job.run(&inner.storage).await;
+			if let Err(e) = &result {
+				error!("Failed to download {}: {}", key, e);
+			}
+			let mut state = inner.state.lock().unwrap();
+			state.finish::<J>(&key, seq, result.map_err(JobError::from));
+			inner.dispatch(&mut state);
+		}));
+	}
+}
+
+/// Finishes the job with [`JobError::Panicked`] if its task panics, so its
+/// download slot is freed and requesters don't wait forever.
+struct PanicGuard<J: Job> {
+	inner: Arc<Inner>,
+	key: J::Key,
+	seq: u64,
+}
+
+impl<J: Job> Drop for PanicGuard<J> {
+	fn drop(&mut self) {
+		if !std::thread::panicking() {
+			return;
+		}
+		error!("Download of {} panicked", self.key);
+		let mut state = self.inner.state.lock().unwrap();
+		state.finish::<J>(&self.key, self.seq, Err(JobError::Panicked));
+		self.inner.dispatch(&mut state);
+	}
+}
+
+impl State {
+	/// Requeue the queued job with higher priority.
+	fn raise_priority<J: Job>(&mut self, key: &J::Key, priority: Priority) {
+		let entry = J::entries(self).get_mut(key).unwrap();
+		let old_key = (Reverse(entry.priority), entry.seq);
+		let new_key = (Reverse(priority), entry.seq);
+		let host = entry.host.clone();
+		entry.priority = priority;
+		if let Some(queue) = self.queues.get_mut(&host) {
+			if queue.remove(&old_key).is_some() {
+				queue.insert(new_key, J::queued(key.clone()));
+			}
+		}
+	}
+
+	/// Remove the job from the queue or from running ones.
+	///
+	/// Returns `None` if the job was already finished or cancelled.
+	fn remove<J: Job>(&mut self, key: &J::Key, seq: u64) -> Option<Entry<J>> {
+		let entries = J::entries(self);
+		if !matches!(entries.get(key), Some(entry) if entry.seq == seq) {
+			return None;
+		}
+		let entry = entries.remove(key).unwrap();
+		match entry.task {
+			Some(_) => *self.running.get_mut(&entry.host).unwrap() -= 1,
+			None => {
+				let key = (Reverse(entry.priority), entry.seq);
+				if let Some(queue) = self.queues.get_mut(&entry.host) {
+					queue.remove(&key);
+					if queue.is_empty() {
+						self.queues.remove(&entry.host);
+					}
+				}
+			}
+		}
+		Some(entry)
+	}
+
+	/// Remove the finished job and send its result.
+	fn finish<J: Job>(&mut self, key: &J::Key, seq: u64, result: Result<J::Output, JobError>) {
+		if let Some(entry) = self.remove::<J>(key, seq) {
+			entry.sender.send_replace(Some(result));
+		}
+	}
+
+	/// Cancel the queued or running job.
+	fn cancel<J: Job>(&mut self, key: &J::Key, seq: u64) {
+		if let Some(entry) = self.remove::<J>(key, seq) {
+			if let Some(task) = &entry.task {
+				task.abort();
+			}
+			entry.sender.send_replace(Some(Err(JobError::Cancelled)));
+		}
+	}
+
+	/// Cancel all queued and running jobs of this type.
+	fn cancel_all<J: Job>(&mut self) {
+		let jobs: Vec<(J::Key, u64)> = J::entries(self)
+			.iter()
+			.map(|(key, entry)| (key.clone(), entry.seq))
+			.collect();
+		for (key, seq) in jobs {
+			self.cancel::<J>(&key, seq);
+		}
+	}
+
+	/// Withdraw one requester of the job, cancelling it if it was the last one.
+	fn release<J: Job>(&mut self, key: &J::Key, seq: u64) {
+		let entry = match J::entries(self).get_mut(key) {
+			Some(entry) if entry.seq == seq => entry,
+			_ => return,
+		};
+		entry.requesters -= 1;
+		if entry.requesters == 0 {
+			self.cancel::<J>(key, seq);
+		}
+	}
+}
+
+/// Requester of a submitted job.
+#[derive(Debug)]
+struct Handle<J: Job> {
+	key: J::Key,
+	seq: u64,
+	receiver: watch::Receiver<Option<Result<J::Output, JobError>>>,
+	inner: Arc<Inner>,
+	/// Whether this requester cancelled the job.
+	cancelled: AtomicBool,
+}
+
+impl<J: Job> Handle<J> {
+	/// Wait for the job to finish and get its result.
+	async fn wait(mut self) -> Result<J::Output, JobError> {
+		loop {
+			if self.cancelled.load(Ordering::Relaxed) {
+				return Err(JobError::Cancelled);
+			}
+			if let Some(result) = self.receiver.borrow().clone() {
+				return result;
+			}
+			if self.receiver.changed().await.is_err() {
+				return Err(JobError::Cancelled);
+			}
+		}
+	}
+
+	/// Cancel the job for this requester.
+	fn cancel(&self) {
+		if self.cancelled.swap(true, Ordering::Relaxed) {
+			return;
+		}
+		let mut state = self.inner.state.lock().unwrap();
+		state.release::<J>(&self.key, self.seq);
+		self.inner.dispatch(&mut state);
+	}
+}
+
+/// Handle of a submitted download job.
+#[derive(Debug)]
+pub struct JobHandle {
+	hash: ContentHash,
+	handle: Handle<DownloadJob>,
+}
+
+impl JobHandle {
+	/// Returns hash of the downloaded object.
+	#[inline]
+	pub fn hash(&self) -> &ContentHash {
+		&self.hash
+	}
+
+	/// Wait for the job to finish and get its result.
+	pub async fn wait(self) -> JobResult {
+		self.handle.wait().await
+	}
+
+	/// Cancel the job for this requester.
+	///
+	/// The job is shared by all requesters of the object, so it's cancelled
+	/// only when every requester cancelled it. Handles which are dropped
+	/// without cancelling keep the job running. Objects which are being
+	/// downloaded keep their `.part` files, so the download can be resumed
+	/// later.
+	pub fn cancel(&self) {
+		self.handle.cancel();
+	}
+}
+
+/// Handle of a submitted import of a CAR archive.
+#[derive(Debug)]
+pub struct CarJobHandle {
+	handle: Handle<CarJob>,
+}
+
+impl CarJobHandle {
+	/// Wait for the import to finish and get its report.
+	pub async fn wait(self) -> Result<CarImportReport, JobError> {
+		self.handle.wait().await
+	}
+
+	/// Cancel the import for this requester.
+	///
+	/// The import is shared like download jobs, see [`JobHandle::cancel`].
+	pub fn cancel(&self) {
+		self.handle.cancel();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::temp_storage_with_client;
+	use crate::storage::StorageBackend;
+	use crate::utils::crypto::sha1_digest;
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::net::NetClient;
+	use crate::utils::test_server::{Route, TestServer};
+
+	fn scheduler(server: &TestServer, limit: usize) -> (TempDir, DownloadScheduler) {
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let (dir, storage) = temp_storage_with_client(client);
+		(
+			dir,
+			DownloadScheduler::with_host_limit(Arc::new(storage), limit),
+		)
+	}
+
+	fn job(name: &str) -> DownloadJob {
+		DownloadJob::asset(&sha1_digest(name.as_bytes()), name, None)
+	}
+
+	#[tokio::test]
+	async fn test_scheduler_priority() {
+		let server = TestServer::start().await;
+		for name in ["first", "sound", "library"] {
+			server.route(&format!("/ipfs/{name}"), Route::ok(name.as_bytes()));
+		}
+		let (_dir, scheduler) = scheduler(&server, 1);
+
+		let first = scheduler.submit(job("first").priority(Priority::Low));
+		let sound = scheduler.submit(job("sound").priority(Priority::Low));
+		let missing = scheduler.submit(job("missing"));
+		let library = scheduler.submit(job("library").priority(Priority::High));
+		// Identical job is shared and raises its priority
+		let duplicate = scheduler.submit(job("sound").priority(Priority::High));
+
+		assert!(first.wait().await.is_ok());
+		assert!(sound.wait().await.is_ok());
+		assert!(duplicate.wait().await.is_ok());
+		assert!(library.wait().await.is_ok());
+		assert!(matches!(missing.wait().await, Err(JobError::Failed(_))));
+		let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+		assert_eq!(
+			paths,
+			vec![
+				"/ipfs/first",
+				"/ipfs/sound",
+				"/ipfs/library",
+				"/ipfs/missing"
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_scheduler_modes() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/first", Route::ok(b"first"));
+		let (_dir, scheduler) = scheduler(&server, 1);
+		let path = scheduler.storage().get_asset_path(&sha1_digest(b"first"));
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		std::fs::write(&path, b"corrupted").unwrap();
+
+		// Job which only checks that the object exists doesn't satisfy the
+		// one which verifies it
+		let exists = scheduler.submit(job("first"));
+		let valid = scheduler.submit(job("first").mode(DownloadMode::IfInvalid));
+		assert!(exists.wait().await.is_ok());
+		assert!(valid.wait().await.is_ok());
+		assert_eq!(server.requests().len(), 1);
+		assert_eq!(std::fs::read(&path).unwrap(), b"first");
+	}
+
+	#[test]
+	fn test_scheduler_hosts() {
+		let mut client = NetClient::new();
+		client.set_ipfs_gateways(&["https://a.test/ipfs/", "https://b.test/ipfs/"]);
+		let storage = Storage::with_backend(
+			Arc::new(client),
+			std::env::temp_dir(),
+			Arc::new(crate::storage::MemoryBackend::new()),
+		);
+		let scheduler = DownloadScheduler::new(Arc::new(storage));
+
+		// Failover between gateways doesn't change the limit IPFS jobs count for
+		let host = scheduler.host(&job("first"));
+		scheduler
+			.storage()
+			.client
+			.gateways()
+			.report_failure("https://a.test/ipfs/");
+		assert_eq!(scheduler.host(&job("first")), host);
+		let url = DownloadJob::asset(&sha1_digest(b"url"), "https://c.test/url", None);
+		assert_eq!(scheduler.host(&url), "c.test");
+	}
+
+	/// Backend which panics on every call.
+	#[derive(Debug)]
+	struct PanickingBackend;
+
+	#[async_trait]
+	impl StorageBackend for PanickingBackend {
+		async fn get(&self, _hash: &ContentHash) -> Result<Vec<u8>, StorageError> {
+			panic!("get")
+		}
+
+		async fn put(&self, _hash: &ContentHash, _data: &[u8]) -> Result<(), StorageError> {
+			panic!("put")
+		}
+
+		async fn exists(&self, _hash: &ContentHash) -> Result<bool, StorageError> {
+			panic!("exists")
+		}
+
+		async fn verify(&self, _hash: &ContentHash) -> Result<bool, StorageError> {
+			panic!("verify")
+		}
+
+		async fn list(&self) -> Result<Vec<(ContentHash, u64)>, StorageError> {
+			panic!("list")
+		}
+	}
+
+	#[tokio::test]
+	async fn test_scheduler_panic() {
+		let storage = Storage::with_backend(
+			Arc::new(NetClient::new()),
+			std::env::temp_dir(),
+			Arc::new(PanickingBackend),
+		);
+		let scheduler = DownloadScheduler::with_host_limit(Arc::new(storage), 1);
+
+		let first = scheduler.submit(job("first"));
+		let second = scheduler.submit(job("second"));
+		assert!(matches!(first.wait().await, Err(JobError::Panicked)));
+		// Slot of the panicked job is freed
+		assert!(matches!(second.wait().await, Err(JobError::Panicked)));
+	}
+
+	#[tokio::test]
+	async fn test_scheduler_cancel() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/first", Route::ok(b"first"));
+		server.route("/ipfs/second", Route::ok(b"second"));
+		let (_dir, scheduler) = scheduler(&server, 1);
+
+		let first = scheduler.submit(job("first"));
+		let second = scheduler.submit(job("second"));
+		second.cancel();
+		assert!(first.wait().await.is_ok());
+		assert!(matches!(second.wait().await, Err(JobError::Cancelled)));
+
+		// Shared job is cancelled only by its last requester
+		let first = scheduler.submit(job("first").mode(DownloadMode::Always));
+		let shared = scheduler.submit(job("first").mode(DownloadMode::Always));
+		shared.cancel();
+		assert!(matches!(shared.wait().await, Err(JobError::Cancelled)));
+		assert!(first.wait().await.is_ok());
+		assert_eq!(server.requests().len(), 2);
+
+		let first = scheduler.submit(job("first").mode(DownloadMode::Always));
+		scheduler.cancel_all();
+		assert!(matches!(first.wait().await, Err(JobError::Cancelled)));
+		assert_eq!(server.requests().len(), 2);
+	}
+}
@@ -1,6 +1,8 @@
 //! Asset index structure.
 
-use crate::storage::{Storage, StorageError};
+use crate::storage::scheduler::{DownloadJob, DownloadMode, DownloadScheduler, JobError, Priority};
+use crate::storage::{write_file_atomic, Storage, StorageError};
+use crate::utils::crypto::ContentHash;
 use serde::{Deserialize, Serialize};
 use std::{
 	collections::HashMap,
@@ -20,6 +22,14 @@ pub enum AssetIndexError {
 	/// IO error.
 	#[error("IO error: {0}")]
 	IO(#[from] std::io::Error),
+	/// Some assets failed to download.
+	///
+	/// Contains hashes of failed assets with their errors.
+	#[error("Failed to download {} assets", .0.len())]
+	Assets(Vec<(String, JobError)>),
+	/// Scheduled download of the asset index failed.
+	#[error("Failed to download asset index: {0}")]
+	Job(#[from] JobError),
 }
 
 /// The name of an asset.
@@ -29,12 +39,32 @@ pub type Name = String;
 ///
 /// This is the index of all the assets in the game,
 /// like textures, models, sounds, etc.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct AssetIndex {
 	/// The asset index objects.
 	///
 	/// This is mapping of asset paths to their entries.
 	pub objects: HashMap<Name, AssetIndexEntry>,
+	/// Whether assets must be placed by their names in `assets/virtual/<id>`.
+	///
+	/// Used by versions before 1.7.
+	#[serde(default, rename = "virtual", skip_serializing_if = "is_false")]
+	pub is_virtual: bool,
+	/// Whether assets must be placed by their names in `resources/` of the
+	/// game directory.
+	///
+	/// Used by versions before 1.6.
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub map_to_resources: bool,
+	/// IPFS CID of the directory with all assets.
+	///
+	/// If it's set, assets are downloaded in one request as a CAR archive.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub root: Option<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+	!value
 }
 
 impl AssetIndex {
@@ -44,14 +74,23 @@ impl AssetIndex {
 		Ok(serde_json::from_str(&file)?)
 	}
 
+	/// Reads the asset index from the storage.
+	pub async fn read(storage: &Storage, hash: &str) -> Result<Self, AssetIndexError> {
+		let data = storage.read_asset(hash).await?;
+		Ok(serde_json::from_slice(&data)?)
+	}
+
 	/// Downloads the asset index.
+	///
+	/// See [`Storage::download_asset`] for `size`.
 	pub async fn download(
 		storage: &Storage,
 		hash: &str,
 		path: &str,
+		size: Option<u64>,
 	) -> Result<Self, AssetIndexError> {
-		let path = storage.download_asset(hash, path).await?;
-		Self::parse(&path)
+		storage.download_asset(hash, path, size).await?;
+		Self::read(storage, hash).await
 	}
 
 	/// Downloads the asset index if it doesn't exist.
@@ -59,9 +98,12 @@ impl AssetIndex {
 		storage: &Storage,
 		hash: &str,
 		path: &str,
+		size: Option<u64>,
 	) -> Result<Self, AssetIndexError> {
-		let path = storage.download_asset_if_not_exists(hash, path).await?;
-		Self::parse(&path)
+		storage
+			.download_asset_if_not_exists(hash, path, size)
+			.await?;
+		Self::read(storage, hash).await
 	}
 
 	/// Downloads the asset index if it's invalid.
@@ -69,19 +111,51 @@ impl AssetIndex {
 		storage: &Storage,
 		hash: &str,
 		path: &str,
+		size: Option<u64>,
+	) -> Result<Self, AssetIndexError> {
+		storage.download_asset_if_invalid(hash, path, size).await?;
+		Self::read(storage, hash).await
+	}
+
+	/// Downloads the asset index if it's invalid, with the scheduler.
+	///
+	/// The download is submitted with high priority, because assets can't be
+	/// downloaded before it.
+	pub async fn get_if_invalid(
+		scheduler: &DownloadScheduler,
+		hash: &str,
+		path: &str,
+		size: Option<u64>,
 	) -> Result<Self, AssetIndexError> {
-		let path = storage.download_asset_if_invalid(hash, path).await?;
-		Self::parse(&path)
+		let job = DownloadJob::asset(hash, path, size)
+			.priority(Priority::High)
+			.mode(DownloadMode::IfInvalid);
+		scheduler.submit(job).wait().await?;
+		Self::read(scheduler.storage(), hash).await
 	}
 
 	/// Save the asset index to a file.
 	pub async fn save(&self, storage: &Storage, hash: &str) -> Result<(), AssetIndexError> {
 		let path = storage.get_index_path(hash);
 		let contents = serde_json::to_string(&self)?;
-		tokio::fs::write(path, contents).await?;
+		write_file_atomic(&path, contents.as_bytes()).await?;
 		Ok(())
 	}
 
+	/// Get directory where legacy versions read assets by their names.
+	///
+	/// Returns `None` if the version reads assets from the hashed `objects/`
+	/// layout. `id` is the ID of the asset index.
+	pub fn get_named_assets_dir(&self, game_dir: &Path, id: &str) -> Option<PathBuf> {
+		if self.map_to_resources {
+			Some(game_dir.join("resources"))
+		} else if self.is_virtual {
+			Some(game_dir.join("assets").join("virtual").join(id))
+		} else {
+			None
+		}
+	}
+
 	/// Get iterator over all assets.
 	pub fn get_assets(
 		&self,
@@ -91,16 +165,53 @@ impl AssetIndex {
 		self.objects.values().cloned()
 	}
 
+	/// Get hashes of assets which are not stored.
+	///
+	/// Assets are checked for existence only, so it's fast enough to tell
+	/// whether all assets are available in offline mode.
+	pub async fn missing_assets(&self, storage: &Storage) -> Result<Vec<String>, StorageError> {
+		let mut missing = Vec::new();
+		for asset in self.objects.values() {
+			let hash = ContentHash::sha1(&asset.hash);
+			if !storage.backend().exists(&hash).await? {
+				missing.push(asset.hash.clone());
+			}
+		}
+		missing.sort();
+		missing.dedup();
+		Ok(missing)
+	}
+
 	/// Downloads all assets.
 	///
-	/// **Warning:** This is slow, because it downloads all assets one by one.
-	pub async fn download_all(&self, storage: &Storage) -> Result<(), AssetIndexError> {
-		for asset in self.get_assets() {
-			let call = asset.download_if_not_exists(storage).await;
-			if let Err(e) = call {
-				log::error!("Failed to download asset: {}", e);
+	/// If [`AssetIndex::root`] is set, assets are downloaded as a single CAR
+	/// archive first, see [`DownloadScheduler::submit_car`]. Assets which are still
+	/// missing then are submitted to the scheduler with low priority, so
+	/// libraries submitted by others are downloaded first.
+	///
+	/// Failed downloads are retried according to the client's retry policy.
+	/// Assets which still failed don't stop the download of other assets, they
+	/// are returned in [`AssetIndexError::Assets`] at the end.
+	pub async fn download_all(&self, scheduler: &DownloadScheduler) -> Result<(), AssetIndexError> {
+		if let Some(root) = &self.root {
+			if let Err(e) = scheduler.submit_car(root, Priority::Low).wait().await {
+				log::warn!("Failed to download assets as CAR archive: {}", e);
+			}
+		}
+		let handles: Vec<_> = self
+			.get_assets()
+			.map(|asset| scheduler.submit(asset.job().priority(Priority::Low)))
+			.collect();
+		let mut failed = Vec::new();
+		for handle in handles {
+			let hash = handle.hash().hex.clone();
+			if let Err(e) = handle.wait().await {
+				failed.push((hash, e));
 			}
 		}
+		if !failed.is_empty() {
+			return Err(AssetIndexError::Assets(failed));
+		}
 		Ok(())
 	}
 }
@@ -117,11 +228,20 @@ pub struct AssetIndexEntry {
 }
 
 impl AssetIndexEntry {
+	/// Get the job downloading the asset if it doesn't exist.
+	///
+	/// See [`DownloadScheduler::submit`].
+	pub fn job(&self) -> DownloadJob {
+		DownloadJob::asset(&self.hash, &self.path, Some(self.size))
+	}
+
 	/// Downloads the asset.
 	///
 	/// Proxy for [`Storage::download_asset`].
 	pub async fn download(&self, storage: &Storage) -> Result<PathBuf, StorageError> {
-		storage.download_asset(&self.hash, &self.path).await
+		storage
+			.download_asset(&self.hash, &self.path, Some(self.size))
+			.await
 	}
 
 	/// Downloads the asset if it doesn't exist.
@@ -129,7 +249,7 @@ impl AssetIndexEntry {
 	/// Proxy for [`Storage::download_asset_if_not_exists`].
 	pub async fn download_if_not_exists(&self, storage: &Storage) -> Result<PathBuf, StorageError> {
 		storage
-			.download_asset_if_not_exists(&self.hash, &self.path)
+			.download_asset_if_not_exists(&self.hash, &self.path, Some(self.size))
 			.await
 	}
 
@@ -138,7 +258,7 @@ impl AssetIndexEntry {
 	/// Proxy for [`Storage::download_asset_if_invalid`].
 	pub async fn download_if_invalid(&self, storage: &Storage) -> Result<PathBuf, StorageError> {
 		storage
-			.download_asset_if_invalid(&self.hash, &self.path)
+			.download_asset_if_invalid(&self.hash, &self.path, Some(self.size))
 			.await
 	}
 
@@ -149,3 +269,163 @@ impl AssetIndexEntry {
 		storage.check_asset(&self.hash).await
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::memory_storage;
+	use crate::utils::car::tests::{car, cid, unixfs_node};
+	use crate::utils::cid::{DAG_PB, RAW};
+	use crate::utils::crypto::sha1_digest;
+	use crate::utils::net::NetClient;
+	use crate::utils::test_server::{Route, TestServer};
+	use std::sync::Arc;
+
+	#[tokio::test]
+	async fn test_download_asset_index() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/sound", Route::ok(b"sound"));
+		let mut objects = HashMap::new();
+		objects.insert(
+			"minecraft/sounds/step.ogg".to_string(),
+			AssetIndexEntry {
+				hash: sha1_digest(b"sound"),
+				path: "sound".to_string(),
+				size: 5,
+			},
+		);
+		let asset_index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+		let index_data = serde_json::to_vec(&asset_index).unwrap();
+		server.route("/ipfs/index", Route::ok(&index_data));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let scheduler = DownloadScheduler::new(Arc::new(memory_storage(client)));
+		let storage = scheduler.storage();
+
+		let asset_index = AssetIndex::download_if_not_exists(
+			storage,
+			&sha1_digest(&index_data),
+			"index",
+			Some(index_data.len() as u64),
+		)
+		.await
+		.unwrap();
+		let asset = asset_index.get_assets().next().unwrap();
+		assert!(!asset.is_valid(storage).await.unwrap());
+		asset_index.download_all(&scheduler).await.unwrap();
+		assert!(asset.is_valid(storage).await.unwrap());
+		assert_eq!(storage.read_asset(&asset.hash).await.unwrap(), b"sound");
+		assert!(!storage.storage_dir().exists());
+
+		// Valid index is not downloaded again
+		let requests = server.requests().len();
+		let asset_index = AssetIndex::get_if_invalid(
+			&scheduler,
+			&sha1_digest(&index_data),
+			"index",
+			Some(index_data.len() as u64),
+		)
+		.await
+		.unwrap();
+		assert_eq!(asset_index.objects.len(), 1);
+		assert_eq!(server.requests().len(), requests);
+	}
+
+	#[tokio::test]
+	async fn test_download_all_reports_failures() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/sound", Route::ok(b"sound"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let scheduler = DownloadScheduler::new(Arc::new(memory_storage(client)));
+		let storage = scheduler.storage();
+		let mut objects = HashMap::new();
+		for (name, data, path) in [
+			("found.ogg", "sound", "sound"),
+			("lost.ogg", "lost", "lost"),
+		] {
+			objects.insert(
+				name.to_string(),
+				AssetIndexEntry {
+					hash: sha1_digest(data.as_bytes()),
+					path: path.to_string(),
+					size: data.len() as u64,
+				},
+			);
+		}
+		let asset_index = AssetIndex {
+			objects,
+			..Default::default()
+		};
+
+		let result = asset_index.download_all(&scheduler).await;
+		match result {
+			Err(AssetIndexError::Assets(failed)) => {
+				assert_eq!(failed.len(), 1);
+				assert_eq!(failed[0].0, sha1_digest(b"lost"));
+			}
+			_ => panic!("expected failed assets, got {result:?}"),
+		}
+		// 404 is not retried
+		let lost_requests = server
+			.requests()
+			.iter()
+			.filter(|request| request.path == "/ipfs/lost")
+			.count();
+		assert_eq!(lost_requests, 1);
+		assert!(storage.check_asset(&sha1_digest(b"sound")).await.unwrap());
+		assert_eq!(
+			asset_index.missing_assets(storage).await.unwrap(),
+			vec![sha1_digest(b"lost")]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_download_all_car() {
+		let sound_cid = cid(RAW, b"sound");
+		let dir = unixfs_node(1, b"", std::slice::from_ref(&sound_cid));
+		let root = cid(DAG_PB, &dir);
+		let archive = car(
+			&root,
+			&[(root.clone(), dir), (sound_cid, b"sound".to_vec())],
+		);
+		let server = TestServer::start().await;
+		server.route(&format!("/ipfs/{root}?format=car"), Route::ok(&archive));
+		server.route("/ipfs/texture", Route::ok(b"texture"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let scheduler = DownloadScheduler::new(Arc::new(memory_storage(client)));
+		let storage = scheduler.storage();
+		let mut objects = HashMap::new();
+		for (name, data) in [("sound.ogg", "sound"), ("texture.png", "texture")] {
+			objects.insert(
+				name.to_string(),
+				AssetIndexEntry {
+					hash: sha1_digest(data.as_bytes()),
+					path: data.to_string(),
+					size: data.len() as u64,
+				},
+			);
+		}
+		let asset_index = AssetIndex {
+			objects,
+			root: Some(root.to_string()),
+			..Default::default()
+		};
+
+		asset_index.download_all(&scheduler).await.unwrap();
+		assert!(storage.check_asset(&sha1_digest(b"sound")).await.unwrap());
+		// Asset missing from the archive is downloaded separately
+		let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+		assert_eq!(
+			paths,
+			vec![
+				format!("/ipfs/{root}?format=car"),
+				"/ipfs/texture".to_string()
+			]
+		);
+	}
+}
@@ -1,9 +1,12 @@
 //! Version manifest structures.
 
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use super::asset_index::AssetIndex;
-use crate::storage::{Storage, StorageError};
+use super::asset_index::{AssetIndex, AssetIndexError};
+use crate::storage::scheduler::{DownloadJob, DownloadMode, DownloadScheduler, JobError, Priority};
+use crate::storage::{write_file_atomic, Storage, StorageError};
+use crate::utils::crypto::ContentHash;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -19,6 +22,12 @@ pub enum VersionManifestError {
 	/// IO error.
 	#[error("IO error: {0}")]
 	IOError(#[from] std::io::Error),
+	/// Failed to download artifact.
+	#[error("Failed to download artifact: {0}")]
+	DownloadError(#[from] JobError),
+	/// Failed to save asset index.
+	#[error("Failed to save asset index: {0}")]
+	AssetIndexError(#[from] AssetIndexError),
 }
 
 fn default_libraries() -> Vec<Library> {
@@ -36,27 +45,46 @@ pub struct Artifact {
 	pub sha1: String,
 	/// Artifact size.
 	pub size: u64,
-	/// Artifact IPFS path.
+	/// Artifact IPFS path or URL.
+	///
+	/// Resolved with [`NetClient::resolve`](crate::utils::net::NetClient::resolve).
 	pub path: String,
 }
 
 impl Artifact {
+	/// Get the job downloading the artifact if it doesn't exist.
+	///
+	/// See [`DownloadScheduler::submit`].
+	pub fn job(&self) -> DownloadJob {
+		DownloadJob::asset(&self.sha1, &self.path, Some(self.size))
+	}
+
 	/// Get the artifact and store it.
-	pub async fn get_artifact(&self, storage: &Storage) -> Result<(), VersionManifestError> {
-		storage
-			.download_asset_if_not_exists(&self.sha1, &self.path)
+	///
+	/// The download is submitted to the scheduler with high priority.
+	pub async fn get_artifact(
+		&self,
+		scheduler: &DownloadScheduler,
+	) -> Result<(), VersionManifestError> {
+		scheduler
+			.submit(self.job().priority(Priority::High))
+			.wait()
 			.await?;
 		Ok(())
 	}
 
 	/// Get the artifact and store it if it's invalid.
+	///
+	/// The download is submitted to the scheduler with high priority.
 	pub async fn get_artifact_if_invalid(
 		&self,
-		storage: &Storage,
+		scheduler: &DownloadScheduler,
 	) -> Result<(), VersionManifestError> {
-		storage
-			.download_asset_if_invalid(&self.sha1, &self.path)
-			.await?;
+		let job = self
+			.job()
+			.priority(Priority::High)
+			.mode(DownloadMode::IfInvalid);
+		scheduler.submit(job).wait().await?;
 		Ok(())
 	}
 }
@@ -71,7 +99,9 @@ pub struct AssetIndexArtifact {
 	pub sha1: String,
 	/// Artifact size.
 	pub size: u64,
-	/// Artifact IPFS path.
+	/// Artifact IPFS path or URL.
+	///
+	/// Resolved with [`NetClient::resolve`](crate::utils::net::NetClient::resolve).
 	pub path: String,
 	/// Total size of all assets.
 	pub total_size: u64,
@@ -81,19 +111,31 @@ pub struct AssetIndexArtifact {
 
 impl AssetIndexArtifact {
 	/// Get the asset index artifact and parse it.
+	///
+	/// The download is submitted to the scheduler with high priority.
 	pub async fn get_asset_index(
 		&self,
-		storage: &Storage,
+		scheduler: &DownloadScheduler,
 	) -> Result<AssetIndex, VersionManifestError> {
-		let asset_index_path = storage
-			.download_asset_if_invalid(&self.sha1, &self.path)
+		Artifact::from(self)
+			.get_artifact_if_invalid(scheduler)
 			.await?;
-		let asset_index_data = tokio::fs::read_to_string(asset_index_path).await?;
-		let asset_index = serde_json::from_str(&asset_index_data)?;
+		let asset_index_data = scheduler.storage().read_asset(&self.sha1).await?;
+		let asset_index = serde_json::from_slice(&asset_index_data)?;
 		Ok(asset_index)
 	}
 }
 
+impl From<&AssetIndexArtifact> for Artifact {
+	fn from(asset_index: &AssetIndexArtifact) -> Self {
+		Self {
+			sha1: asset_index.sha1.clone(),
+			size: asset_index.size,
+			path: asset_index.path.clone(),
+		}
+	}
+}
+
 /// Artifact downloads.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ArtifactDownloads {
@@ -108,6 +150,17 @@ pub struct ArtifactDownloads {
 	pub classifiers: Option<HashMap<String, Artifact>>,
 }
 
+impl ArtifactDownloads {
+	/// Get iterator over the artifact and all classifiers.
+	///
+	/// Unlike [`Library::get_artifacts`], this doesn't check rules or current OS.
+	pub fn iter(&self) -> impl Iterator<Item = &Artifact> {
+		self.artifact
+			.iter()
+			.chain(self.classifiers.iter().flat_map(|c| c.values()))
+	}
+}
+
 /// Main jar artifact.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MainJar {
@@ -257,17 +310,63 @@ impl Library {
 				artifacts.push(artifact.clone());
 			}
 		}
-		if let Some(classifiers) = &self.downloads.classifiers {
-			if let Some(natives) = &self.natives {
-				if let Some(native) = natives.get(&get_os_name()) {
-					if let Some(classifier) = classifiers.get(native) {
-						artifacts.push(classifier.clone());
-					}
-				}
-			}
+		if let Some(native) = self.get_native_artifact() {
+			artifacts.push(native.clone());
 		}
 		artifacts
 	}
+
+	/// Get native classifier name for the current OS.
+	///
+	/// For example, `natives-linux`.
+	pub fn get_native_classifier(&self) -> Option<&str> {
+		self.natives
+			.as_ref()?
+			.get(&get_os_name())
+			.map(String::as_str)
+	}
+
+	/// Get native artifact for the current OS.
+	pub fn get_native_artifact(&self) -> Option<&Artifact> {
+		self.downloads
+			.classifiers
+			.as_ref()?
+			.get(self.get_native_classifier()?)
+	}
+}
+
+/// Get relative path of the maven artifact with the given name.
+///
+/// Name should be in `group:artifact:version[:classifier]` format.
+/// Returns `None` if the name is invalid.
+///
+/// # Examples
+///
+/// ```
+/// use firelaunch::structures::version_manifest::maven_path;
+/// use std::path::Path;
+///
+/// assert_eq!(
+///   maven_path("org.lwjgl:lwjgl:3.2.2", Some("natives-linux")).unwrap(),
+///   Path::new("org/lwjgl/lwjgl/3.2.2/lwjgl-3.2.2-natives-linux.jar")
+/// );
+/// ```
+pub fn maven_path(name: &str, classifier: Option<&str>) -> Option<PathBuf> {
+	let mut parts = name.split(':');
+	let (group, artifact, version) = (parts.next()?, parts.next()?, parts.next()?);
+	let classifier = parts.next().or(classifier);
+	if group.is_empty() || artifact.is_empty() || version.is_empty() || parts.next().is_some() {
+		return None;
+	}
+	let file_name = match classifier {
+		Some(classifier) => format!("{artifact}-{version}-{classifier}.jar"),
+		None => format!("{artifact}-{version}.jar"),
+	};
+	let mut path: PathBuf = group.split('.').collect();
+	path.push(artifact);
+	path.push(version);
+	path.push(file_name);
+	Some(path)
 }
 
 /// Requirement.
@@ -339,9 +438,109 @@ pub struct VersionManifest {
 	pub requires: Vec<Requirement>,
 }
 
+impl VersionManifest {
+	/// Parses the version manifest from a file.
+	pub fn parse(path: &Path) -> Result<Self, VersionManifestError> {
+		let file = std::fs::read_to_string(path)?;
+		Ok(serde_json::from_str(&file)?)
+	}
+
+	/// Save the version manifest to the storage.
+	pub async fn save(&self, storage: &Storage) -> Result<(), VersionManifestError> {
+		let path = storage.get_version_path(&self.product_uid, &self.version);
+		tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+		let contents = serde_json::to_string(&self)?;
+		write_file_atomic(&path, contents.as_bytes()).await?;
+		Ok(())
+	}
+
+	/// Install the version: save the manifest and download the artifacts
+	/// needed on the current OS with high priority.
+	///
+	/// The manifest is saved first, so
+	/// [`Storage::collect_garbage`](crate::storage::Storage::collect_garbage)
+	/// keeps the artifacts as soon as they are downloaded. The asset index is
+	/// saved too, but assets are not downloaded, see
+	/// [`AssetIndex::download_all`].
+	///
+	/// Objects of the version are pinned, so they are not evicted to satisfy
+	/// the quota, see [`Storage::pin_version`](crate::storage::Storage::pin_version).
+	pub async fn install(&self, scheduler: &DownloadScheduler) -> Result<(), VersionManifestError> {
+		let storage = scheduler.storage();
+		self.save(storage).await?;
+		let artifacts = self.get_artifacts();
+		// Artifacts must not be evicted by each other while downloading
+		storage
+			.add_pinned(
+				artifacts
+					.iter()
+					.map(|artifact| ContentHash::sha1(&artifact.sha1)),
+			)
+			.await?;
+		let handles: Vec<_> = artifacts
+			.iter()
+			.map(|artifact| scheduler.submit(artifact.job().priority(Priority::High)))
+			.collect();
+		for handle in handles {
+			handle.wait().await?;
+		}
+		let asset_index = match &self.asset_index {
+			Some(artifact) => {
+				let asset_index = AssetIndex::read(storage, &artifact.sha1).await?;
+				asset_index.save(storage, &artifact.sha1).await?;
+				Some(asset_index)
+			}
+			None => None,
+		};
+		storage.pin_version(self, asset_index.as_ref()).await?;
+		Ok(())
+	}
+
+	/// Get artifacts needed on the current OS.
+	///
+	/// This includes the asset index, the main jar and library artifacts which
+	/// satisfy their rules, see [`Library::get_artifacts`].
+	pub fn get_artifacts(&self) -> Vec<Artifact> {
+		let mut artifacts: Vec<Artifact> = Vec::new();
+		if let Some(asset_index) = &self.asset_index {
+			artifacts.push(asset_index.into());
+		}
+		if let Some(main_jar) = &self.main_jar {
+			artifacts.extend(main_jar.downloads.iter().cloned());
+		}
+		for library in &self.libraries {
+			artifacts.extend(library.get_artifacts());
+		}
+		artifacts
+	}
+
+	/// Get all artifacts referenced by this version.
+	///
+	/// This includes the asset index, the main jar and every library artifact
+	/// and classifier, regardless of rules and current OS.
+	pub fn all_artifacts(&self) -> Vec<Artifact> {
+		let mut artifacts: Vec<Artifact> = Vec::new();
+		if let Some(asset_index) = &self.asset_index {
+			artifacts.push(asset_index.into());
+		}
+		if let Some(main_jar) = &self.main_jar {
+			artifacts.extend(main_jar.downloads.iter().cloned());
+		}
+		for library in &self.libraries {
+			artifacts.extend(library.downloads.iter().cloned());
+		}
+		artifacts
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::storage::tests::memory_storage;
+	use crate::utils::crypto::sha1_digest;
+	use crate::utils::net::NetClient;
+	use crate::utils::test_server::{Route, TestServer};
+	use std::sync::Arc;
 
 	#[test]
 	fn test_rule_is_satisfied() {
@@ -381,4 +580,57 @@ mod tests {
 			assert!(rule.is_satisfied());
 		}
 	}
+
+	#[tokio::test]
+	async fn test_get_artifact() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/jar", Route::ok(b"jar"));
+		server.route("/ipfs/index", Route::ok(b"{\"objects\": {}}"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let scheduler = DownloadScheduler::new(Arc::new(memory_storage(client)));
+		let storage = scheduler.storage();
+
+		let artifact = Artifact {
+			sha1: sha1_digest(b"jar"),
+			size: 3,
+			path: "jar".to_string(),
+		};
+		artifact.get_artifact(&scheduler).await.unwrap();
+		assert!(storage.check_asset(&artifact.sha1).await.unwrap());
+
+		let corrupted = Artifact {
+			sha1: sha1_digest(b"other"),
+			..artifact
+		};
+		assert!(corrupted.get_artifact_if_invalid(&scheduler).await.is_err());
+		assert!(!storage.check_asset(&corrupted.sha1).await.unwrap());
+
+		let asset_index_artifact = AssetIndexArtifact {
+			sha1: sha1_digest(b"{\"objects\": {}}"),
+			size: 15,
+			path: "index".to_string(),
+			total_size: 0,
+			id: "1.12".to_string(),
+		};
+		let asset_index = asset_index_artifact
+			.get_asset_index(&scheduler)
+			.await
+			.unwrap();
+		assert!(asset_index.objects.is_empty());
+		assert!(!storage.storage_dir().exists());
+	}
+
+	#[test]
+	fn test_maven_path() {
+		assert_eq!(
+			maven_path("com.mojang:minecraft:1.12.2:client", None).unwrap(),
+			Path::new("com/mojang/minecraft/1.12.2/minecraft-1.12.2-client.jar")
+		);
+		assert_eq!(
+			maven_path("org.lwjgl:lwjgl:3.2.2", None).unwrap(),
+			Path::new("org/lwjgl/lwjgl/3.2.2/lwjgl-3.2.2.jar")
+		);
+		assert!(maven_path("invalid", None).is_none());
+	}
 }
@@ -0,0 +1,483 @@
+//! CAR archives and UnixFS nodes.
+//!
+//! Gateways can return a whole IPFS DAG as a single CAR (content addressable
+//! archive) instead of one file per request. [`CarDecoder`] parses CARv1
+//! incrementally, so it can be fed with network chunks or file reads, and
+//! verifies every block against its CID.
+//!
+//! Files and directories in the DAG are UnixFS nodes, which are decoded with
+//! [`UnixFsNode::decode`]. Small files are usually single `raw` blocks, while
+//! big ones are `dag-pb` nodes linking to their chunks.
+//!
+//! See <https://ipld.io/specs/transport/car/carv1/> and
+//! <https://github.com/ipfs/specs/blob/main/UNIXFS.md>.
+
+use super::cid::{read_varint, Cid, CidError};
+
+/// Maximum size of a CAR section. IPFS blocks are limited to 2 MiB.
+const MAX_SECTION_SIZE: u64 = 4 * 1024 * 1024;
+/// Maximum nesting of the CAR header.
+const MAX_HEADER_DEPTH: usize = 8;
+
+/// CAR error.
+#[derive(thiserror::Error, Debug)]
+pub enum CarError {
+	/// Header is not a valid CARv1 header.
+	#[error("Invalid CAR header")]
+	InvalidHeader,
+	/// CAR version is not supported.
+	#[error("Unsupported CAR version: {0}")]
+	UnsupportedVersion(u64),
+	/// Section is bigger than any valid block.
+	#[error("CAR section is too large: {0} bytes")]
+	SectionTooLarge(u64),
+	/// Archive ended in the middle of a section.
+	#[error("CAR archive is truncated")]
+	Truncated,
+	/// Block has an invalid CID or doesn't match it.
+	#[error("Invalid block: {0}")]
+	InvalidBlock(#[from] CidError),
+	/// Block is not a valid UnixFS node.
+	#[error("Invalid UnixFS node")]
+	InvalidNode,
+	/// Archive doesn't contain the requested DAG.
+	#[error("CAR archive doesn't have root {0}")]
+	UnexpectedRoot(String),
+	/// First block of the archive is not its root.
+	#[error("CAR archive starts with {0} instead of its root")]
+	RootNotFirst(String),
+	/// Incomplete files of the archive need too much memory.
+	#[error("Incomplete files in CAR archive exceed {0} bytes")]
+	TooLarge(usize),
+}
+
+/// Verified block of the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+	/// CID of the block.
+	pub cid: Cid,
+	/// Block data.
+	pub data: Vec<u8>,
+}
+
+/// Incremental CARv1 decoder.
+///
+/// # Examples
+///
+/// ```no_run
+/// use firelaunch::utils::car::CarDecoder;
+///
+/// # fn main() -> Result<(), firelaunch::utils::car::CarError> {
+/// let mut decoder = CarDecoder::new();
+/// decoder.push(&std::fs::read("assets.car").unwrap());
+/// while let Some(block) = decoder.next_block()? {
+///   println!("{}: {} bytes", block.cid, block.data.len());
+/// }
+/// decoder.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct CarDecoder {
+	/// Received bytes which are not decoded yet.
+	buffer: Vec<u8>,
+	/// Roots from the header, once it's decoded.
+	roots: Option<Vec<Cid>>,
+}
+
+impl CarDecoder {
+	/// Creates a new decoder.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Feed the next part of the archive.
+	pub fn push(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	/// Get roots of the DAG, if the header is decoded already.
+	pub fn roots(&self) -> Option<&[Cid]> {
+		self.roots.as_deref()
+	}
+
+	/// Decode the next block from the fed data.
+	///
+	/// Returns `None` if more data is needed. Every block is verified against
+	/// its CID.
+	pub fn next_block(&mut self) -> Result<Option<Block>, CarError> {
+		if self.roots.is_none() {
+			match self.next_section()? {
+				Some(header) => self.roots = Some(decode_header(&header)?),
+				None => return Ok(None),
+			}
+		}
+		let section = match self.next_section()? {
+			Some(section) => section,
+			None => return Ok(None),
+		};
+		let mut data = &section[..];
+		let cid = Cid::read(&mut data)?;
+		cid.verify(data)?;
+		Ok(Some(Block {
+			cid,
+			data: data.to_vec(),
+		}))
+	}
+
+	/// Check that the whole archive was decoded.
+	pub fn finish(&self) -> Result<(), CarError> {
+		match self.roots.is_some() && self.buffer.is_empty() {
+			true => Ok(()),
+			false => Err(CarError::Truncated),
+		}
+	}
+
+	/// Take the next length-prefixed section from the buffer.
+	fn next_section(&mut self) -> Result<Option<Vec<u8>>, CarError> {
+		let mut rest = &self.buffer[..];
+		// Varint itself may be incomplete
+		if rest.len() < 9 && rest.iter().all(|byte| byte & 0x80 != 0) {
+			return Ok(None);
+		}
+		let length = read_varint(&mut rest).map_err(|_| CarError::Truncated)?;
+		if length > MAX_SECTION_SIZE {
+			return Err(CarError::SectionTooLarge(length));
+		}
+		let length = length as usize;
+		if rest.len() < length {
+			return Ok(None);
+		}
+		let section = rest[..length].to_vec();
+		let consumed = self.buffer.len() - rest.len() + length;
+		self.buffer.drain(..consumed);
+		Ok(Some(section))
+	}
+}
+
+/// Decoded DAG-CBOR value, only the types used by CAR headers.
+#[derive(Debug)]
+enum Cbor {
+	Uint(u64),
+	Bytes(Vec<u8>),
+	Text(String),
+	Array(Vec<Cbor>),
+	Map(Vec<(Cbor, Cbor)>),
+	Tag(u64, Box<Cbor>),
+	Other,
+}
+
+/// Decode CAR header and return its roots.
+fn decode_header(header: &[u8]) -> Result<Vec<Cid>, CarError> {
+	let mut rest = header;
+	let fields = match read_cbor(&mut rest, 0)? {
+		Cbor::Map(fields) => fields,
+		_ => return Err(CarError::InvalidHeader),
+	};
+	let field = |name: &str| {
+		fields
+			.iter()
+			.find(|(key, _)| matches!(key, Cbor::Text(key) if key == name))
+			.map(|(_, value)| value)
+	};
+	match field("version") {
+		Some(Cbor::Uint(1)) => {}
+		Some(Cbor::Uint(version)) => return Err(CarError::UnsupportedVersion(*version)),
+		_ => return Err(CarError::InvalidHeader),
+	}
+	let roots = match field("roots") {
+		Some(Cbor::Array(roots)) => roots,
+		_ => return Err(CarError::InvalidHeader),
+	};
+	roots
+		.iter()
+		.map(|root| match root {
+			// CIDs are tagged byte strings with the identity multibase prefix
+			Cbor::Tag(42, value) => match value.as_ref() {
+				Cbor::Bytes(bytes) if bytes.first() == Some(&0) => {
+					Ok(Cid::from_bytes(&bytes[1..])?)
+				}
+				_ => Err(CarError::InvalidHeader),
+			},
+			_ => Err(CarError::InvalidHeader),
+		})
+		.collect()
+}
+
+/// Read DAG-CBOR value from the beginning of `bytes`, advancing it.
+fn read_cbor(bytes: &mut &[u8], depth: usize) -> Result<Cbor, CarError> {
+	if depth > MAX_HEADER_DEPTH {
+		return Err(CarError::InvalidHeader);
+	}
+	let (&initial, rest) = bytes.split_first().ok_or(CarError::InvalidHeader)?;
+	*bytes = rest;
+	let (major, info) = (initial >> 5, initial & 0x1f);
+	let argument = match info {
+		0..=23 => u64::from(info),
+		24..=27 => {
+			let length = 1 << (info - 24);
+			if bytes.len() < length {
+				return Err(CarError::InvalidHeader);
+			}
+			let (argument, rest) = bytes.split_at(length);
+			*bytes = rest;
+			argument
+				.iter()
+				.fold(0, |value, &byte| (value << 8) | u64::from(byte))
+		}
+		// Indefinite lengths are not allowed in DAG-CBOR
+		_ => return Err(CarError::InvalidHeader),
+	};
+	let mut take = |length: u64| {
+		if (bytes.len() as u64) < length {
+			return Err(CarError::InvalidHeader);
+		}
+		let (taken, rest) = bytes.split_at(length as usize);
+		*bytes = rest;
+		Ok(taken.to_vec())
+	};
+	Ok(match major {
+		0 => Cbor::Uint(argument),
+		2 => Cbor::Bytes(take(argument)?),
+		3 => Cbor::Text(String::from_utf8(take(argument)?).map_err(|_| CarError::InvalidHeader)?),
+		4 => Cbor::Array(
+			(0..argument)
+				.map(|_| read_cbor(bytes, depth + 1))
+				.collect::<Result<_, _>>()?,
+		),
+		5 => Cbor::Map(
+			(0..argument)
+				.map(|_| Ok((read_cbor(bytes, depth + 1)?, read_cbor(bytes, depth + 1)?)))
+				.collect::<Result<_, CarError>>()?,
+		),
+		6 => Cbor::Tag(argument, Box::new(read_cbor(bytes, depth + 1)?)),
+		// Negative integers, floats and simple values
+		_ => Cbor::Other,
+	})
+}
+
+/// Type of UnixFS node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+	/// Raw data, a chunk of a file.
+	Raw,
+	/// Directory.
+	Directory,
+	/// File or its part.
+	File,
+	/// Metadata of a file.
+	Metadata,
+	/// Symbolic link.
+	Symlink,
+	/// Part of a sharded directory.
+	HamtShard,
+}
+
+/// Decoded UnixFS `dag-pb` node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixFsNode {
+	/// Type of the node.
+	pub kind: NodeKind,
+	/// Data stored in the node itself. For files it precedes the data of
+	/// linked blocks.
+	pub data: Vec<u8>,
+	/// Linked blocks, in order.
+	pub links: Vec<Cid>,
+}
+
+impl UnixFsNode {
+	/// Decode `dag-pb` block as a UnixFS node.
+	pub fn decode(block: &[u8]) -> Result<Self, CarError> {
+		let mut unixfs = None;
+		let mut links = Vec::new();
+		for field in read_protobuf(block)? {
+			match field {
+				(1, ProtobufField::Bytes(data)) => unixfs = Some(data),
+				(2, ProtobufField::Bytes(link)) => {
+					for field in read_protobuf(link)? {
+						if let (1, ProtobufField::Bytes(hash)) = field {
+							links.push(Cid::from_bytes(hash)?);
+						}
+					}
+				}
+				_ => return Err(CarError::InvalidNode),
+			}
+		}
+
+		let mut kind = None;
+		let mut data = Vec::new();
+		for field in read_protobuf(unixfs.ok_or(CarError::InvalidNode)?)? {
+			match field {
+				(1, ProtobufField::Varint(value)) => {
+					kind = Some(match value {
+						0 => NodeKind::Raw,
+						1 => NodeKind::Directory,
+						2 => NodeKind::File,
+						3 => NodeKind::Metadata,
+						4 => NodeKind::Symlink,
+						5 => NodeKind::HamtShard,
+						_ => return Err(CarError::InvalidNode),
+					})
+				}
+				(2, ProtobufField::Bytes(bytes)) => data = bytes.to_vec(),
+				_ => {}
+			}
+		}
+		Ok(Self {
+			kind: kind.ok_or(CarError::InvalidNode)?,
+			data,
+			links,
+		})
+	}
+}
+
+/// Value of a protobuf field.
+enum ProtobufField<'a> {
+	Varint(u64),
+	Bytes(&'a [u8]),
+}
+
+/// Read all fields of a protobuf message as field numbers with values.
+///
+/// Fixed-size fields are skipped, they are not used by UnixFS.
+fn read_protobuf(mut bytes: &[u8]) -> Result<Vec<(u64, ProtobufField<'_>)>, CarError> {
+	let mut fields = Vec::new();
+	while !bytes.is_empty() {
+		let key = read_varint(&mut bytes).map_err(|_| CarError::InvalidNode)?;
+		let skip = match key & 0x7 {
+			0 => {
+				let value = read_varint(&mut bytes).map_err(|_| CarError::InvalidNode)?;
+				fields.push((key >> 3, ProtobufField::Varint(value)));
+				0
+			}
+			2 => {
+				let length = read_varint(&mut bytes).map_err(|_| CarError::InvalidNode)?;
+				if (bytes.len() as u64) < length {
+					return Err(CarError::InvalidNode);
+				}
+				let (value, rest) = bytes.split_at(length as usize);
+				fields.push((key >> 3, ProtobufField::Bytes(value)));
+				bytes = rest;
+				0
+			}
+			1 => 8,
+			5 => 4,
+			_ => return Err(CarError::InvalidNode),
+		};
+		if bytes.len() < skip {
+			return Err(CarError::InvalidNode);
+		}
+		bytes = &bytes[skip..];
+	}
+	Ok(fields)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::utils::cid::{write_varint, Multihash, DAG_PB, RAW};
+	use crate::utils::crypto::HashAlgorithm;
+
+	/// Get CIDv1 of the block.
+	pub(crate) fn cid(codec: u64, block: &[u8]) -> Cid {
+		Cid {
+			version: 1,
+			codec,
+			hash: Multihash::digest(HashAlgorithm::Sha256, block),
+		}
+	}
+
+	/// Encode protobuf length-delimited field.
+	fn bytes_field(field: u64, value: &[u8], message: &mut Vec<u8>) {
+		write_varint(field << 3 | 2, message);
+		write_varint(value.len() as u64, message);
+		message.extend_from_slice(value);
+	}
+
+	/// Encode UnixFS node as a `dag-pb` block.
+	pub(crate) fn unixfs_node(kind: u64, data: &[u8], links: &[Cid]) -> Vec<u8> {
+		let mut node = Vec::new();
+		for link in links {
+			let mut encoded = Vec::new();
+			bytes_field(1, &link.to_bytes(), &mut encoded);
+			bytes_field(2, b"", &mut encoded);
+			bytes_field(2, &encoded, &mut node);
+		}
+		let mut unixfs = vec![0x08];
+		write_varint(kind, &mut unixfs);
+		if !data.is_empty() {
+			bytes_field(2, data, &mut unixfs);
+		}
+		bytes_field(1, &unixfs, &mut node);
+		node
+	}
+
+	/// Encode CARv1 archive.
+	pub(crate) fn car(root: &Cid, blocks: &[(Cid, Vec<u8>)]) -> Vec<u8> {
+		let mut header = b"\xa2\x65roots\x81\xd8\x2a\x58".to_vec();
+		let root = root.to_bytes();
+		header.push(root.len() as u8 + 1);
+		header.push(0);
+		header.extend(root);
+		header.extend(b"\x67version\x01");
+
+		let mut car = Vec::new();
+		write_varint(header.len() as u64, &mut car);
+		car.extend(header);
+		for (cid, data) in blocks {
+			let cid = cid.to_bytes();
+			write_varint((cid.len() + data.len()) as u64, &mut car);
+			car.extend(cid);
+			car.extend(data);
+		}
+		car
+	}
+
+	#[test]
+	fn test_car_decoder() {
+		let leaf = b"leaf".to_vec();
+		let leaf_cid = cid(RAW, &leaf);
+		let file = unixfs_node(2, b"head ", std::slice::from_ref(&leaf_cid));
+		let file_cid = cid(DAG_PB, &file);
+		let archive = car(
+			&file_cid,
+			&[(file_cid.clone(), file.clone()), (leaf_cid.clone(), leaf)],
+		);
+
+		// Byte by byte, like the smallest network chunks
+		let mut decoder = CarDecoder::new();
+		let mut blocks = Vec::new();
+		for byte in &archive {
+			decoder.push(std::slice::from_ref(byte));
+			while let Some(block) = decoder.next_block().unwrap() {
+				blocks.push(block);
+			}
+		}
+		decoder.finish().unwrap();
+		assert_eq!(decoder.roots(), Some(&[file_cid.clone()][..]));
+		assert_eq!(blocks.len(), 2);
+		assert_eq!(blocks[1].cid, leaf_cid);
+
+		let node = UnixFsNode::decode(&blocks[0].data).unwrap();
+		assert_eq!(node.kind, NodeKind::File);
+		assert_eq!(node.data, b"head ");
+		assert_eq!(node.links, vec![leaf_cid.clone()]);
+
+		// Truncated archive
+		let mut decoder = CarDecoder::new();
+		decoder.push(&archive[..archive.len() - 1]);
+		while decoder.next_block().unwrap().is_some() {}
+		assert!(matches!(decoder.finish(), Err(CarError::Truncated)));
+
+		// Block which doesn't match its CID
+		let mut corrupted = archive.clone();
+		*corrupted.last_mut().unwrap() ^= 1;
+		let mut decoder = CarDecoder::new();
+		decoder.push(&corrupted);
+		assert!(decoder.next_block().unwrap().is_some());
+		assert!(matches!(
+			decoder.next_block(),
+			Err(CarError::InvalidBlock(CidError::DigestMismatch(_)))
+		));
+	}
+}
@@ -0,0 +1,432 @@
+//! IPFS content identifiers.
+//!
+//! Artifact paths in manifests are IPFS CIDs. A CID contains the multihash of
+//! the block it addresses, so content of a `raw` block can be verified locally
+//! instead of trusting the gateway which served it. Content of `dag-pb` CIDs
+//! is split into blocks, so only the root block can be checked with
+//! [`Cid::verify`].
+//!
+//! Only CIDv0 and CIDv1 in base32, base58btc and base16 multibase encodings
+//! are supported.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::crypto::{ContentHash, HashAlgorithm};
+
+/// Multicodec of raw binary blocks.
+pub const RAW: u64 = 0x55;
+/// Multicodec of UnixFS blocks, used by CIDv0.
+pub const DAG_PB: u64 = 0x70;
+
+/// Multihash code of the identity "hash", which is the data itself.
+pub const IDENTITY: u64 = 0x00;
+/// Multihash code of SHA-1.
+pub const SHA1: u64 = 0x11;
+/// Multihash code of SHA-256.
+pub const SHA2_256: u64 = 0x12;
+/// Multihash code of SHA-512.
+pub const SHA2_512: u64 = 0x13;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// CID error.
+#[derive(thiserror::Error, Debug)]
+pub enum CidError {
+	/// CID is not properly encoded.
+	#[error("Invalid CID encoding")]
+	InvalidEncoding,
+	/// Multibase prefix is not supported.
+	#[error("Unsupported multibase prefix: {0}")]
+	UnsupportedMultibase(char),
+	/// Varint is truncated or too long.
+	#[error("Invalid varint")]
+	InvalidVarint,
+	/// CID version is not supported.
+	#[error("Unsupported CID version: {0}")]
+	UnsupportedVersion(u64),
+	/// CID is truncated or has trailing bytes.
+	#[error("Invalid CID length")]
+	InvalidLength,
+	/// Multihash function is not supported.
+	#[error("Unsupported multihash: {0:#x}")]
+	UnsupportedHash(u64),
+	/// Block doesn't match the CID.
+	#[error("Block doesn't match CID {0}")]
+	DigestMismatch(String),
+}
+
+/// Self-describing hash.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Multihash {
+	/// Hash function code.
+	pub code: u64,
+	/// Digest bytes.
+	pub digest: Vec<u8>,
+}
+
+impl Multihash {
+	/// Calculate multihash of the given data.
+	pub fn digest(algorithm: HashAlgorithm, data: &[u8]) -> Self {
+		let hash = ContentHash::digest(algorithm, data);
+		Self {
+			code: match algorithm {
+				HashAlgorithm::Sha1 => SHA1,
+				HashAlgorithm::Sha256 => SHA2_256,
+				HashAlgorithm::Sha512 => SHA2_512,
+			},
+			digest: hex::decode(hash.hex).unwrap(),
+		}
+	}
+
+	/// Get the hash algorithm, if it's supported.
+	pub fn algorithm(&self) -> Option<HashAlgorithm> {
+		match self.code {
+			SHA1 => Some(HashAlgorithm::Sha1),
+			SHA2_256 => Some(HashAlgorithm::Sha256),
+			SHA2_512 => Some(HashAlgorithm::Sha512),
+			_ => None,
+		}
+	}
+
+	/// Get the digest as a content hash, if the algorithm is supported and the
+	/// digest is not truncated.
+	pub fn content_hash(&self) -> Option<ContentHash> {
+		let hash = ContentHash::new(self.algorithm()?, &hex::encode(&self.digest));
+		hash.is_valid().then_some(hash)
+	}
+
+	/// Read multihash from the beginning of `bytes`, advancing it.
+	fn read(bytes: &mut &[u8]) -> Result<Self, CidError> {
+		let code = read_varint(bytes)?;
+		let length = read_varint(bytes)? as usize;
+		if bytes.len() < length {
+			return Err(CidError::InvalidLength);
+		}
+		let (digest, rest) = bytes.split_at(length);
+		*bytes = rest;
+		Ok(Self {
+			code,
+			digest: digest.to_vec(),
+		})
+	}
+
+	/// Encode multihash to bytes.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(self.digest.len() + 2);
+		write_varint(self.code, &mut bytes);
+		write_varint(self.digest.len() as u64, &mut bytes);
+		bytes.extend_from_slice(&self.digest);
+		bytes
+	}
+}
+
+/// Content identifier.
+///
+/// # Examples
+///
+/// ```
+/// use firelaunch::utils::cid::{Cid, RAW};
+///
+/// let cid: Cid = "bafkreib3tq2y6nxqumnwvu7bj4yjy7hrtcwjerxigfxzzzkd2wyzvqblqa"
+///   .parse()
+///   .unwrap();
+/// assert_eq!(cid.codec, RAW);
+/// assert!(cid.verify(b"file").is_ok());
+/// assert!(cid.verify(b"other").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cid {
+	/// CID version, `0` or `1`.
+	pub version: u64,
+	/// Multicodec of the block.
+	pub codec: u64,
+	/// Multihash of the block.
+	pub hash: Multihash,
+}
+
+impl Cid {
+	/// Decode binary CID.
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, CidError> {
+		let mut rest = bytes;
+		let cid = Self::read(&mut rest)?;
+		if !rest.is_empty() {
+			return Err(CidError::InvalidLength);
+		}
+		Ok(cid)
+	}
+
+	/// Read binary CID from the beginning of `bytes`, advancing it.
+	pub(super) fn read(bytes: &mut &[u8]) -> Result<Self, CidError> {
+		// CIDv0 is a bare SHA-256 multihash
+		if bytes.starts_with(&[SHA2_256 as u8, 32]) {
+			return Ok(Self {
+				version: 0,
+				codec: DAG_PB,
+				hash: Multihash::read(bytes)?,
+			});
+		}
+		let version = read_varint(bytes)?;
+		if version != 1 {
+			return Err(CidError::UnsupportedVersion(version));
+		}
+		Ok(Self {
+			version,
+			codec: read_varint(bytes)?,
+			hash: Multihash::read(bytes)?,
+		})
+	}
+
+	/// Encode CID to bytes.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		if self.version == 0 {
+			return self.hash.to_bytes();
+		}
+		let mut bytes = Vec::new();
+		write_varint(self.version, &mut bytes);
+		write_varint(self.codec, &mut bytes);
+		bytes.extend(self.hash.to_bytes());
+		bytes
+	}
+
+	/// Get the hash of the content, if it can be verified without decoding
+	/// blocks.
+	///
+	/// It's only possible for `raw` CIDs with a supported hash function,
+	/// because the block is the content itself.
+	pub fn content_hash(&self) -> Option<ContentHash> {
+		match self.codec {
+			RAW => self.hash.content_hash(),
+			_ => None,
+		}
+	}
+
+	/// Check that the block matches the CID.
+	///
+	/// # Errors
+	///
+	/// - [`CidError::UnsupportedHash`] if the hash function is not supported.
+	/// - [`CidError::DigestMismatch`] if the block doesn't match.
+	pub fn verify(&self, block: &[u8]) -> Result<(), CidError> {
+		let valid = match self.hash.code {
+			IDENTITY => self.hash.digest == block,
+			code => {
+				let algorithm = self
+					.hash
+					.algorithm()
+					.ok_or(CidError::UnsupportedHash(code))?;
+				Multihash::digest(algorithm, block) == self.hash
+			}
+		};
+		match valid {
+			true => Ok(()),
+			false => Err(CidError::DigestMismatch(self.to_string())),
+		}
+	}
+}
+
+impl FromStr for Cid {
+	type Err = CidError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.len() == 46 && s.starts_with("Qm") {
+			return Self::from_bytes(&base58_decode(s).ok_or(CidError::InvalidEncoding)?);
+		}
+		let mut chars = s.chars();
+		let prefix = chars.next().ok_or(CidError::InvalidEncoding)?;
+		let bytes = match prefix {
+			'b' | 'B' => base32_decode(chars.as_str()),
+			'z' => base58_decode(chars.as_str()),
+			'f' | 'F' => hex::decode(chars.as_str()).ok(),
+			_ => return Err(CidError::UnsupportedMultibase(prefix)),
+		};
+		Self::from_bytes(&bytes.ok_or(CidError::InvalidEncoding)?)
+	}
+}
+
+/// CIDv0 is formatted in base58btc, CIDv1 in base32.
+impl fmt::Display for Cid {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.version {
+			0 => f.write_str(&base58_encode(&self.to_bytes())),
+			_ => write!(f, "b{}", base32_encode(&self.to_bytes())),
+		}
+	}
+}
+
+/// Read unsigned varint from the beginning of `bytes`, advancing it.
+pub(super) fn read_varint(bytes: &mut &[u8]) -> Result<u64, CidError> {
+	let mut value = 0;
+	// Multiformats limit varints to 9 bytes
+	for i in 0..9 {
+		let (&byte, rest) = bytes.split_first().ok_or(CidError::InvalidVarint)?;
+		*bytes = rest;
+		value |= u64::from(byte & 0x7f) << (7 * i);
+		if byte & 0x80 == 0 {
+			return Ok(value);
+		}
+	}
+	Err(CidError::InvalidVarint)
+}
+
+/// Append unsigned varint to `bytes`.
+pub(super) fn write_varint(mut value: u64, bytes: &mut Vec<u8>) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			bytes.push(byte);
+			return;
+		}
+		bytes.push(byte | 0x80);
+	}
+}
+
+/// Decode unpadded RFC 4648 base32, case-insensitive.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+	let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+	let (mut buffer, mut bits) = (0u32, 0);
+	for c in s.bytes() {
+		let value = BASE32_ALPHABET
+			.iter()
+			.position(|&a| a == c.to_ascii_lowercase())?;
+		buffer = (buffer << 5) | value as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			bytes.push((buffer >> bits) as u8);
+			buffer &= (1 << bits) - 1;
+		}
+	}
+	Some(bytes)
+}
+
+/// Encode bytes to unpadded lowercase RFC 4648 base32.
+fn base32_encode(bytes: &[u8]) -> String {
+	let mut result = String::with_capacity((bytes.len() * 8).div_ceil(5));
+	let (mut buffer, mut bits) = (0u32, 0);
+	for &byte in bytes {
+		buffer = (buffer << 8) | u32::from(byte);
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			result.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+		}
+		buffer &= (1 << bits) - 1;
+	}
+	if bits > 0 {
+		result.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+	}
+	result
+}
+
+/// Decode base58btc.
+fn base58_decode(s: &str) -> Option<Vec<u8>> {
+	// Little-endian base256 digits
+	let mut bytes: Vec<u8> = Vec::with_capacity(s.len());
+	for c in s.bytes() {
+		let mut carry = BASE58_ALPHABET.iter().position(|&a| a == c)? as u32;
+		for byte in bytes.iter_mut() {
+			carry += u32::from(*byte) * 58;
+			*byte = carry as u8;
+			carry >>= 8;
+		}
+		while carry > 0 {
+			bytes.push(carry as u8);
+			carry >>= 8;
+		}
+	}
+	// Leading ones are leading zero bytes
+	bytes.extend(s.bytes().take_while(|&c| c == b'1').map(|_| 0));
+	bytes.reverse();
+	Some(bytes)
+}
+
+/// Encode bytes to base58btc.
+fn base58_encode(bytes: &[u8]) -> String {
+	// Little-endian base58 digits
+	let mut digits: Vec<u8> = Vec::with_capacity(bytes.len() * 138 / 100 + 1);
+	for &byte in bytes {
+		let mut carry = u32::from(byte);
+		for digit in digits.iter_mut() {
+			carry += u32::from(*digit) << 8;
+			*digit = (carry % 58) as u8;
+			carry /= 58;
+		}
+		while carry > 0 {
+			digits.push((carry % 58) as u8);
+			carry /= 58;
+		}
+	}
+	let zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+	"1".repeat(zeros)
+		+ &digits
+			.iter()
+			.rev()
+			.map(|&digit| BASE58_ALPHABET[digit as usize] as char)
+			.collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_cid() {
+		let v0: Cid = "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n"
+			.parse()
+			.unwrap();
+		assert_eq!((v0.version, v0.codec), (0, DAG_PB));
+		assert_eq!(
+			v0.to_string(),
+			"QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n"
+		);
+		assert_eq!(v0.content_hash(), None);
+
+		let v1: Cid = "bafybeihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+			.parse()
+			.unwrap();
+		assert_eq!((v1.version, v1.codec), (1, DAG_PB));
+		assert_eq!(v1.hash, v0.hash);
+		assert_eq!(
+			v1.to_string(),
+			"bafybeihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+		);
+		let base16: Cid = format!("f{}", hex::encode(v1.to_bytes())).parse().unwrap();
+		assert_eq!(base16, v1);
+		let base58: Cid = format!("z{}", base58_encode(&v1.to_bytes()))
+			.parse()
+			.unwrap();
+		assert_eq!(base58, v1);
+
+		let raw: Cid = "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+			.parse()
+			.unwrap();
+		assert_eq!(
+			raw.content_hash(),
+			Some(ContentHash::new(
+				HashAlgorithm::Sha256,
+				"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+			))
+		);
+		assert!(raw.verify(b"").is_ok());
+		assert!(matches!(
+			raw.verify(b"data"),
+			Err(CidError::DigestMismatch(_))
+		));
+
+		assert!(matches!(
+			"file".parse::<Cid>(),
+			Err(CidError::InvalidEncoding)
+		));
+		assert!(matches!(
+			"xyz".parse::<Cid>(),
+			Err(CidError::UnsupportedMultibase('x'))
+		));
+		assert!(matches!(
+			"bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvy".parse::<Cid>(),
+			Err(CidError::InvalidLength)
+		));
+	}
+}
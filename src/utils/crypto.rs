@@ -3,7 +3,10 @@
 //! This module contains various utilities for cryptography, such as
 //! hash calculation, salt generation, signature verification, etc.
 
+use std::fmt;
+
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use sha1::Digest;
 
 /// Generates a random string of the given length.
@@ -69,6 +72,172 @@ pub fn sha1_digest(data: &[u8]) -> String {
 	hex::encode(result)
 }
 
+/// Calculates the SHA-512 digest of the given data.
+///
+/// # Examples
+///
+/// ```
+/// use firelaunch::utils::crypto::sha512_digest;
+///
+/// let data = b"Hello, world!";
+/// let digest = sha512_digest(data);
+/// assert_eq!(&digest[..16], "c1527cd893c12477");
+/// ```
+pub fn sha512_digest(data: &[u8]) -> String {
+	let mut hasher = sha2::Sha512::new();
+	hasher.update(data);
+	hex::encode(hasher.finalize())
+}
+
+/// Hash algorithm used for content addressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+	/// SHA-1, used by Minecraft assets and libraries.
+	Sha1,
+	/// SHA-256, used by runtime archives.
+	Sha256,
+	/// SHA-512, used by Modrinth mod files.
+	Sha512,
+}
+
+impl HashAlgorithm {
+	/// All supported algorithms.
+	pub const ALL: [HashAlgorithm; 3] = [Self::Sha1, Self::Sha256, Self::Sha512];
+
+	/// Get lowercase name of the algorithm.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Sha1 => "sha1",
+			Self::Sha256 => "sha256",
+			Self::Sha512 => "sha512",
+		}
+	}
+
+	/// Get length of the hex encoded digest.
+	pub fn hex_len(&self) -> usize {
+		match self {
+			Self::Sha1 => 40,
+			Self::Sha256 => 64,
+			Self::Sha512 => 128,
+		}
+	}
+
+	/// Create a new incremental hasher for the algorithm.
+	pub fn hasher(&self) -> Hasher {
+		match self {
+			Self::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+			Self::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+			Self::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+		}
+	}
+}
+
+impl fmt::Display for HashAlgorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// Incremental hasher for any of [`HashAlgorithm`]s.
+#[derive(Debug, Clone)]
+pub enum Hasher {
+	/// SHA-1 hasher.
+	Sha1(sha1::Sha1),
+	/// SHA-256 hasher.
+	Sha256(sha2::Sha256),
+	/// SHA-512 hasher.
+	Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+	/// Feed data to the hasher.
+	pub fn update(&mut self, data: &[u8]) {
+		match self {
+			Self::Sha1(hasher) => hasher.update(data),
+			Self::Sha256(hasher) => hasher.update(data),
+			Self::Sha512(hasher) => hasher.update(data),
+		}
+	}
+
+	/// Finish hashing and return hex encoded digest.
+	pub fn finalize(self) -> String {
+		match self {
+			Self::Sha1(hasher) => hex::encode(hasher.finalize()),
+			Self::Sha256(hasher) => hex::encode(hasher.finalize()),
+			Self::Sha512(hasher) => hex::encode(hasher.finalize()),
+		}
+	}
+}
+
+/// Typed content digest.
+///
+/// It's used as a key of objects in the storage.
+///
+/// # Examples
+///
+/// ```
+/// use firelaunch::utils::crypto::{ContentHash, HashAlgorithm};
+///
+/// let hash = ContentHash::digest(HashAlgorithm::Sha1, b"Hello, world!");
+/// assert_eq!(hash, ContentHash::sha1("943a702d06f34599aee1f8da8ef9f7296031d699"));
+/// assert_eq!(hash.to_string(), "sha1:943a702d06f34599aee1f8da8ef9f7296031d699");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ContentHash {
+	/// Hash algorithm.
+	pub algorithm: HashAlgorithm,
+	/// Lowercase hex encoded digest.
+	pub hex: String,
+}
+
+impl ContentHash {
+	/// Create a new content hash.
+	pub fn new(algorithm: HashAlgorithm, hex: &str) -> Self {
+		Self {
+			algorithm,
+			hex: hex.to_ascii_lowercase(),
+		}
+	}
+
+	/// Create a new SHA-1 content hash.
+	#[inline]
+	pub fn sha1(hex: &str) -> Self {
+		Self::new(HashAlgorithm::Sha1, hex)
+	}
+
+	/// Calculate content hash of the given data.
+	pub fn digest(algorithm: HashAlgorithm, data: &[u8]) -> Self {
+		let mut hasher = algorithm.hasher();
+		hasher.update(data);
+		Self {
+			algorithm,
+			hex: hasher.finalize(),
+		}
+	}
+
+	/// Returns the hex encoded digest if it's a SHA-1 hash.
+	#[inline]
+	pub fn as_sha1(&self) -> Option<&str> {
+		(self.algorithm == HashAlgorithm::Sha1).then_some(self.hex.as_str())
+	}
+
+	/// Check that the digest has correct length and consists of hex digits.
+	pub fn is_valid(&self) -> bool {
+		self.hex.len() == self.algorithm.hex_len()
+			&& self
+				.hex
+				.bytes()
+				.all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+	}
+}
+
+impl fmt::Display for ContentHash {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.algorithm, self.hex)
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -95,4 +264,19 @@ mod tests {
 		let digest = sha1_digest(data);
 		assert_eq!(digest, "943a702d06f34599aee1f8da8ef9f7296031d699");
 	}
+
+	#[test]
+	fn test_content_hash() {
+		let data = b"Hello, world!";
+		let hash = ContentHash::digest(HashAlgorithm::Sha256, data);
+		assert_eq!(hash.hex, sha256_digest(data));
+		assert!(hash.is_valid());
+
+		let hash = ContentHash::digest(HashAlgorithm::Sha512, data);
+		assert_eq!(hash.hex, sha512_digest(data));
+		assert!(hash.is_valid());
+
+		assert!(!ContentHash::sha1("../../etc/passwd").is_valid());
+		assert!(!ContentHash::sha1(&sha256_digest(data)).is_valid());
+	}
 }
@@ -0,0 +1,83 @@
+//! Filesystem utilities.
+
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncWriteExt;
+
+use super::crypto::generate_random_string;
+
+/// Write the file atomically.
+///
+/// Contents are written to a uniquely named temporary file, synced to disk and
+/// renamed into place, so other processes never see a partially written file.
+pub async fn write_file_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+	let mut tmp_path = path.as_os_str().to_owned();
+	tmp_path.push(format!(".{}.tmp", generate_random_string(8)));
+	let tmp_path = PathBuf::from(tmp_path);
+	let result = async {
+		let mut file = tokio::fs::File::create(&tmp_path).await?;
+		file.write_all(contents).await?;
+		file.sync_all().await?;
+		tokio::fs::rename(&tmp_path, path).await
+	}
+	.await;
+	if result.is_err() {
+		let _ = tokio::fs::remove_file(&tmp_path).await;
+	}
+	result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	/// Temporary directory, removed with its contents when dropped, even if
+	/// the test fails.
+	#[derive(Debug)]
+	pub(crate) struct TempDir(PathBuf);
+
+	impl TempDir {
+		/// Create a new uniquely named directory in the system temporary
+		/// directory.
+		pub(crate) fn new() -> Self {
+			let path = std::env::temp_dir()
+				.join(format!("firelaunch-test-{}", generate_random_string(16)));
+			std::fs::create_dir_all(&path).unwrap();
+			Self(path)
+		}
+
+		/// Get path of the directory.
+		pub(crate) fn path(&self) -> &Path {
+			&self.0
+		}
+
+		/// Get path of the given file in the directory.
+		pub(crate) fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+			self.0.join(path)
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			if let Err(e) = std::fs::remove_dir_all(&self.0) {
+				eprintln!("Failed to remove {}: {}", self.0.display(), e);
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn test_write_file_atomic() {
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		write_file_atomic(&path, b"first").await.unwrap();
+		write_file_atomic(&path, b"second").await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), b"second");
+		// Temporary files are renamed into place
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+		let path = dir.path().to_path_buf();
+		drop(dir);
+		assert!(!path.exists());
+	}
+}
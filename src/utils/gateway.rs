@@ -0,0 +1,735 @@
+//! IPFS downloads from a gateway pool with health tracking.
+//!
+//! [`GatewayPool`] keeps an ordered list of IPFS gateways together with their
+//! observed latency and errors. Gateways which failed recently are put on a
+//! cooldown and used only if every other gateway is cooling down too.
+//!
+//! IPFS downloads of [`NetClient`] fail over between sources: the URL set by a
+//! rewrite rule, the local IPFS node and the gateways of the pool, see
+//! [`NetClient::download_ipfs_part_with`].
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::header::ACCEPT;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use tokio::fs;
+
+use super::cid::Cid;
+use super::crypto::{ContentHash, HashAlgorithm};
+use super::kubo::KuboApi;
+use super::net::{
+	download_resumable, fetch, hash_file, part_path, BodyStream, NetClient, NetworkError,
+};
+
+/// Default IPFS gateways, in order of preference.
+pub const DEFAULT_GATEWAYS: [&str; 3] = [
+	"https://ipfs.frsqr.xyz/ipfs/",
+	"https://ipfs.io/ipfs/",
+	"https://dweb.link/ipfs/",
+];
+
+/// Default cooldown period of a failed gateway.
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Longest cooldown of a failed gateway, regardless of the failure count.
+const MAX_COOLDOWN: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Latency assumed for gateways which were not used yet.
+///
+/// Gateways are tried in the configured order until the latency of the
+/// preferred ones is known to be worse than this.
+const UNKNOWN_LATENCY: Duration = Duration::from_secs(1);
+
+/// Weight of the latest latency sample in the moving average.
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// Health of a single gateway.
+#[derive(Debug, Clone)]
+pub struct GatewayStatus {
+	/// Gateway URL prefix, e.g. `https://ipfs.io/ipfs/`.
+	pub url: String,
+	/// Moving average of the time to the response headers.
+	pub latency: Option<Duration>,
+	/// Number of failures since the last success.
+	pub failures: u32,
+	/// Total number of successful requests.
+	pub successes: u64,
+	/// End of the cooldown, if the gateway is cooling down.
+	pub cooldown_until: Option<Instant>,
+}
+
+impl GatewayStatus {
+	fn new(url: &str) -> Self {
+		Self {
+			url: url.to_string(),
+			latency: None,
+			failures: 0,
+			successes: 0,
+			cooldown_until: None,
+		}
+	}
+
+	/// Check if the gateway is cooling down after a failure.
+	pub fn is_cooling_down(&self, now: Instant) -> bool {
+		matches!(self.cooldown_until, Some(until) if until > now)
+	}
+
+	/// Score of the gateway, lower is better.
+	fn score(&self) -> Duration {
+		self.latency
+			.unwrap_or(UNKNOWN_LATENCY)
+			.saturating_mul(self.failures.saturating_add(1))
+	}
+}
+
+/// Ordered list of IPFS gateways with health tracking.
+///
+/// It's shared between clones of [`NetClient`], so all
+/// of them learn about failing gateways.
+#[derive(Debug)]
+pub struct GatewayPool {
+	gateways: Mutex<Vec<GatewayStatus>>,
+	cooldown: Mutex<Duration>,
+}
+
+impl GatewayPool {
+	/// Creates a new pool with the given gateway URL prefixes in order of
+	/// preference.
+	///
+	/// If `urls` is empty, [`DEFAULT_GATEWAYS`] are used instead.
+	pub fn new<S: AsRef<str>>(urls: &[S]) -> Self {
+		let gateways = if urls.is_empty() {
+			warn!("No IPFS gateways configured, using the default ones");
+			DEFAULT_GATEWAYS
+				.iter()
+				.map(|url| GatewayStatus::new(url))
+				.collect()
+		} else {
+			urls.iter()
+				.map(|url| GatewayStatus::new(url.as_ref()))
+				.collect()
+		};
+		Self {
+			gateways: Mutex::new(gateways),
+			cooldown: Mutex::new(DEFAULT_COOLDOWN),
+		}
+	}
+
+	/// Sets how long a failed gateway is skipped.
+	///
+	/// Cooldown grows linearly with the number of consecutive failures, up to
+	/// a day.
+	pub fn set_cooldown(&self, cooldown: Duration) {
+		*self.cooldown.lock().unwrap() = cooldown;
+	}
+
+	/// Get gateway URL prefixes, best first.
+	///
+	/// Gateways which are not cooling down go first, sorted by their latency
+	/// and recent failures. Gateways with equal scores keep the configured
+	/// order. Cooling down gateways go last, so they are still tried if
+	/// nothing else works.
+	pub fn ordered(&self) -> Vec<String> {
+		let now = Instant::now();
+		let mut gateways = self.gateways.lock().unwrap().clone();
+		gateways.sort_by_key(|gateway| {
+			let cooling_down = gateway.is_cooling_down(now);
+			(
+				cooling_down,
+				cooling_down.then_some(gateway.cooldown_until),
+				gateway.score(),
+			)
+		});
+		gateways.into_iter().map(|gateway| gateway.url).collect()
+	}
+
+	/// Get the best gateway URL prefix at the moment.
+	pub fn best(&self) -> String {
+		self.ordered().swap_remove(0)
+	}
+
+	/// Get health of all gateways in the configured order.
+	pub fn statuses(&self) -> Vec<GatewayStatus> {
+		self.gateways.lock().unwrap().clone()
+	}
+
+	/// Record a successful request to the gateway.
+	pub fn report_success(&self, url: &str, latency: Duration) {
+		let mut gateways = self.gateways.lock().unwrap();
+		if let Some(gateway) = gateways.iter_mut().find(|gateway| gateway.url == url) {
+			gateway.latency = Some(match gateway.latency {
+				Some(average) => {
+					average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT)
+				}
+				None => latency,
+			});
+			gateway.failures = 0;
+			gateway.successes += 1;
+			gateway.cooldown_until = None;
+		}
+	}
+
+	/// Record a failed request to the gateway and put it on a cooldown.
+	pub fn report_failure(&self, url: &str) {
+		let cooldown = *self.cooldown.lock().unwrap();
+		let mut gateways = self.gateways.lock().unwrap();
+		if let Some(gateway) = gateways.iter_mut().find(|gateway| gateway.url == url) {
+			gateway.failures = gateway.failures.saturating_add(1);
+			let cooldown = cooldown.saturating_mul(gateway.failures).min(MAX_COOLDOWN);
+			let now = Instant::now();
+			gateway.cooldown_until = Some(now.checked_add(cooldown).unwrap_or(now));
+			warn!(
+				"IPFS gateway {} failed {} times in a row, skipping it for {:?}",
+				url, gateway.failures, cooldown
+			);
+		}
+	}
+}
+
+impl Default for GatewayPool {
+	fn default() -> Self {
+		Self::new(&DEFAULT_GATEWAYS)
+	}
+}
+
+impl NetClient {
+	/// Sets the only IPFS gateway URL.
+	///
+	/// See [`NetClient::set_ipfs_gateways`] for details.
+	pub fn set_ipfs_gateway(&mut self, url: &str) {
+		self.set_ipfs_gateways(&[url]);
+	}
+
+	/// Sets IPFS gateway URLs in order of preference.
+	///
+	/// IPFS downloads fail over to the next gateway if one fails, and failed
+	/// gateways are skipped for a while. The default gateways are
+	/// [`DEFAULT_GATEWAYS`].
+	///
+	/// If `urls` is empty, the current gateways are kept.
+	pub fn set_ipfs_gateways<S: AsRef<str>>(&mut self, urls: &[S]) {
+		if urls.is_empty() {
+			warn!("Ignoring an empty list of IPFS gateways");
+			return;
+		}
+		self.gateways = Arc::new(GatewayPool::new(urls));
+	}
+
+	/// Returns the IPFS gateway pool.
+	#[inline]
+	pub fn gateways(&self) -> &GatewayPool {
+		&self.gateways
+	}
+
+	/// Gets the IPFS gateway URL for the given CID (or path).
+	///
+	/// The best gateway at the moment is used. You can change IPFS gateways by
+	/// using [`NetClient::set_ipfs_gateways`].
+	///
+	/// # Examples
+	///
+	/// ```
+	/// use firelaunch::utils::net::NetClient;
+	///
+	/// let client = NetClient::new();
+	/// assert_eq!("https://ipfs.frsqr.xyz/ipfs/CID", client.ipfs("CID"));
+	/// ```
+	pub fn ipfs(&self, cid: &str) -> String {
+		format!("{}{cid}", self.gateways.best())
+	}
+
+	/// Downloads a file from IPFS to the `.part` file and returns its hash
+	/// calculated with the given algorithm.
+	///
+	/// The local IPFS node is tried first, if it's set with
+	/// [`NetClient::set_kubo_api`]. Then gateways are tried from the best one,
+	/// failing over to the next one if the request fails. Existing `.part`
+	/// file is resumed only from the first source. It's discarded when the
+	/// download fails over, because bytes from a failed source can't be
+	/// trusted.
+	///
+	/// Every gateway is tried once, the download is not retried. Callers are
+	/// expected to retry the whole download with [`NetClient::retry_policy`]
+	/// after verifying the result.
+	///
+	/// If `size` is given, the download is aborted with
+	/// [`NetworkError::SizeMismatch`] as soon as the file is known to have a
+	/// different size, and the next gateway is tried.
+	///
+	/// If `cid` is a `raw` CID, the file is verified against it and
+	/// [`NetworkError::CidMismatch`] is returned if the last gateway sent a
+	/// wrong file. The `.part` file is removed then. See [`Cid::content_hash`].
+	///
+	/// If a rewrite rule applies to `cid`, the rewritten URL is tried before
+	/// all other sources, and the file is verified against `cid` the same way.
+	/// `sha1` is the SHA-1 hash the caller verifies the file against, if any,
+	/// see [`NetClient::resolve`].
+	///
+	/// `cid` may also be a URL. The file is downloaded directly from it then,
+	/// after applying rewrite rules.
+	///
+	/// See [`download_part_with`](super::net::download_part_with) for details.
+	pub async fn download_ipfs_part_with(
+		&self,
+		cid: &str,
+		path: &Path,
+		algorithm: HashAlgorithm,
+		size: Option<u64>,
+		sha1: Option<&str>,
+	) -> Result<String, NetworkError> {
+		let expected = cid_content_hash(cid);
+		let mut last_error = None;
+		for source in self.ipfs_sources(cid, sha1) {
+			if last_error.is_some() {
+				remove_part(path).await?;
+			}
+			let url = source.url(cid);
+			let mut hasher = algorithm.hasher();
+			let transfer = self.transfer(cid).with_expected_size(size);
+			let request = || source.request(&self.client, &url);
+			let result = download_resumable(request, &url, path, Some(&mut hasher), transfer)
+				.await
+				.map(|latency| (latency, hasher.finalize()));
+			let result = match (result, &expected) {
+				(Ok((latency, hash)), Some(expected)) => {
+					verify_part(cid, path, expected, &ContentHash::new(algorithm, &hash))
+						.await
+						.map(|_| (latency, hash))
+				}
+				(result, _) => result,
+			};
+			match result {
+				Ok((latency, hash)) => {
+					self.source_succeeded(&source, latency);
+					return Ok(hash);
+				}
+				Err(e) => last_error = Some(self.source_failed(&source, e)?),
+			}
+		}
+		Err(last_error.unwrap())
+	}
+
+	/// Downloads a file from IPFS into memory.
+	///
+	/// Sources are failed over, rewrite rules are applied and `size` and `cid`
+	/// are checked the same way as in [`NetClient::download_ipfs_part_with`].
+	pub async fn download_ipfs_bytes(
+		&self,
+		cid: &str,
+		size: Option<u64>,
+		sha1: Option<&str>,
+	) -> Result<Vec<u8>, NetworkError> {
+		let expected = cid_content_hash(cid);
+		let mut last_error = None;
+		for source in self.ipfs_sources(cid, sha1) {
+			let url = source.url(cid);
+			let transfer = self.transfer(cid).with_expected_size(size);
+			let request = source.request(&self.client, &url);
+			let result = match (fetch(request, &url, transfer).await, &expected) {
+				(Ok(response), Some(expected))
+					if ContentHash::digest(expected.algorithm, &response.data) != *expected =>
+				{
+					Err(NetworkError::CidMismatch(cid.to_string()))
+				}
+				(result, _) => result,
+			};
+			match result {
+				Ok(response) => {
+					self.source_succeeded(&source, response.latency);
+					return Ok(response.data);
+				}
+				Err(e) => last_error = Some(self.source_failed(&source, e)?),
+			}
+		}
+		Err(last_error.unwrap())
+	}
+
+	/// Opens the IPFS DAG as a CAR archive stream.
+	///
+	/// Sources are failed over until one of them responds. Errors while
+	/// receiving the body are returned as is, so the whole download must be
+	/// retried. Blocks are not verified, use
+	/// [`CarDecoder`](super::car::CarDecoder) for that.
+	///
+	/// Rewrite rules which don't need the SHA-1 hash apply, the archive is
+	/// requested from the rewritten URL with `?format=car` first.
+	pub async fn stream_ipfs_car<'a>(
+		&'a self,
+		cid: &'a str,
+	) -> Result<BodyStream<'a>, NetworkError> {
+		let mut last_error = None;
+		for source in self.ipfs_sources(cid, None) {
+			let url = source.car_url(cid);
+			let request = source
+				.request(&self.client, &url)
+				.header(ACCEPT, CAR_MEDIA_TYPE);
+			match BodyStream::open(request, &url, self.transfer(cid)).await {
+				Ok(stream) => {
+					self.source_succeeded(&source, stream.latency());
+					return Ok(stream);
+				}
+				Err(e) => last_error = Some(self.source_failed(&source, e)?),
+			}
+		}
+		Err(last_error.unwrap())
+	}
+
+	/// Sources of IPFS content in order of preference.
+	///
+	/// URL set by a rewrite rule is tried first, see [`NetClient::resolve`].
+	fn ipfs_sources(&self, cid: &str, sha1: Option<&str>) -> Vec<IpfsSource> {
+		let resolved = self.resolve(cid, sha1);
+		if cid.contains("://") {
+			return vec![IpfsSource::Url(resolved)];
+		}
+		let mirror = (resolved != cid).then_some(IpfsSource::Url(resolved));
+		mirror
+			.into_iter()
+			.chain(self.kubo_api().map(IpfsSource::Kubo))
+			.chain(self.gateways.ordered().into_iter().map(IpfsSource::Gateway))
+			.collect()
+	}
+
+	/// Handle successful request to the source.
+	fn source_succeeded(&self, source: &IpfsSource, latency: Duration) {
+		if let IpfsSource::Gateway(gateway) = source {
+			self.gateways.report_success(gateway, latency);
+		}
+	}
+
+	/// Handle failed request to the source.
+	///
+	/// Returns the error back if the next source should be tried. Errors which
+	/// are not caused by the source, like local IO errors, are returned as
+	/// `Err`, so they are not retried.
+	///
+	/// Failures of the local IPFS node are only logged, it's tried again by
+	/// the next download.
+	fn source_failed(
+		&self,
+		source: &IpfsSource,
+		error: NetworkError,
+	) -> Result<NetworkError, NetworkError> {
+		let status = match &error {
+			NetworkError::NetworkError(e) => e.status(),
+			// Source sent a wrong file or stalled
+			NetworkError::SizeMismatch(_, _)
+			| NetworkError::CidMismatch(_)
+			| NetworkError::ReadTimeout(_) => None,
+			_ => return Err(error),
+		};
+		let gateway = match source {
+			IpfsSource::Gateway(gateway) => gateway,
+			IpfsSource::Kubo(api) => {
+				warn!("Local IPFS node {} failed: {}", api.address(), error);
+				return Ok(error);
+			}
+			IpfsSource::Url(_) => return Ok(error),
+		};
+		warn!("IPFS gateway {} failed: {}", gateway, error);
+		// Client errors mean that the gateway works, but can't serve this file
+		let is_healthy = matches!(status, Some(status)
+			if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS);
+		if !is_healthy {
+			self.gateways.report_failure(gateway);
+		}
+		Ok(error)
+	}
+}
+
+/// Media type of CAR archives requested from IPFS gateways.
+///
+/// Blocks are requested in depth-first order with duplicates, so every file
+/// node is followed by all its chunks.
+const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car; version=1; order=dfs; dups=y";
+
+/// Remove the `.part` file of the given path, if it exists.
+async fn remove_part(path: &Path) -> Result<(), NetworkError> {
+	match fs::remove_file(part_path(path)).await {
+		Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+		_ => Ok(()),
+	}
+}
+
+/// Get the hash of the content addressed by the IPFS path, if it can be
+/// verified locally.
+fn cid_content_hash(cid: &str) -> Option<ContentHash> {
+	let hash = cid.parse::<Cid>().ok()?.content_hash();
+	if hash.is_none() {
+		debug!("Content of {} can't be verified locally", cid);
+	}
+	hash
+}
+
+/// Check the downloaded `.part` file against the hash of its CID, removing
+/// the file if it doesn't match.
+///
+/// `hash` is the already calculated hash of the file, it's reused if the
+/// algorithms are the same.
+async fn verify_part(
+	cid: &str,
+	path: &Path,
+	expected: &ContentHash,
+	hash: &ContentHash,
+) -> Result<(), NetworkError> {
+	let part = part_path(path);
+	let actual = if expected.algorithm == hash.algorithm {
+		hash.clone()
+	} else {
+		let mut hasher = expected.algorithm.hasher();
+		hash_file(&part, &mut hasher).await?;
+		ContentHash::new(expected.algorithm, &hasher.finalize())
+	};
+	if actual != *expected {
+		fs::remove_file(&part).await?;
+		return Err(NetworkError::CidMismatch(cid.to_string()));
+	}
+	Ok(())
+}
+
+/// Source of IPFS content.
+enum IpfsSource {
+	/// Local IPFS node.
+	Kubo(KuboApi),
+	/// IPFS gateway URL, like `https://ipfs.io/ipfs/`.
+	Gateway(String),
+	/// Content is downloaded from the URL, e.g. a mirror set by a rewrite rule.
+	Url(String),
+}
+
+impl IpfsSource {
+	/// Get URL of the file with the given CID (or path).
+	fn url(&self, cid: &str) -> String {
+		match self {
+			Self::Kubo(api) => api.offline_endpoint("cat", cid),
+			Self::Gateway(gateway) => format!("{gateway}{cid}"),
+			Self::Url(url) => url.clone(),
+		}
+	}
+
+	/// Get URL of the CAR archive of the DAG with the given root CID.
+	fn car_url(&self, cid: &str) -> String {
+		match self {
+			Self::Kubo(api) => api.offline_endpoint("dag/export", cid),
+			Self::Gateway(gateway) => format!("{gateway}{cid}?format=car"),
+			Self::Url(url) => format!("{url}?format=car"),
+		}
+	}
+
+	/// Build the request for `url`.
+	///
+	/// RPC API accepts only `POST` requests, and is accessed without proxies.
+	fn request(&self, client: &Client, url: &str) -> RequestBuilder {
+		match self {
+			Self::Kubo(api) => api.client().post(url),
+			Self::Gateway(_) | Self::Url(_) => client.get(url),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::test_server::{Route, TestServer};
+
+	#[test]
+	fn test_gateway_pool() {
+		let pool = GatewayPool::new(&["a", "b", "c"]);
+		assert_eq!(pool.ordered(), vec!["a", "b", "c"]);
+
+		pool.report_failure("a");
+		assert_eq!(pool.best(), "b");
+		assert_eq!(pool.ordered(), vec!["b", "c", "a"]);
+
+		// Slow gateway is ranked below the ones with unknown latency
+		pool.report_success("b", Duration::from_secs(3));
+		assert_eq!(pool.ordered(), vec!["c", "b", "a"]);
+
+		pool.report_success("a", Duration::from_millis(100));
+		assert_eq!(pool.best(), "a");
+
+		// When every gateway is cooling down, the one recovering first is used
+		pool.report_failure("a");
+		pool.report_failure("a");
+		pool.report_failure("b");
+		pool.report_failure("c");
+		assert_eq!(pool.ordered()[2], "a");
+	}
+
+	#[test]
+	fn test_gateway_pool_limits() {
+		let pool = GatewayPool::new::<&str>(&[]);
+		assert_eq!(pool.ordered(), DEFAULT_GATEWAYS);
+
+		// Huge cooldowns must not overflow
+		let pool = GatewayPool::new(&["a", "b"]);
+		pool.set_cooldown(Duration::MAX);
+		pool.report_failure("a");
+		pool.report_failure("a");
+		assert!(pool.statuses()[0].is_cooling_down(Instant::now()));
+		assert_eq!(pool.best(), "b");
+	}
+
+	#[tokio::test]
+	async fn test_ipfs_read_timeout_failover() {
+		// Gateway which accepts connections, but never responds
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let stalled = format!("http://{}/ipfs/", listener.local_addr().unwrap());
+		tokio::spawn(async move {
+			let mut streams = Vec::new();
+			while let Ok((stream, _)) = listener.accept().await {
+				streams.push(stream);
+			}
+		});
+		let server = TestServer::start().await;
+		server.route("/ipfs/file", Route::ok(b"file"));
+		let mut client = NetClient::new();
+		client.set_read_timeout(Some(Duration::from_millis(50)));
+		client.set_ipfs_gateways(&[&stalled, &server.url("/ipfs/")]);
+
+		let data = client
+			.download_ipfs_bytes("file", None, None)
+			.await
+			.unwrap();
+		assert_eq!(data, b"file");
+		let statuses = client.gateways().statuses();
+		assert_eq!(statuses[0].failures, 1);
+		assert_eq!(statuses[1].successes, 1);
+	}
+
+	#[tokio::test]
+	async fn test_ipfs_failover() {
+		let server = TestServer::start().await;
+		server.route("/down/file", Route::status(502));
+		server.route("/missing/file", Route::status(404));
+		server.route("/up/file", Route::ok(b"file"));
+		let mut client = NetClient::new();
+		let down = server.url("/down/");
+		let missing = server.url("/missing/");
+		let up = server.url("/up/");
+		client.set_ipfs_gateways(&[&down, &missing, &up]);
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		let hash = client
+			.download_ipfs_part_with("file", &path, HashAlgorithm::Sha1, None, None)
+			.await
+			.unwrap();
+		assert_eq!(hash, crate::utils::crypto::sha1_digest(b"file"));
+		assert_eq!(client.ipfs("file"), format!("{up}file"));
+		let statuses = client.gateways().statuses();
+		// Gateway which can't find the file is not considered unhealthy
+		assert_eq!(statuses[0].failures, 1);
+		assert_eq!(statuses[1].failures, 0);
+		assert_eq!(statuses[2].successes, 1);
+
+		// Working gateway is preferred now, failed one is not requested again
+		assert_eq!(
+			client
+				.download_ipfs_bytes("file", None, None)
+				.await
+				.unwrap(),
+			b"file"
+		);
+		let down_requests = server
+			.requests()
+			.iter()
+			.filter(|request| request.path == "/down/file")
+			.count();
+		assert_eq!(down_requests, 1);
+		assert_eq!(client.gateways().ordered()[2], down);
+	}
+
+	#[tokio::test]
+	async fn test_ipfs_failover_discards_part() {
+		let server = TestServer::start().await;
+		server.route("/down/file", Route::status(502));
+		server.route(
+			"/up/file",
+			Route {
+				ranges: true,
+				..Route::ok(b"file")
+			},
+		);
+		let mut client = NetClient::new();
+		client.set_ipfs_gateways(&[&server.url("/down/"), &server.url("/up/")]);
+		let dir = TempDir::new();
+		let path = dir.join("file");
+		// Part file could come from any source, it's not resumed after failover
+		std::fs::write(part_path(&path), b"ba").unwrap();
+
+		let hash = client
+			.download_ipfs_part_with("file", &path, HashAlgorithm::Sha1, None, None)
+			.await
+			.unwrap();
+		assert_eq!(hash, crate::utils::crypto::sha1_digest(b"file"));
+		assert_eq!(std::fs::read(part_path(&path)).unwrap(), b"file");
+		let requests = server.requests();
+		assert_eq!(requests[0].headers["range"], "bytes=2-");
+		assert!(!requests[1].headers.contains_key("range"));
+	}
+
+	#[tokio::test]
+	async fn test_size_mismatch() {
+		let server = TestServer::start().await;
+		server.route("/big/file", Route::ok(b"file and more"));
+		server.route("/up/file", Route::ok(b"file"));
+		let mut client = NetClient::new();
+		let big = server.url("/big/");
+		client.set_ipfs_gateways(&[&big, &server.url("/up/")]);
+		let dir = TempDir::new();
+		let path = dir.join("file");
+		// Stale part file bigger than the file is not resumed
+		std::fs::write(part_path(&path), b"stale data").unwrap();
+
+		let hash = client
+			.download_ipfs_part_with("file", &path, HashAlgorithm::Sha1, Some(4), None)
+			.await
+			.unwrap();
+		assert_eq!(hash, crate::utils::crypto::sha1_digest(b"file"));
+		assert_eq!(std::fs::read(part_path(&path)).unwrap(), b"file");
+		// Gateway which sent a wrong file is considered unhealthy
+		assert_eq!(client.gateways().statuses()[0].failures, 1);
+		assert!(!server.requests()[0].headers.contains_key("range"));
+
+		let result = client.download_ipfs_bytes("file", Some(5), None).await;
+		assert!(matches!(result, Err(NetworkError::SizeMismatch(5, _))));
+	}
+
+	#[tokio::test]
+	async fn test_cid_verification() {
+		// Raw CID of "file"
+		let cid = "bafkreib3tq2y6nxqumnwvu7bj4yjy7hrtcwjerxigfxzzzkd2wyzvqblqa";
+		let server = TestServer::start().await;
+		server.route(&format!("/evil/{cid}"), Route::ok(b"evil"));
+		server.route(&format!("/up/{cid}"), Route::ok(b"file"));
+		let mut client = NetClient::new();
+		let evil = server.url("/evil/");
+		client.set_ipfs_gateways(&[&evil, &server.url("/up/")]);
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		let hash = client
+			.download_ipfs_part_with(cid, &path, HashAlgorithm::Sha1, Some(4), None)
+			.await
+			.unwrap();
+		assert_eq!(hash, crate::utils::crypto::sha1_digest(b"file"));
+		assert_eq!(std::fs::read(part_path(&path)).unwrap(), b"file");
+		// Gateway which sent a wrong file is considered unhealthy
+		assert_eq!(client.gateways().statuses()[0].failures, 1);
+
+		client.set_ipfs_gateways(&[&evil]);
+		let result = client.download_ipfs_bytes(cid, None, None).await;
+		assert!(matches!(result, Err(NetworkError::CidMismatch(_))));
+		std::fs::remove_file(part_path(&path)).unwrap();
+		let result = client
+			.download_ipfs_part_with(cid, &path, HashAlgorithm::Sha1, None, None)
+			.await;
+		assert!(matches!(result, Err(NetworkError::CidMismatch(_))));
+		// Wrong file is not kept for resuming
+		assert!(!part_path(&path).exists());
+	}
+}
@@ -0,0 +1,400 @@
+//! Local IPFS node support.
+//!
+//! Users running [Kubo](https://github.com/ipfs/kubo) can let the launcher
+//! download content from their node instead of public gateways, with
+//! [`NetClient::set_kubo_api`] or [`NetClient::detect_kubo`]. Installed
+//! content is pinned on the node in the background with
+//! [`NetClient::pin_ipfs_in_background`], so it's kept and provided to other
+//! peers.
+//!
+//! Content is read from the node in offline mode, so the node answers only
+//! with blocks it already has, and the launcher falls back to gateways
+//! instead of waiting for the node to find the content in the network.
+//!
+//! The node is accessed with its [HTTP RPC API](https://docs.ipfs.tech/reference/kubo/rpc/).
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use reqwest::{Client, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+use super::net::{NetClient, NetworkError};
+
+/// Default address of the RPC API.
+pub const DEFAULT_KUBO_API: &str = "http://127.0.0.1:5001";
+
+/// Timeout of detecting the local node.
+const DETECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Timeout of pinning one CID, including fetching missing blocks.
+const PIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Client of the Kubo RPC API.
+#[derive(Debug, Clone)]
+pub struct KuboApi {
+	address: String,
+	client: Client,
+}
+
+/// Information about a block stored on the node.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BlockStat {
+	/// CID of the block.
+	#[serde(rename = "Key")]
+	pub key: String,
+	/// Size of the block in bytes.
+	#[serde(rename = "Size")]
+	pub size: u64,
+}
+
+/// Response of the `version` command.
+#[derive(Debug, Deserialize)]
+struct Version {
+	#[serde(rename = "Version")]
+	version: String,
+}
+
+impl KuboApi {
+	/// Creates a client of the RPC API at the given address, like
+	/// `http://127.0.0.1:5001`.
+	///
+	/// The API is accessed without proxies.
+	pub fn new(address: &str) -> Self {
+		Self {
+			address: address.trim_end_matches('/').to_string(),
+			client: Client::builder().no_proxy().build().unwrap_or_default(),
+		}
+	}
+
+	/// Creates a client of the RPC API of the node running on this machine.
+	///
+	/// The address is read from the `api` file of the node repository at
+	/// `$IPFS_PATH` or `~/.ipfs`, which is written by the running node.
+	/// [`DEFAULT_KUBO_API`] is used if there is no such file.
+	pub fn local() -> Self {
+		Self::new(&local_api_address().unwrap_or_else(|| DEFAULT_KUBO_API.to_string()))
+	}
+
+	/// Returns the address of the RPC API.
+	#[inline]
+	pub fn address(&self) -> &str {
+		&self.address
+	}
+
+	/// Returns the client used to access the RPC API.
+	#[inline]
+	pub(super) fn client(&self) -> &Client {
+		&self.client
+	}
+
+	/// Get URL of the RPC command with the given query parameters.
+	///
+	/// Parameters are percent-encoded.
+	pub(super) fn endpoint(&self, command: &str, params: &[(&str, &str)]) -> String {
+		let url = format!("{}/api/v0/{command}", self.address);
+		match Url::parse(&url) {
+			Ok(mut url) if !params.is_empty() => {
+				url.query_pairs_mut().extend_pairs(params);
+				url.into()
+			}
+			// Invalid address fails when the request is sent
+			_ => url,
+		}
+	}
+
+	/// Get URL of the RPC command reading the given CID (or path) only from
+	/// blocks stored on the node.
+	pub(super) fn offline_endpoint(&self, command: &str, cid: &str) -> String {
+		self.endpoint(command, &[("arg", cid), ("offline", "true")])
+	}
+
+	/// Get version of the node.
+	pub async fn version(&self) -> Result<String, NetworkError> {
+		Ok(self
+			.call::<Version>(&self.endpoint("version", &[]))
+			.await?
+			.version)
+	}
+
+	/// Pin the CID (or path) recursively, so it's kept on the node.
+	///
+	/// Content which is not on the node yet is fetched from the network.
+	pub async fn pin_add(&self, cid: &str) -> Result<(), NetworkError> {
+		self.call::<serde_json::Value>(&self.endpoint("pin/add", &[("arg", cid)]))
+			.await?;
+		Ok(())
+	}
+
+	/// Get information about the block with the given CID.
+	///
+	/// The block is not fetched from the network, so an error is returned if
+	/// it's not stored on the node.
+	pub async fn block_stat(&self, cid: &str) -> Result<BlockStat, NetworkError> {
+		self.call(&self.offline_endpoint("block/stat", cid)).await
+	}
+
+	/// Send the RPC request and parse the JSON response.
+	async fn call<T: DeserializeOwned>(&self, url: &str) -> Result<T, NetworkError> {
+		debug!("Calling IPFS RPC API: {}", url);
+		let response = self.client.post(url).send().await?.error_for_status()?;
+		Ok(response.json().await?)
+	}
+}
+
+impl Default for KuboApi {
+	fn default() -> Self {
+		Self::new(DEFAULT_KUBO_API)
+	}
+}
+
+impl NetClient {
+	/// Sets the RPC API of the local IPFS node.
+	///
+	/// If set, IPFS downloads are tried from the node before the gateways, and
+	/// [`NetClient::pin_ipfs`] pins content on it. See
+	/// [`NetClient::detect_kubo`] to find a running node automatically.
+	///
+	/// The node is shared by this client and its clones, so it can be set
+	/// after the client is handed out.
+	pub fn set_kubo_api(&self, api: Option<KuboApi>) {
+		*self.kubo.write().unwrap() = api;
+	}
+
+	/// Returns the RPC API of the local IPFS node, if any.
+	#[inline]
+	pub fn kubo_api(&self) -> Option<KuboApi> {
+		self.kubo.read().unwrap().clone()
+	}
+
+	/// Use the local IPFS node if it's running.
+	///
+	/// Returns `true` if the node responded, and sets it with
+	/// [`NetClient::set_kubo_api`]. See [`KuboApi::local`] for how the node is
+	/// found.
+	///
+	/// The node is set for clones of this client too, so the detection can
+	/// run in the background while the client is already used.
+	pub async fn detect_kubo(&self) -> bool {
+		let api = KuboApi::local();
+		match tokio::time::timeout(DETECT_TIMEOUT, api.version()).await {
+			Ok(Ok(version)) => {
+				info!("Using local IPFS node {} ({})", api.address(), version);
+				self.set_kubo_api(Some(api));
+				true
+			}
+			_ => {
+				debug!("Local IPFS node is not running at {}", api.address());
+				false
+			}
+		}
+	}
+
+	/// Pin the CID (or path) on the local IPFS node.
+	///
+	/// Returns `false` if there is no local node, or `cid` is a URL.
+	///
+	/// Pinning fetches missing blocks from the network, so it fails with
+	/// [`NetworkError::ReadTimeout`] if it takes longer than 10 minutes.
+	pub async fn pin_ipfs(&self, cid: &str) -> Result<bool, NetworkError> {
+		let api = match self.kubo_api() {
+			Some(api) if !cid.contains("://") => api,
+			_ => return Ok(false),
+		};
+		if self.is_offline() {
+			return Err(NetworkError::Offline);
+		}
+		debug!("Pinning {} on local IPFS node", cid);
+		match tokio::time::timeout(PIN_TIMEOUT, api.pin_add(cid)).await {
+			Ok(result) => result.map(|_| true),
+			Err(_) => Err(NetworkError::ReadTimeout(PIN_TIMEOUT)),
+		}
+	}
+
+	/// Pin the CIDs (or paths) on the local IPFS node in a background task.
+	///
+	/// Pinning fetches missing blocks from the network, which may take long,
+	/// so every CID is given a timeout and failures are only logged.
+	///
+	/// Returns the task handle, or `None` if there is no local node, the
+	/// client is offline, or there is no Tokio runtime to spawn the task on.
+	/// URLs are skipped.
+	pub fn pin_ipfs_in_background(&self, cids: Vec<String>) -> Option<JoinHandle<()>> {
+		let api = self.kubo_api()?;
+		let cids: Vec<String> = cids
+			.into_iter()
+			.filter(|cid| !cid.contains("://"))
+			.collect();
+		if cids.is_empty() || self.is_offline() {
+			return None;
+		}
+		let runtime = Handle::try_current().ok()?;
+		Some(runtime.spawn(async move {
+			for cid in cids {
+				debug!("Pinning {} on local IPFS node", cid);
+				match tokio::time::timeout(PIN_TIMEOUT, api.pin_add(&cid)).await {
+					Ok(Ok(())) => {}
+					Ok(Err(e)) => warn!("Failed to pin {} on local IPFS node: {}", cid, e),
+					Err(_) => warn!("Timed out pinning {} on local IPFS node", cid),
+				}
+			}
+		}))
+	}
+}
+
+/// Read the RPC API address from the node repository.
+fn local_api_address() -> Option<String> {
+	let repo = std::env::var_os("IPFS_PATH")
+		.map(PathBuf::from)
+		.or_else(|| dirs::home_dir().map(|home| home.join(".ipfs")))?;
+	let multiaddr = std::fs::read_to_string(repo.join("api")).ok()?;
+	multiaddr_to_url(multiaddr.trim())
+}
+
+/// Convert TCP multiaddr, like `/ip4/127.0.0.1/tcp/5001`, to HTTP URL.
+///
+/// Unspecified addresses are replaced with loopback ones.
+fn multiaddr_to_url(multiaddr: &str) -> Option<String> {
+	let parts: Vec<&str> = multiaddr.trim_start_matches('/').split('/').collect();
+	match parts.as_slice() {
+		["ip4", "0.0.0.0", "tcp", port, ..] => Some(format!("http://127.0.0.1:{port}")),
+		["ip6", "::", "tcp", port, ..] => Some(format!("http://[::1]:{port}")),
+		["ip4" | "dns" | "dns4" | "dns6", host, "tcp", port, ..] => {
+			Some(format!("http://{host}:{port}"))
+		}
+		["ip6", host, "tcp", port, ..] => Some(format!("http://[{host}]:{port}")),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::crypto::{sha1_digest, HashAlgorithm};
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::test_server::{Route, TestServer};
+
+	#[test]
+	fn test_multiaddr_to_url() {
+		assert_eq!(
+			multiaddr_to_url("/ip4/127.0.0.1/tcp/5001").as_deref(),
+			Some("http://127.0.0.1:5001")
+		);
+		assert_eq!(
+			multiaddr_to_url("/ip4/0.0.0.0/tcp/5002").as_deref(),
+			Some("http://127.0.0.1:5002")
+		);
+		assert_eq!(
+			multiaddr_to_url("/ip6/::1/tcp/5001").as_deref(),
+			Some("http://[::1]:5001")
+		);
+		assert_eq!(
+			multiaddr_to_url("/dns4/ipfs.local/tcp/5001/http").as_deref(),
+			Some("http://ipfs.local:5001")
+		);
+		assert_eq!(multiaddr_to_url("/unix/var/run/ipfs.sock"), None);
+	}
+
+	#[tokio::test]
+	async fn test_kubo_api() {
+		let node = TestServer::start().await;
+		node.route("/api/v0/version", Route::ok(br#"{"Version":"0.18.1"}"#));
+		node.route(
+			"/api/v0/pin/add?arg=file",
+			Route::ok(br#"{"Pins":["file"]}"#),
+		);
+		node.route(
+			"/api/v0/block/stat?arg=file&offline=true",
+			Route::ok(br#"{"Key":"file","Size":5}"#),
+		);
+		let api = KuboApi::new(&node.url("/"));
+
+		assert_eq!(api.version().await.unwrap(), "0.18.1");
+		api.pin_add("file").await.unwrap();
+		let stat = api.block_stat("file").await.unwrap();
+		assert_eq!(stat.size, 5);
+		assert!(api.block_stat("missing").await.is_err());
+		// RPC API accepts only POST requests
+		assert!(node
+			.requests()
+			.iter()
+			.all(|request| request.method == "POST"));
+	}
+
+	#[tokio::test]
+	async fn test_kubo_download() {
+		let node = TestServer::start().await;
+		node.route("/api/v0/cat?arg=file&offline=true", Route::ok(b"hello"));
+		node.route(
+			"/api/v0/pin/add?arg=file",
+			Route::ok(br#"{"Pins":["file"]}"#),
+		);
+		let gateway = TestServer::start().await;
+		gateway.route("/ipfs/file", Route::ok(b"hello"));
+		gateway.route("/ipfs/other", Route::ok(b"other"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&gateway.url("/ipfs/"));
+		assert!(!client.pin_ipfs("file").await.unwrap());
+		client.set_kubo_api(Some(KuboApi::new(&node.url(""))));
+
+		let dir = TempDir::new();
+		let path = dir.join("file");
+		let hash = client
+			.download_ipfs_part_with("file", &path, HashAlgorithm::Sha1, Some(5), None)
+			.await
+			.unwrap();
+		assert_eq!(hash, sha1_digest(b"hello"));
+		assert!(gateway.requests().is_empty());
+		assert!(client.pin_ipfs("file").await.unwrap());
+		let requests = node.requests();
+		assert_eq!(requests.len(), 2);
+		assert!(requests.iter().all(|request| request.method == "POST"));
+
+		// Content missing on the node is downloaded from the gateway
+		let data = client
+			.download_ipfs_bytes("other", None, None)
+			.await
+			.unwrap();
+		assert_eq!(data, b"other");
+		assert_eq!(gateway.requests().len(), 1);
+		// Failures of the node don't affect gateways
+		assert_eq!(client.gateways().statuses()[0].failures, 0);
+	}
+
+	#[test]
+	fn test_endpoint() {
+		let api = KuboApi::new("http://127.0.0.1:5001/");
+		assert_eq!(
+			api.endpoint("version", &[]),
+			"http://127.0.0.1:5001/api/v0/version"
+		);
+		assert_eq!(
+			api.offline_endpoint("cat", "CID/a b&c=d"),
+			"http://127.0.0.1:5001/api/v0/cat?arg=CID%2Fa+b%26c%3Dd&offline=true"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_pin_in_background() {
+		let node = TestServer::start().await;
+		node.route("/api/v0/pin/add?arg=a", Route::ok(br#"{"Pins":["a"]}"#));
+		let client = NetClient::new();
+		let cids = vec!["a".to_string(), "b".to_string(), "https://a".to_string()];
+		assert!(client.pin_ipfs_in_background(cids.clone()).is_none());
+		// Node set later is used by clones made before
+		let clone = client.clone();
+		client.set_kubo_api(Some(KuboApi::new(&node.url(""))));
+
+		// Failure of one CID doesn't stop pinning of others
+		clone.pin_ipfs_in_background(cids).unwrap().await.unwrap();
+		let paths: Vec<String> = node
+			.requests()
+			.into_iter()
+			.map(|request| request.path)
+			.collect();
+		assert_eq!(paths, ["/api/v0/pin/add?arg=a", "/api/v0/pin/add?arg=b"]);
+	}
+}
@@ -0,0 +1,263 @@
+//! HTTP cache for mutable metadata.
+//!
+//! Objects are addressed by their hashes and never change, but version lists
+//! and package indexes do. [`MetadataCache`] keeps their last downloaded
+//! copies with `ETag` and `Last-Modified` validators, so
+//! [`NetClient::fetch_metadata`] can send conditional requests and reuse the
+//! copy when the server answers `304 Not Modified`.
+//!
+//! When the network is unavailable, cached copies are served until they are
+//! older than the staleness window.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use super::crypto::sha256_digest;
+use super::fs::write_file_atomic;
+use super::net::{fetch, NetClient, NetworkError};
+use super::retry::Retryable;
+
+/// Default staleness window of cached metadata.
+pub const DEFAULT_MAX_STALE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Validators of a cached response.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CacheEntry {
+	/// URL of the response, to detect hash collisions.
+	url: String,
+	/// `ETag` header of the response.
+	etag: Option<String>,
+	/// `Last-Modified` header of the response.
+	last_modified: Option<String>,
+	/// Time of the last successful validation, in seconds since UNIX epoch.
+	fetched_at: u64,
+}
+
+impl CacheEntry {
+	fn new(url: &str, headers: &HeaderMap) -> Self {
+		let header = |name| {
+			headers
+				.get(name)
+				.and_then(|value| value.to_str().ok())
+				.map(String::from)
+		};
+		Self {
+			url: url.to_string(),
+			etag: header(ETAG),
+			last_modified: header(LAST_MODIFIED),
+			fetched_at: unix_time(),
+		}
+	}
+}
+
+/// On-disk cache of mutable metadata.
+#[derive(Debug)]
+pub struct MetadataCache {
+	dir: PathBuf,
+	max_stale: Mutex<Duration>,
+}
+
+impl MetadataCache {
+	/// Creates a new cache in the given directory.
+	///
+	/// The directory is created when the first response is cached.
+	pub fn new(dir: &Path) -> Self {
+		Self {
+			dir: dir.to_path_buf(),
+			max_stale: Mutex::new(DEFAULT_MAX_STALE),
+		}
+	}
+
+	/// Get cache directory.
+	#[inline]
+	pub fn dir(&self) -> &Path {
+		&self.dir
+	}
+
+	/// Sets how long a cached copy may be served without validating it with
+	/// the server, when the network is unavailable.
+	pub fn set_max_stale(&self, max_stale: Duration) {
+		*self.max_stale.lock().unwrap() = max_stale;
+	}
+
+	/// Get the staleness window of cached copies.
+	pub fn max_stale(&self) -> Duration {
+		*self.max_stale.lock().unwrap()
+	}
+
+	/// Get paths of the entry and the body of the given URL.
+	fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+		let key = sha256_digest(url.as_bytes());
+		(
+			self.dir.join(format!("{key}.json")),
+			self.dir.join(format!("{key}.body")),
+		)
+	}
+
+	/// Load the cached response of the given URL.
+	async fn load(&self, url: &str) -> Option<(CacheEntry, Vec<u8>)> {
+		let (entry_path, body_path) = self.paths(url);
+		let entry: CacheEntry =
+			serde_json::from_slice(&tokio::fs::read(entry_path).await.ok()?).ok()?;
+		if entry.url != url {
+			return None;
+		}
+		let body = tokio::fs::read(body_path).await.ok()?;
+		Some((entry, body))
+	}
+
+	/// Save the response of the given URL.
+	///
+	/// If `body` is `None`, only the entry is updated.
+	async fn store(&self, entry: &CacheEntry, body: Option<&[u8]>) -> std::io::Result<()> {
+		let (entry_path, body_path) = self.paths(&entry.url);
+		tokio::fs::create_dir_all(&self.dir).await?;
+		if let Some(body) = body {
+			write_file_atomic(&body_path, body).await?;
+		}
+		write_file_atomic(&entry_path, serde_json::to_string(entry)?.as_bytes()).await
+	}
+
+	/// Check if the cached copy may be served without validation.
+	fn is_usable(&self, entry: &CacheEntry) -> bool {
+		unix_time().saturating_sub(entry.fetched_at) <= self.max_stale().as_secs()
+	}
+}
+
+impl NetClient {
+	/// Sets the cache used by [`NetClient::fetch_metadata`].
+	pub fn set_metadata_cache(&mut self, cache: Option<Arc<MetadataCache>>) {
+		self.metadata_cache = cache;
+	}
+
+	/// Returns the cache used by [`NetClient::fetch_metadata`], if any.
+	#[inline]
+	pub fn metadata_cache(&self) -> Option<&MetadataCache> {
+		self.metadata_cache.as_deref()
+	}
+
+	/// Downloads mutable metadata, like a version list, into memory.
+	///
+	/// If the metadata cache is set (see [`NetClient::set_metadata_cache`]),
+	/// the request is conditional and the cached copy is returned if the
+	/// server answers `304 Not Modified`. The cached copy is also returned in
+	/// offline mode, or if the request failed because of a network error, as
+	/// long as it's within the staleness window.
+	///
+	/// Without the cache, it's the same as [`NetClient::download_bytes`].
+	pub async fn fetch_metadata(&self, url: &str) -> Result<Vec<u8>, NetworkError> {
+		let cache = match self.metadata_cache() {
+			Some(cache) => cache,
+			None => return self.download_bytes(url).await,
+		};
+		let cached = cache.load(url).await;
+		if self.is_offline() {
+			return match cached {
+				Some((entry, body)) if cache.is_usable(&entry) => {
+					debug!("Using cached metadata in offline mode: {}", url);
+					Ok(body)
+				}
+				_ => Err(NetworkError::Offline),
+			};
+		}
+
+		let result = self
+			.retry_policy()
+			.retry(|| {
+				let mut request = self.client().get(self.resolve(url, None));
+				if let Some((entry, _)) = &cached {
+					if let Some(etag) = &entry.etag {
+						request = request.header(IF_NONE_MATCH, etag);
+					}
+					if let Some(last_modified) = &entry.last_modified {
+						request = request.header(IF_MODIFIED_SINCE, last_modified);
+					}
+				}
+				fetch(request, url, self.transfer(url))
+			})
+			.await;
+
+		match (result, cached) {
+			(Ok(response), Some((entry, body))) if response.status == StatusCode::NOT_MODIFIED => {
+				debug!("Metadata is not modified: {}", url);
+				let entry = CacheEntry {
+					fetched_at: unix_time(),
+					..entry
+				};
+				if let Err(e) = cache.store(&entry, None).await {
+					warn!("Failed to update cached metadata of {}: {}", url, e);
+				}
+				Ok(body)
+			}
+			(Ok(response), _) => {
+				let entry = CacheEntry::new(url, &response.headers);
+				if let Err(e) = cache.store(&entry, Some(&response.data)).await {
+					warn!("Failed to cache metadata of {}: {}", url, e);
+				}
+				Ok(response.data)
+			}
+			(Err(e), Some((entry, body))) if e.is_retryable() && cache.is_usable(&entry) => {
+				warn!("Failed to fetch {}, using cached metadata: {}", url, e);
+				Ok(body)
+			}
+			(Err(e), _) => Err(e),
+		}
+	}
+}
+
+/// Get current time in seconds since UNIX epoch.
+fn unix_time() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|time| time.as_secs())
+		.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::test_server::{Route, TestServer};
+	use std::sync::Arc;
+
+	#[tokio::test]
+	async fn test_metadata_cache() {
+		let server = TestServer::start().await;
+		server.route(
+			"/versions.json",
+			Route {
+				headers: vec![("ETag".to_string(), "\"v1\"".to_string())],
+				..Route::ok(b"[\"1.19\"]")
+			},
+		);
+		let dir = TempDir::new();
+		let cache = Arc::new(MetadataCache::new(dir.path()));
+		let mut client = NetClient::new();
+		client.set_metadata_cache(Some(cache.clone()));
+		let url = server.url("/versions.json");
+
+		assert_eq!(client.fetch_metadata(&url).await.unwrap(), b"[\"1.19\"]");
+		// Second request is conditional and the body is taken from the cache
+		assert_eq!(client.fetch_metadata(&url).await.unwrap(), b"[\"1.19\"]");
+		let requests = server.requests();
+		assert_eq!(requests.len(), 2);
+		assert!(!requests[0].headers.contains_key("if-none-match"));
+		assert_eq!(requests[1].headers["if-none-match"], "\"v1\"");
+
+		client.set_offline(true);
+		assert_eq!(client.fetch_metadata(&url).await.unwrap(), b"[\"1.19\"]");
+		cache.set_max_stale(Duration::ZERO);
+		// Entry is at most a second old, so it's stale only after a second
+		tokio::time::sleep(Duration::from_millis(1100)).await;
+		assert!(matches!(
+			client.fetch_metadata(&url).await,
+			Err(NetworkError::Offline)
+		));
+		assert_eq!(server.requests().len(), 2);
+	}
+}
@@ -3,9 +3,21 @@
 //! This module contains various utilities for the library, such as
 //! logging setup, hash calculation, etc.
 
+pub mod car;
+pub mod cid;
 pub mod crypto;
+pub mod fs;
+pub mod gateway;
+pub mod kubo;
 pub mod log;
+pub mod metadata_cache;
 pub mod net;
-pub mod parallel;
+pub mod net_config;
+pub mod progress;
+pub mod rate_limit;
+pub mod retry;
+pub mod rewrite;
+#[cfg(test)]
+pub(crate) mod test_server;
 
 pub use self::log::init_logging;
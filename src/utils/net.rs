@@ -1,11 +1,27 @@
 //! Network utilities.
 
-use std::path::Path;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use reqwest::{Client, IntoUrl, RequestBuilder};
-use sha1::Digest;
+use reqwest::header::{HeaderMap, CONTENT_RANGE, RANGE};
+use reqwest::{Client, IntoUrl, RequestBuilder, Response, StatusCode};
 use thiserror::Error;
-use tokio::{fs, io::AsyncWriteExt};
+use tokio::{
+	fs,
+	io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use super::crypto::{HashAlgorithm, Hasher};
+use super::gateway::GatewayPool;
+use super::kubo::KuboApi;
+use super::metadata_cache::MetadataCache;
+use super::progress::{ProgressEvent, ProgressHandler, TransferState};
+use super::rate_limit::RateLimiter;
+use super::retry::{RetryPolicy, Retryable};
+use super::rewrite::RewriteRule;
 
 /// Network error.
 #[derive(Error, Debug)]
@@ -19,39 +35,135 @@ pub enum NetworkError {
 	/// Directory not exists error.
 	#[error("Directory not exists: {0}")]
 	DirectoryNotExists(String),
+	/// No response or data was received within the read timeout.
+	#[error("Read timed out after {0:?}")]
+	ReadTimeout(Duration),
+	/// Client is in offline mode. See [`NetClient::set_offline`].
+	#[error("Network is unavailable in offline mode")]
+	Offline,
+	/// Size of the downloaded file doesn't match the expected one.
+	///
+	/// If the download was aborted because it exceeded the expected size, the
+	/// actual size is the number of bytes received until then.
+	#[error("Size mismatch: {0} bytes (expected) != {1} bytes (actual)")]
+	SizeMismatch(u64, u64),
+	/// Content downloaded from IPFS doesn't match its CID.
+	#[error("Content doesn't match CID {0}")]
+	CidMismatch(String),
+}
+
+impl Retryable for NetworkError {
+	fn is_retryable(&self) -> bool {
+		match self {
+			Self::NetworkError(e) => match e.status() {
+				Some(status) => {
+					status.is_server_error()
+						|| status == StatusCode::REQUEST_TIMEOUT
+						|| status == StatusCode::TOO_MANY_REQUESTS
+				}
+				// Connection errors, timeouts and interrupted bodies
+				None => !e.is_builder() && !e.is_redirect(),
+			},
+			// Server sent a different or truncated file, another one may not
+			Self::ReadTimeout(_) | Self::SizeMismatch(_, _) | Self::CidMismatch(_) => true,
+			Self::IOError(_) | Self::DirectoryNotExists(_) | Self::Offline => false,
+		}
+	}
 }
 
 /// Network client.
 ///
 /// This is a wrapper around [`reqwest::Client`] and functions in this module.
+///
+/// Methods of other features are defined in their modules: IPFS downloads in
+/// [`gateway`](super::gateway), retries in [`retry`](super::retry), bandwidth
+/// limiting in [`rate_limit`](super::rate_limit), progress reporting in
+/// [`progress`](super::progress), metadata caching in
+/// [`metadata_cache`](super::metadata_cache), the local IPFS node in
+/// [`kubo`](super::kubo) and URL rewriting in [`rewrite`](super::rewrite).
 #[derive(Debug, Clone)]
 pub struct NetClient {
-	client: Client,
-	ipfs_gateway: String,
+	pub(super) client: Client,
+	pub(super) gateways: Arc<GatewayPool>,
+	pub(super) retry_policy: RetryPolicy,
+	pub(super) rate_limiter: Arc<RateLimiter>,
+	pub(super) progress: Option<Arc<dyn ProgressHandler>>,
+	read_timeout: Option<Duration>,
+	offline: Arc<AtomicBool>,
+	pub(super) metadata_cache: Option<Arc<MetadataCache>>,
+	pub(super) kubo: Arc<RwLock<Option<KuboApi>>>,
+	pub(super) rewrite_rules: Arc<Vec<RewriteRule>>,
 }
 
 impl NetClient {
 	/// Creates a new network client.
 	pub fn new() -> Self {
-		Self {
-			client: Client::new(),
-			ipfs_gateway: "https://ipfs.frsqr.xyz/ipfs/".to_string(),
-		}
+		Self::from_client(Client::new())
 	}
 
 	/// Creates a new network client from the given [`reqwest::Client`].
 	pub fn from_client(client: Client) -> Self {
 		Self {
 			client,
-			ipfs_gateway: "https://ipfs.frsqr.xyz/ipfs/".to_string(),
+			gateways: Arc::new(GatewayPool::default()),
+			retry_policy: RetryPolicy::default(),
+			rate_limiter: Arc::new(RateLimiter::unlimited()),
+			progress: None,
+			read_timeout: None,
+			offline: Arc::new(AtomicBool::new(false)),
+			metadata_cache: None,
+			kubo: Arc::new(RwLock::new(None)),
+			rewrite_rules: Arc::new(Vec::new()),
 		}
 	}
 
-	/// Sets the IPFS gateway URL.
+	/// Sets timeout of waiting for the response or the next chunk of its body.
+	///
+	/// See [`NetConfig`](super::net_config::NetConfig) for other network
+	/// settings.
+	pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+		self.read_timeout = timeout;
+	}
+
+	/// Enables or disables offline mode.
 	///
-	/// The default value is `https://ipfs.frsqr.xyz/ipfs/`.
-	pub fn set_ipfs_gateway(&mut self, url: &str) {
-		self.ipfs_gateway = url.to_string();
+	/// In offline mode every download of this client and its clones fails
+	/// immediately with [`NetworkError::Offline`], without touching the network.
+	/// Requests made with [`NetClient::get`] and similar methods are not
+	/// affected.
+	pub fn set_offline(&self, offline: bool) {
+		if self.offline.swap(offline, Ordering::SeqCst) != offline {
+			info!(
+				"Offline mode {}",
+				if offline { "enabled" } else { "disabled" }
+			);
+		}
+	}
+
+	/// Check if the client is in offline mode.
+	#[inline]
+	pub fn is_offline(&self) -> bool {
+		self.offline.load(Ordering::SeqCst)
+	}
+
+	/// Options of a download with the given id.
+	pub(super) fn transfer<'a>(&'a self, id: &'a str) -> Transfer<'a> {
+		Transfer {
+			limiter: Some(&self.rate_limiter),
+			progress: self.progress.as_deref().map(|handler| (handler, id)),
+			read_timeout: self.read_timeout,
+			offline: self.is_offline(),
+			expected_size: None,
+		}
+	}
+
+	/// Returns rate limiter and progress handler of this client for downloads
+	/// made with free functions of this module.
+	pub fn download_options(&self) -> DownloadOptions<'_> {
+		DownloadOptions {
+			limiter: Some(&self.rate_limiter),
+			progress: self.progress.as_deref(),
+		}
 	}
 
 	/// Returns a reference to the underlying [`reqwest::Client`].
@@ -62,34 +174,94 @@ impl NetClient {
 
 	/// Downloads a file from the given URL to the given path.
 	///
+	/// Failed download is retried according to the [`RetryPolicy`].
+	///
 	/// See [`download_to`] for details.
 	#[inline]
 	pub async fn download_to(&self, url: &str, path: &Path) -> Result<(), NetworkError> {
-		download_to(&self.client, url, path).await
+		self.retry_policy
+			.retry(|| async {
+				let source = self.resolve(url, None);
+				debug!("Downloading file from {} to {}", source, path.display());
+				download_resumable(
+					|| self.client.get(&source),
+					&source,
+					path,
+					None,
+					self.transfer(url),
+				)
+				.await?;
+				fs::rename(part_path(path), path).await?;
+				Ok(())
+			})
+			.await
 	}
 
 	/// Downloads a file from the given URL to the given path and returns its hash.
 	///
+	/// Failed download is retried according to the [`RetryPolicy`].
+	///
 	/// See [`download_and_hash`] for details.
 	#[inline]
 	pub async fn download_and_hash(&self, url: &str, path: &Path) -> Result<String, NetworkError> {
-		download_and_hash(&self.client, url, path).await
+		self.retry_policy
+			.retry(|| async {
+				debug!("Downloading file from {} to {}", url, path.display());
+				let hash = self.download_part(url, path).await?;
+				fs::rename(part_path(path), path).await?;
+				Ok(hash)
+			})
+			.await
 	}
 
-	/// Gets the IPFS gateway URL for the given CID (or path).
+	/// Downloads a file from the given URL to the `.part` file and returns its hash.
 	///
-	/// You can change the IPFS gateway URL by using [`NetClient::set_ipfs_gateway`].
+	/// See [`download_part`] for details.
+	#[inline]
+	pub async fn download_part(&self, url: &str, path: &Path) -> Result<String, NetworkError> {
+		self.download_part_with(url, path, HashAlgorithm::Sha1)
+			.await
+	}
+
+	/// Downloads a file from the given URL to the `.part` file and returns its
+	/// hash calculated with the given algorithm.
 	///
-	/// # Examples
+	/// See [`download_part_with`] for details.
+	#[inline]
+	pub async fn download_part_with(
+		&self,
+		url: &str,
+		path: &Path,
+		algorithm: HashAlgorithm,
+	) -> Result<String, NetworkError> {
+		let source = self.resolve(url, None);
+		let mut hasher = algorithm.hasher();
+		download_resumable(
+			|| self.client.get(&source),
+			&source,
+			path,
+			Some(&mut hasher),
+			self.transfer(url),
+		)
+		.await?;
+		Ok(hasher.finalize())
+	}
+
+	/// Downloads a file from the given URL into memory.
 	///
-	/// ```
-	/// use firelaunch::utils::net::NetClient;
+	/// Failed download is retried according to the [`RetryPolicy`].
 	///
-	/// let client = NetClient::new();
-	/// assert_eq!("https://ipfs.frsqr.xyz/ipfs/CID", client.ipfs("CID"));
-	/// ```
-	pub fn ipfs(&self, cid: &str) -> String {
-		format!("{}{cid}", self.ipfs_gateway)
+	/// See [`download_bytes`] for details.
+	#[inline]
+	pub async fn download_bytes(&self, url: &str) -> Result<Vec<u8>, NetworkError> {
+		self.retry_policy
+			.retry(|| async {
+				let source = self.resolve(url, None);
+				Ok(fetch_bytes(&self.client, &source, self.transfer(url))
+					.await?
+					.0)
+			})
+			.await
 	}
 
 	/// Proxy for [`reqwest::Client::get`].
@@ -135,23 +307,54 @@ impl Default for NetClient {
 	}
 }
 
+/// Options of downloads made with free functions of this module.
+///
+/// Default options don't limit the bandwidth and don't report progress.
+/// [`NetClient::download_options`] returns options sharing the limiter and
+/// the progress handler of the client.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DownloadOptions<'a> {
+	/// Bandwidth limiter.
+	pub limiter: Option<&'a RateLimiter>,
+	/// Handler of progress events, which are sent with the URL as an id.
+	pub progress: Option<&'a dyn ProgressHandler>,
+}
+
+impl<'a> DownloadOptions<'a> {
+	/// Options of the download from the given URL.
+	fn transfer(self, url: &'a str) -> Transfer<'a> {
+		Transfer {
+			limiter: self.limiter,
+			progress: self.progress.map(|handler| (handler, url)),
+			..Transfer::default()
+		}
+	}
+}
+
 /// Downloads a file from the given URL to the given path.
 ///
 /// Function downloads a file from the given URL to the given path.
 /// If the file already exists, it will be overwritten.
 ///
-/// It chunks the file to not use too much memory.
+/// It chunks the file to not use too much memory. File is synced to disk
+/// before this function returns.
+///
+/// File is downloaded to the [`part_path`] first and renamed to the given path
+/// when the download is finished. Interrupted download is resumed on the next
+/// call, if the server supports `Range` requests.
+///
+/// Bandwidth limit and progress reporting are set with `options`.
 ///
 /// # Examples
 ///
 /// ```
-/// use firelaunch::utils::net::download_to;
+/// use firelaunch::utils::net::{download_to, DownloadOptions};
 /// use tokio::runtime::Runtime;
 /// use std::path::Path;
 ///
 /// let mut rt = Runtime::new().unwrap();
 /// rt.block_on(async {
-///   download_to(&reqwest::Client::new(), "https://ipfs.frsqr.xyz/ipfs/bafybeih764jjsjnf5inznxgifpzuzinhgn4565sxxqtl2vuylaawc6mzf4/hello.txt", &Path::new("hello.txt")).await.unwrap();
+///   download_to(&reqwest::Client::new(), "https://ipfs.frsqr.xyz/ipfs/bafybeih764jjsjnf5inznxgifpzuzinhgn4565sxxqtl2vuylaawc6mzf4/hello.txt", &Path::new("hello.txt"), DownloadOptions::default()).await.unwrap();
 /// });
 ///
 /// // Check that the file was downloaded
@@ -166,18 +369,15 @@ impl Default for NetClient {
 /// - [`NetworkError::NetworkError`] if there was an error while downloading the file.
 /// - [`NetworkError::IOError`] if there was an error while writing the file.
 /// - [`NetworkError::DirectoryNotExists`] if the parent directory of the given path does not exist.
-pub async fn download_to(client: &Client, url: &str, path: &Path) -> Result<(), NetworkError> {
+pub async fn download_to(
+	client: &Client,
+	url: &str,
+	path: &Path,
+	options: DownloadOptions<'_>,
+) -> Result<(), NetworkError> {
 	debug!("Downloading file from {} to {}", url, path.display());
-	if path.parent().is_none() {
-		return Err(NetworkError::DirectoryNotExists(
-			path.to_str().unwrap().to_string(),
-		));
-	}
-	let mut response = client.get(url).send().await?;
-	let mut file = fs::File::create(path).await?;
-	while let Some(chunk) = response.chunk().await? {
-		file.write_all(&chunk).await?;
-	}
+	download_resumable(|| client.get(url), url, path, None, options.transfer(url)).await?;
+	fs::rename(part_path(path), path).await?;
 	Ok(())
 }
 
@@ -186,7 +386,10 @@ pub async fn download_to(client: &Client, url: &str, path: &Path) -> Result<(),
 /// Function downloads a file from the given URL to the given path.
 /// If the file already exists, it will be overwritten.
 ///
-/// It chunks the file to not use too much memory.
+/// It chunks the file to not use too much memory. File is synced to disk
+/// before this function returns.
+///
+/// Download is resumed the same way as in [`download_to`].
 ///
 /// Hash is calculated using SHA1.
 ///
@@ -196,13 +399,13 @@ pub async fn download_to(client: &Client, url: &str, path: &Path) -> Result<(),
 /// # Examples
 ///
 /// ```
-/// use firelaunch::utils::net::download_and_hash;
+/// use firelaunch::utils::net::{download_and_hash, DownloadOptions};
 /// use tokio::runtime::Runtime;
 /// use std::path::Path;
 ///
 /// let mut rt = Runtime::new().unwrap();
 /// rt.block_on(async {
-///   let hash = download_and_hash(&reqwest::Client::new(), "https://ipfs.frsqr.xyz/ipfs/bafybeih764jjsjnf5inznxgifpzuzinhgn4565sxxqtl2vuylaawc6mzf4/hello.txt", &Path::new("hello.txt")).await.unwrap();
+///   let hash = download_and_hash(&reqwest::Client::new(), "https://ipfs.frsqr.xyz/ipfs/bafybeih764jjsjnf5inznxgifpzuzinhgn4565sxxqtl2vuylaawc6mzf4/hello.txt", &Path::new("hello.txt"), DownloadOptions::default()).await.unwrap();
 ///
 ///   // Check that the file was downloaded
 ///   assert!(Path::new("hello.txt").exists());
@@ -224,53 +427,644 @@ pub async fn download_and_hash(
 	client: &Client,
 	url: &str,
 	path: &Path,
+	options: DownloadOptions<'_>,
 ) -> Result<String, NetworkError> {
 	debug!("Downloading file from {} to {}", url, path.display());
+	let hash = download_part(client, url, path, options).await?;
+	fs::rename(part_path(path), path).await?;
+	Ok(hash)
+}
+
+/// Downloads a file from the given URL to the [`part_path`] of the given path
+/// and calculates its sha1 hash.
+///
+/// Unlike [`download_and_hash`], the `.part` file is not renamed to the given
+/// path, so the caller can verify it first.
+///
+/// If the `.part` file already exists, download is resumed with a `Range`
+/// request and the hash is calculated from the bytes already on disk plus the
+/// new ones. If the server ignores the range, the file is downloaded from the
+/// beginning.
+///
+/// # Errors
+///
+/// - [`NetworkError::NetworkError`] if there was an error while downloading the file.
+/// - [`NetworkError::IOError`] if there was an error while writing the file.
+/// - [`NetworkError::DirectoryNotExists`] if the parent directory of the given path does not exist.
+pub async fn download_part(
+	client: &Client,
+	url: &str,
+	path: &Path,
+	options: DownloadOptions<'_>,
+) -> Result<String, NetworkError> {
+	download_part_with(client, url, path, HashAlgorithm::Sha1, options).await
+}
+
+/// Same as [`download_part`], but calculates hash using the given algorithm.
+pub async fn download_part_with(
+	client: &Client,
+	url: &str,
+	path: &Path,
+	algorithm: HashAlgorithm,
+	options: DownloadOptions<'_>,
+) -> Result<String, NetworkError> {
+	let mut hasher = algorithm.hasher();
+	download_resumable(
+		|| client.get(url),
+		url,
+		path,
+		Some(&mut hasher),
+		options.transfer(url),
+	)
+	.await?;
+	Ok(hasher.finalize())
+}
+
+/// Downloads a file from the given URL into memory.
+///
+/// Use this function only for small files or when the file can't be written
+/// to disk, e.g. for in-memory storage backends.
+///
+/// # Errors
+///
+/// - [`NetworkError::NetworkError`] if there was an error while downloading the file.
+pub async fn download_bytes(
+	client: &Client,
+	url: &str,
+	options: DownloadOptions<'_>,
+) -> Result<Vec<u8>, NetworkError> {
+	Ok(fetch_bytes(client, url, options.transfer(url)).await?.0)
+}
+
+/// Options of a single download made by [`NetClient`].
+///
+/// Downloads made with free functions of this module use only the limiter
+/// and the progress handler from their [`DownloadOptions`].
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Transfer<'a> {
+	/// Bandwidth limiter.
+	limiter: Option<&'a RateLimiter>,
+	/// Progress handler and id of the download.
+	progress: Option<(&'a dyn ProgressHandler, &'a str)>,
+	/// Timeout of waiting for the response or the next chunk.
+	read_timeout: Option<Duration>,
+	/// Whether the download must fail without sending a request.
+	offline: bool,
+	/// Expected size of the file.
+	expected_size: Option<u64>,
+}
+
+impl Transfer<'_> {
+	/// Set the expected size of the file.
+	pub(super) fn with_expected_size(self, size: Option<u64>) -> Self {
+		Self {
+			expected_size: size,
+			..self
+		}
+	}
+
+	/// Wait until the chunk of the given size can be received.
+	async fn acquire(&self, bytes: usize) {
+		if let Some(limiter) = self.limiter {
+			limiter.acquire(bytes as u64).await;
+		}
+	}
+
+	/// Fail if the client is in offline mode.
+	fn check_online(&self) -> Result<(), NetworkError> {
+		match self.offline {
+			true => Err(NetworkError::Offline),
+			false => Ok(()),
+		}
+	}
+
+	/// Fail if `size` is known and differs from the expected size.
+	fn check_size(&self, size: Option<u64>) -> Result<(), NetworkError> {
+		match (self.expected_size, size) {
+			(Some(expected), Some(size)) if size != expected => {
+				Err(NetworkError::SizeMismatch(expected, size))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Fail if `received` bytes exceed the expected size.
+	fn check_received(&self, received: u64) -> Result<(), NetworkError> {
+		match self.expected_size {
+			Some(expected) if received > expected => {
+				Err(NetworkError::SizeMismatch(expected, received))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Wait for the response or the next chunk, failing after the read timeout.
+	async fn read<T>(
+		&self,
+		future: impl Future<Output = reqwest::Result<T>>,
+	) -> Result<T, NetworkError> {
+		match self.read_timeout {
+			Some(timeout) => tokio::time::timeout(timeout, future)
+				.await
+				.map_err(|_| NetworkError::ReadTimeout(timeout))?,
+			None => future.await,
+		}
+		.map_err(NetworkError::from)
+	}
+
+	/// Send the progress event to the handler.
+	fn report(&self, received: u64, total: Option<u64>, state: TransferState) {
+		if let Some((handler, id)) = self.progress {
+			handler.on_progress(&ProgressEvent {
+				id: id.to_string(),
+				received,
+				total,
+				state,
+			});
+		}
+	}
+
+	/// Report the download as failed if `result` is an error.
+	fn check<T>(&self, result: Result<T, NetworkError>) -> Result<T, NetworkError> {
+		if result.is_err() {
+			self.report(0, None, TransferState::Failed);
+		}
+		result
+	}
+}
+
+/// Downloads a file into memory and returns it with the time to the response.
+async fn fetch_bytes(
+	client: &Client,
+	url: &str,
+	transfer: Transfer<'_>,
+) -> Result<(Vec<u8>, Duration), NetworkError> {
+	let response = fetch(client.get(url), url, transfer).await?;
+	Ok((response.data, response.latency))
+}
+
+/// Response downloaded into memory.
+pub(super) struct FetchedResponse {
+	/// Response status, never an error one.
+	pub status: StatusCode,
+	/// Response headers.
+	pub headers: HeaderMap,
+	/// Response body.
+	pub data: Vec<u8>,
+	/// Time from sending the request to receiving the response headers.
+	pub latency: Duration,
+}
+
+/// Send the request for `url` and download the response into memory.
+///
+/// Error statuses are returned as [`NetworkError::NetworkError`].
+pub(super) async fn fetch(
+	request: RequestBuilder,
+	url: &str,
+	transfer: Transfer<'_>,
+) -> Result<FetchedResponse, NetworkError> {
+	let result = async {
+		transfer.check_online()?;
+		debug!("Downloading file from {} into memory", url);
+		let started = Instant::now();
+		let response = transfer.read(request.send()).await?;
+		let mut response = response.error_for_status()?;
+		let latency = started.elapsed();
+		let total = response.content_length();
+		transfer.check_size(total)?;
+		let total = total.or(transfer.expected_size);
+		transfer.report(0, total, TransferState::Running);
+		let mut data = Vec::new();
+		while let Some(chunk) = transfer.read(response.chunk()).await? {
+			transfer.check_received((data.len() + chunk.len()) as u64)?;
+			transfer.acquire(chunk.len()).await;
+			data.extend_from_slice(&chunk);
+			transfer.report(data.len() as u64, total, TransferState::Running);
+		}
+		transfer.check_size(Some(data.len() as u64))?;
+		transfer.report(data.len() as u64, total, TransferState::Finished);
+		Ok(FetchedResponse {
+			status: response.status(),
+			headers: response.headers().clone(),
+			data,
+			latency,
+		})
+	}
+	.await;
+	transfer.check(result)
+}
+
+/// Response body which is received chunk by chunk.
+///
+/// Chunks are rate limited, reported and timed out the same way as other
+/// downloads of [`NetClient`].
+#[derive(Debug)]
+pub struct BodyStream<'a> {
+	response: Response,
+	transfer: Transfer<'a>,
+	received: u64,
+	total: Option<u64>,
+	latency: Duration,
+}
+
+impl<'a> BodyStream<'a> {
+	/// Send the request for `url` and wait for the response headers.
+	///
+	/// Error statuses are returned as [`NetworkError::NetworkError`].
+	pub(super) async fn open(
+		request: RequestBuilder,
+		url: &str,
+		transfer: Transfer<'a>,
+	) -> Result<BodyStream<'a>, NetworkError> {
+		let result = async {
+			transfer.check_online()?;
+			debug!("Streaming file from {}", url);
+			let started = Instant::now();
+			let response = transfer.read(request.send()).await?;
+			let response = response.error_for_status()?;
+			let total = response.content_length();
+			transfer.report(0, total, TransferState::Running);
+			Ok(Self {
+				response,
+				transfer,
+				received: 0,
+				total,
+				latency: started.elapsed(),
+			})
+		}
+		.await;
+		transfer.check(result)
+	}
+
+	/// Get time from sending the request to receiving the response headers.
+	#[inline]
+	pub fn latency(&self) -> Duration {
+		self.latency
+	}
+
+	/// Receive the next chunk of the body, or `None` at its end.
+	pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, NetworkError> {
+		let result = self.transfer.read(self.response.chunk()).await;
+		let chunk = match self.transfer.check(result)? {
+			Some(chunk) => chunk,
+			None => {
+				self.transfer
+					.report(self.received, self.total, TransferState::Finished);
+				return Ok(None);
+			}
+		};
+		self.transfer.acquire(chunk.len()).await;
+		self.received += chunk.len() as u64;
+		self.transfer
+			.report(self.received, self.total, TransferState::Running);
+		Ok(Some(chunk.to_vec()))
+	}
+}
+
+/// Get path of the partially downloaded file for the given path.
+///
+/// # Examples
+///
+/// ```
+/// use firelaunch::utils::net::part_path;
+/// use std::path::Path;
+///
+/// assert_eq!(part_path(Path::new("dir/file.jar")), Path::new("dir/file.jar.part"));
+/// ```
+pub fn part_path(path: &Path) -> PathBuf {
+	let mut part = path.as_os_str().to_owned();
+	part.push(".part");
+	PathBuf::from(part)
+}
+
+/// Downloads a file to the [`part_path`] of the given path, resuming the
+/// download if the `.part` file already exists.
+///
+/// If `hasher` is given, it's fed with the whole file contents, including the
+/// bytes downloaded previously.
+///
+/// `request` builds the request for `url`, it may be called several times.
+///
+/// Returns time from sending the request to receiving the response headers.
+pub(super) async fn download_resumable(
+	request: impl Fn() -> RequestBuilder,
+	url: &str,
+	path: &Path,
+	hasher: Option<&mut Hasher>,
+	transfer: Transfer<'_>,
+) -> Result<Duration, NetworkError> {
+	let result = write_part(request, url, path, hasher, transfer).await;
+	transfer.check(result)
+}
+
+/// Body of [`download_resumable`].
+async fn write_part(
+	request: impl Fn() -> RequestBuilder,
+	url: &str,
+	path: &Path,
+	mut hasher: Option<&mut Hasher>,
+	transfer: Transfer<'_>,
+) -> Result<Duration, NetworkError> {
+	transfer.check_online()?;
 	if path.parent().is_none() {
 		return Err(NetworkError::DirectoryNotExists(
 			path.to_str().unwrap().to_string(),
 		));
 	}
-	let mut response = client.get(url).send().await?;
-	let mut file = fs::File::create(path).await?;
-	let mut hasher = sha1::Sha1::new();
-	while let Some(chunk) = response.chunk().await? {
+	let part = part_path(path);
+	let mut offset = match fs::metadata(&part).await {
+		Ok(metadata) => metadata.len(),
+		Err(_) => 0,
+	};
+	if transfer.check_received(offset).is_err() {
+		debug!(
+			"Part file is bigger than expected, restarting download of {}",
+			url
+		);
+		offset = 0;
+	}
+
+	let started = Instant::now();
+	let mut ranged = request();
+	if offset > 0 {
+		debug!("Resuming download of {} from byte {}", url, offset);
+		ranged = ranged.header(RANGE, format!("bytes={offset}-"));
+	}
+	let mut response = transfer.read(ranged.send()).await?;
+	if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+		// Part file is bigger than the remote file, start over
+		debug!("Range not satisfiable, restarting download of {}", url);
+		offset = 0;
+		response = transfer.read(request().send()).await?;
+	}
+	let mut response = response.error_for_status()?;
+	let latency = started.elapsed();
+
+	let content_range = response
+		.headers()
+		.get(CONTENT_RANGE)
+		.and_then(|value| value.to_str().ok());
+	let resumed = offset > 0
+		&& response.status() == StatusCode::PARTIAL_CONTENT
+		&& matches!(content_range, Some(range) if range.starts_with(&format!("bytes {offset}-")));
+	let mut received = if resumed { offset } else { 0 };
+	let total = response.content_length().map(|length| received + length);
+	transfer.check_size(total)?;
+	let total = total.or(transfer.expected_size);
+
+	let mut file = if resumed {
+		if let Some(hasher) = hasher.as_deref_mut() {
+			hash_file(&part, hasher).await?;
+		}
+		fs::OpenOptions::new().append(true).open(&part).await?
+	} else {
+		if offset > 0 {
+			debug!("Server ignored range, downloading {} from scratch", url);
+		}
+		fs::File::create(&part).await?
+	};
+
+	transfer.report(received, total, TransferState::Running);
+	let size_check = loop {
+		let chunk = match transfer.read(response.chunk()).await? {
+			Some(chunk) => chunk,
+			None => break transfer.check_size(Some(received)),
+		};
+		received += chunk.len() as u64;
+		if let Err(e) = transfer.check_received(received) {
+			break Err(e);
+		}
+		transfer.acquire(chunk.len()).await;
 		file.write_all(&chunk).await?;
-		hasher.update(&chunk);
+		if let Some(hasher) = hasher.as_deref_mut() {
+			hasher.update(&chunk);
+		}
+		transfer.report(received, total, TransferState::Running);
+	};
+	if let Err(e) = size_check {
+		// Data from the misbehaving server is not resumed
+		drop(file);
+		fs::remove_file(&part).await?;
+		return Err(e);
 	}
-	let hash = hasher.finalize();
-	Ok(hex::encode(hash))
+	file.sync_all().await?;
+	transfer.report(received, total, TransferState::Finished);
+	Ok(latency)
+}
+
+/// Feed contents of the given file to the hasher.
+pub(super) async fn hash_file(path: &Path, hasher: &mut Hasher) -> Result<(), NetworkError> {
+	let mut reader = fs::File::open(path).await?;
+	let mut buffer = vec![0; 128 * 1024];
+	loop {
+		let n = reader.read(&mut buffer).await?;
+		if n == 0 {
+			break;
+		}
+		hasher.update(&buffer[..n]);
+	}
+	Ok(())
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use std::path::Path;
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::test_server::{Route, TestServer};
 
 	#[tokio::test]
 	async fn test_download_to() {
-		let client = Client::new();
-		download_to(&client, "https://ipfs.frsqr.xyz/ipfs/bafybeih764jjsjnf5inznxgifpzuzinhgn4565sxxqtl2vuylaawc6mzf4/hello.txt", Path::new("hello.txt")).await.unwrap();
+		let server = TestServer::start().await;
+		server.route("/hello.txt", Route::ok(b"Hello, world!"));
+		let dir = TempDir::new();
+		let path = dir.join("file");
 
-		// Check that the file was downloaded
-		assert!(Path::new("hello.txt").exists());
+		download_to(
+			&Client::new(),
+			&server.url("/hello.txt"),
+			&path,
+			DownloadOptions::default(),
+		)
+		.await
+		.unwrap();
 
-		// Cleanup
-		std::fs::remove_file("hello.txt").unwrap();
+		// Check that the file was downloaded
+		assert_eq!(std::fs::read(&path).unwrap(), b"Hello, world!");
+		assert!(!part_path(&path).exists());
 	}
 
 	#[tokio::test]
 	async fn test_download_and_hash() {
-		let client = Client::new();
-		let hash = download_and_hash(&client, "https://ipfs.frsqr.xyz/ipfs/bafybeih764jjsjnf5inznxgifpzuzinhgn4565sxxqtl2vuylaawc6mzf4/hello.txt", Path::new("hello.txt2")).await.unwrap();
+		let server = TestServer::start().await;
+		server.route("/hello.txt", Route::ok(b"Hello, world!"));
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		let hash = download_and_hash(
+			&Client::new(),
+			&server.url("/hello.txt"),
+			&path,
+			DownloadOptions::default(),
+		)
+		.await
+		.unwrap();
 
 		// Check that the file was downloaded
-		assert!(Path::new("hello.txt2").exists());
+		assert!(path.exists());
 
 		// Check that the hash is correct
-		assert_eq!(hash, "e1b4daf52c3f457146e4d8640e4b4f8fdd759bc4");
+		assert_eq!(hash, "943a702d06f34599aee1f8da8ef9f7296031d699");
+	}
+
+	#[tokio::test]
+	async fn test_download_resume() {
+		let server = TestServer::start().await;
+		server.route(
+			"/file",
+			Route {
+				ranges: true,
+				..Route::ok(b"Hello, world!")
+			},
+		);
+		let dir = TempDir::new();
+		let path = dir.join("file");
+		std::fs::write(part_path(&path), b"Hello").unwrap();
+
+		let hash = download_and_hash(
+			&Client::new(),
+			&server.url("/file"),
+			&path,
+			DownloadOptions::default(),
+		)
+		.await
+		.unwrap();
+		assert_eq!(hash, crate::utils::crypto::sha1_digest(b"Hello, world!"));
+		assert_eq!(std::fs::read(&path).unwrap(), b"Hello, world!");
+		assert!(!part_path(&path).exists());
+		assert_eq!(server.requests()[0].headers["range"], "bytes=5-");
+	}
+
+	#[tokio::test]
+	async fn test_download_resume_ignored() {
+		let server = TestServer::start().await;
+		server.route("/file", Route::ok(b"Hello, world!"));
+		let dir = TempDir::new();
+		let path = dir.join("file");
+		std::fs::write(part_path(&path), b"Bye").unwrap();
+
+		let hash = download_and_hash(
+			&Client::new(),
+			&server.url("/file"),
+			&path,
+			DownloadOptions::default(),
+		)
+		.await
+		.unwrap();
+		assert_eq!(hash, crate::utils::crypto::sha1_digest(b"Hello, world!"));
+		assert_eq!(std::fs::read(&path).unwrap(), b"Hello, world!");
+	}
+
+	#[tokio::test]
+	async fn test_read_timeout() {
+		// Server which accepts connections, but never responds
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/file", listener.local_addr().unwrap());
+		tokio::spawn(async move {
+			let mut streams = Vec::new();
+			while let Ok((stream, _)) = listener.accept().await {
+				streams.push(stream);
+			}
+		});
+		let mut client = NetClient::new();
+		client.set_read_timeout(Some(Duration::from_millis(50)));
+		client.set_retry_policy(RetryPolicy::none());
 
-		// Cleanup
-		std::fs::remove_file("hello.txt2").unwrap();
+		let result = client.download_bytes(&url).await;
+		assert!(matches!(result, Err(NetworkError::ReadTimeout(_))));
+	}
+
+	#[tokio::test]
+	async fn test_offline() {
+		let server = TestServer::start().await;
+		server.route("/file", Route::ok(b"file"));
+		let client = NetClient::new();
+		let clone = client.clone();
+		client.set_offline(true);
+		assert!(clone.is_offline());
+
+		let result = clone.download_bytes(&server.url("/file")).await;
+		assert!(matches!(result, Err(NetworkError::Offline)));
+		assert!(server.requests().is_empty());
+
+		client.set_offline(false);
+		assert_eq!(
+			clone.download_bytes(&server.url("/file")).await.unwrap(),
+			b"file"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_download_error_status() {
+		let server = TestServer::start().await;
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		let result = download_to(
+			&Client::new(),
+			&server.url("/missing"),
+			&path,
+			DownloadOptions::default(),
+		)
+		.await;
+		assert!(matches!(result, Err(NetworkError::NetworkError(_))));
+		assert!(!path.exists());
+	}
+
+	#[tokio::test]
+	async fn test_rate_limit() {
+		let server = TestServer::start().await;
+		server.route("/file", Route::ok(&[0; 3000]));
+		let client = NetClient::new();
+		client.rate_limiter().set_limit(Some(2000));
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		// First 2000 bytes are taken from the full bucket, the rest takes 0.5s
+		let started = Instant::now();
+		client
+			.download_to(&server.url("/file"), &path)
+			.await
+			.unwrap();
+		assert!(started.elapsed() >= Duration::from_millis(400));
+		assert_eq!(std::fs::read(&path).unwrap().len(), 3000);
+	}
+
+	#[tokio::test]
+	async fn test_download_options() {
+		let server = TestServer::start().await;
+		server.route("/file", Route::ok(&[0; 3000]));
+		let mut client = NetClient::new();
+		client.rate_limiter().set_limit(Some(2000));
+		let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+		client.set_progress_handler(Some(Arc::new(sender)));
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		// Free function shares the limiter of the client
+		let url = server.url("/file");
+		let started = Instant::now();
+		download_to(client.client(), &url, &path, client.download_options())
+			.await
+			.unwrap();
+		assert!(started.elapsed() >= Duration::from_millis(400));
+		drop(client);
+		let mut events = Vec::new();
+		while let Some(event) = receiver.recv().await {
+			events.push(event);
+		}
+		let last = events.last().unwrap();
+		assert_eq!(last.id, url);
+		assert_eq!(last.received, 3000);
+		assert_eq!(last.state, TransferState::Finished);
 	}
 }
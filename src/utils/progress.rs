@@ -0,0 +1,292 @@
+//! Download progress reporting.
+//!
+//! Downloads made by [`NetClient`] send [`ProgressEvent`]s to its
+//! [`ProgressHandler`], if one is set.
+//! [`ProgressTracker`] is a handler which aggregates events of concurrent
+//! downloads into total bytes, throughput and ETA.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::net::NetClient;
+
+/// Period over which throughput is averaged.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// State of a single download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+	/// Download is in progress.
+	Running,
+	/// Download is finished.
+	Finished,
+	/// Download failed. It may be started again with the same id.
+	Failed,
+}
+
+/// Progress of a single download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+	/// Download id, the URL or the CID for IPFS downloads.
+	pub id: String,
+	/// Bytes received so far, including bytes of the resumed download.
+	pub received: u64,
+	/// Size of the whole file, if known.
+	pub total: Option<u64>,
+	/// State of the download.
+	pub state: TransferState,
+}
+
+/// Receiver of download progress events.
+///
+/// Events are sent from download tasks, so handlers should be cheap.
+pub trait ProgressHandler: std::fmt::Debug + Send + Sync {
+	/// Handle the progress event.
+	fn on_progress(&self, event: &ProgressEvent);
+}
+
+/// Events are sent to the channel. Events are dropped if the receiver is closed.
+impl ProgressHandler for tokio::sync::mpsc::UnboundedSender<ProgressEvent> {
+	fn on_progress(&self, event: &ProgressEvent) {
+		let _ = self.send(event.clone());
+	}
+}
+
+/// Aggregated progress of all downloads.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgressSnapshot {
+	/// Bytes received by running and finished downloads.
+	pub received: u64,
+	/// Total size of running and finished downloads, if all of them are known.
+	pub total: Option<u64>,
+	/// Number of running downloads.
+	pub running: usize,
+	/// Number of finished downloads.
+	pub finished: usize,
+	/// Number of failed downloads.
+	pub failed: usize,
+	/// Average download speed over the last few seconds, in bytes per second.
+	pub throughput: f64,
+	/// Estimated time until all downloads are finished.
+	pub eta: Option<Duration>,
+}
+
+impl ProgressSnapshot {
+	/// Get fraction of received bytes from `0.0` to `1.0`, if total is known.
+	pub fn fraction(&self) -> Option<f64> {
+		match self.total {
+			Some(0) => Some(1.0),
+			Some(total) => Some((self.received as f64 / total as f64).min(1.0)),
+			None => None,
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+	/// Latest event of every download.
+	downloads: HashMap<String, ProgressEvent>,
+	/// Bytes received since the tracker was created or reset, not counting
+	/// bytes of resumed downloads which were on disk already.
+	downloaded: u64,
+	/// Recent values of `downloaded` for throughput calculation.
+	samples: VecDeque<(Instant, u64)>,
+}
+
+/// Aggregator of concurrent downloads progress.
+///
+/// # Examples
+///
+/// ```
+/// use firelaunch::utils::progress::{ProgressEvent, ProgressHandler, ProgressTracker, TransferState};
+///
+/// let tracker = ProgressTracker::new();
+/// tracker.on_progress(&ProgressEvent {
+///   id: "file".to_string(),
+///   received: 50,
+///   total: Some(200),
+///   state: TransferState::Running,
+/// });
+/// let snapshot = tracker.snapshot();
+/// assert_eq!(snapshot.received, 50);
+/// assert_eq!(snapshot.fraction(), Some(0.25));
+/// ```
+#[derive(Debug, Default)]
+pub struct ProgressTracker {
+	state: Mutex<TrackerState>,
+}
+
+impl ProgressTracker {
+	/// Creates a new empty tracker.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Forget all downloads, e.g. before starting a new batch.
+	pub fn reset(&self) {
+		*self.state.lock().unwrap() = TrackerState::default();
+	}
+
+	/// Get aggregated progress of all downloads.
+	pub fn snapshot(&self) -> ProgressSnapshot {
+		let mut state = self.state.lock().unwrap();
+		let now = Instant::now();
+		prune_samples(&mut state.samples, now);
+
+		let mut snapshot = ProgressSnapshot {
+			total: Some(0),
+			..ProgressSnapshot::default()
+		};
+		for event in state.downloads.values() {
+			match event.state {
+				TransferState::Running => snapshot.running += 1,
+				TransferState::Finished => snapshot.finished += 1,
+				// Failed downloads don't add to the received and total bytes
+				TransferState::Failed => {
+					snapshot.failed += 1;
+					continue;
+				}
+			}
+			snapshot.received += event.received;
+			snapshot.total = match (snapshot.total, event.total) {
+				(Some(sum), Some(total)) => Some(sum + total),
+				_ => None,
+			};
+		}
+
+		if let Some(&(oldest, downloaded)) = state.samples.front() {
+			let elapsed = now.duration_since(oldest).as_secs_f64();
+			if elapsed > 0.0 {
+				snapshot.throughput = (state.downloaded - downloaded) as f64 / elapsed;
+			}
+		}
+		if let Some(total) = snapshot.total {
+			let remaining = total.saturating_sub(snapshot.received);
+			if remaining == 0 {
+				snapshot.eta = Some(Duration::ZERO);
+			} else if snapshot.throughput > 0.0 {
+				snapshot.eta = Some(Duration::from_secs_f64(
+					remaining as f64 / snapshot.throughput,
+				));
+			}
+		}
+		snapshot
+	}
+}
+
+impl ProgressHandler for ProgressTracker {
+	fn on_progress(&self, event: &ProgressEvent) {
+		let mut state = self.state.lock().unwrap();
+		// The first event of a download, or of a restarted one, carries bytes
+		// which were on disk already
+		let previous = state
+			.downloads
+			.get(&event.id)
+			.filter(|event| event.state != TransferState::Failed)
+			.map(|event| event.received);
+		if let Some(previous) = previous {
+			state.downloaded += event.received.saturating_sub(previous);
+		}
+		let now = Instant::now();
+		let downloaded = state.downloaded;
+		state.samples.push_back((now, downloaded));
+		prune_samples(&mut state.samples, now);
+		state.downloads.insert(event.id.clone(), event.clone());
+	}
+}
+
+impl NetClient {
+	/// Sets the handler of download progress events.
+	///
+	/// Events are sent by every download of this client, with the URL as an id.
+	/// IPFS downloads use the CID (or path) as an id instead, so it doesn't
+	/// change when the download fails over to another gateway.
+	pub fn set_progress_handler(&mut self, handler: Option<Arc<dyn ProgressHandler>>) {
+		self.progress = handler;
+	}
+}
+
+/// Remove samples older than [`THROUGHPUT_WINDOW`], keeping at least one.
+fn prune_samples(samples: &mut VecDeque<(Instant, u64)>, now: Instant) {
+	while samples.len() > 1
+		&& matches!(samples.front(), Some(&(time, _)) if now.duration_since(time) > THROUGHPUT_WINDOW)
+	{
+		samples.pop_front();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::crypto::HashAlgorithm;
+	use crate::utils::fs::tests::TempDir;
+	use crate::utils::test_server::{Route, TestServer};
+
+	fn event(id: &str, received: u64, total: Option<u64>, state: TransferState) -> ProgressEvent {
+		ProgressEvent {
+			id: id.to_string(),
+			received,
+			total,
+			state,
+		}
+	}
+
+	#[test]
+	fn test_progress_tracker() {
+		let tracker = ProgressTracker::new();
+		// Resumed download, 100 bytes were on disk
+		tracker.on_progress(&event("a", 100, Some(400), TransferState::Running));
+		tracker.on_progress(&event("b", 0, Some(100), TransferState::Running));
+		std::thread::sleep(Duration::from_millis(50));
+		tracker.on_progress(&event("a", 300, Some(400), TransferState::Running));
+		tracker.on_progress(&event("b", 100, Some(100), TransferState::Finished));
+
+		let snapshot = tracker.snapshot();
+		assert_eq!(snapshot.received, 400);
+		assert_eq!(snapshot.total, Some(500));
+		assert_eq!(snapshot.fraction(), Some(0.8));
+		assert_eq!((snapshot.running, snapshot.finished), (1, 1));
+		// Only the 300 new bytes count towards throughput
+		assert!(snapshot.throughput > 0.0 && snapshot.throughput <= 300.0 / 0.05);
+		let eta = snapshot.eta.unwrap();
+		assert_eq!(eta, Duration::from_secs_f64(100.0 / snapshot.throughput));
+
+		// Unknown size of a single download makes the total unknown
+		tracker.on_progress(&event("c", 0, None, TransferState::Running));
+		let snapshot = tracker.snapshot();
+		assert_eq!(snapshot.total, None);
+		assert_eq!(snapshot.eta, None);
+
+		tracker.reset();
+		assert_eq!(tracker.snapshot().received, 0);
+	}
+
+	#[tokio::test]
+	async fn test_progress() {
+		let server = TestServer::start().await;
+		server.route("/ipfs/file", Route::ok(b"Hello, world!"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&server.url("/ipfs/"));
+		let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+		client.set_progress_handler(Some(Arc::new(sender)));
+		let dir = TempDir::new();
+		let path = dir.join("file");
+
+		client
+			.download_ipfs_part_with("file", &path, HashAlgorithm::Sha1, None, None)
+			.await
+			.unwrap();
+		drop(client);
+		let mut events = Vec::new();
+		while let Some(event) = receiver.recv().await {
+			events.push(event);
+		}
+		assert_eq!(events[0].received, 0);
+		let last = events.last().unwrap();
+		assert_eq!(last.id, "file");
+		assert_eq!(last.received, 13);
+		assert_eq!(last.total, Some(13));
+		assert_eq!(last.state, TransferState::Finished);
+	}
+}
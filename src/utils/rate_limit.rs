@@ -0,0 +1,163 @@
+//! Download bandwidth limiter.
+//!
+//! [`RateLimiter`] is a token bucket shared by all clones of [`NetClient`].
+//! Every downloaded chunk takes tokens from the bucket, and the download is
+//! paused when the bucket is empty, so the total bandwidth of parallel
+//! downloads stays below the limit.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::net::NetClient;
+
+/// Token bucket state.
+#[derive(Debug)]
+struct Bucket {
+	/// Limit in bytes per second, `None` if unlimited.
+	limit: Option<u64>,
+	/// Available bytes. Negative if chunks were taken in advance.
+	tokens: f64,
+	/// Time of the last refill.
+	updated: Instant,
+}
+
+impl Bucket {
+	/// Add tokens for the time passed since the last refill.
+	///
+	/// At most one second worth of tokens is kept, so idle time doesn't allow
+	/// long bursts.
+	fn refill(&mut self, limit: u64) {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * limit as f64).min(limit as f64);
+		self.updated = now;
+	}
+}
+
+/// Token bucket bandwidth limiter.
+///
+/// The limit can be changed at any time, including during downloads.
+#[derive(Debug)]
+pub struct RateLimiter {
+	bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+	/// Creates a new limiter with the given limit in bytes per second.
+	///
+	/// `None` or `Some(0)` means no limit.
+	pub fn new(limit: Option<u64>) -> Self {
+		let limit = limit.filter(|&limit| limit > 0);
+		Self {
+			bucket: Mutex::new(Bucket {
+				limit,
+				tokens: limit.unwrap_or(0) as f64,
+				updated: Instant::now(),
+			}),
+		}
+	}
+
+	/// Creates a new limiter without a limit.
+	pub fn unlimited() -> Self {
+		Self::new(None)
+	}
+
+	/// Get the limit in bytes per second, `None` if unlimited.
+	pub fn limit(&self) -> Option<u64> {
+		self.bucket.lock().unwrap().limit
+	}
+
+	/// Sets the limit in bytes per second.
+	///
+	/// `None` or `Some(0)` removes the limit.
+	pub fn set_limit(&self, limit: Option<u64>) {
+		let limit = limit.filter(|&limit| limit > 0);
+		let mut bucket = self.bucket.lock().unwrap();
+		if let Some(limit) = limit {
+			match bucket.limit {
+				Some(old_limit) => bucket.refill(old_limit),
+				None => {
+					bucket.tokens = limit as f64;
+					bucket.updated = Instant::now();
+				}
+			}
+			bucket.tokens = bucket.tokens.min(limit as f64);
+		}
+		bucket.limit = limit;
+		info!("Download bandwidth limit set to {:?} bytes/s", limit);
+	}
+
+	/// Take `bytes` tokens from the bucket, waiting if there are not enough.
+	///
+	/// Chunks bigger than the bucket are allowed, the next call will wait
+	/// longer instead.
+	pub async fn acquire(&self, bytes: u64) {
+		let delay = self.reserve(bytes);
+		if !delay.is_zero() {
+			tokio::time::sleep(delay).await;
+		}
+	}
+
+	/// Take tokens and get time to wait until they are available.
+	fn reserve(&self, bytes: u64) -> Duration {
+		let mut bucket = self.bucket.lock().unwrap();
+		let limit = match bucket.limit {
+			Some(limit) => limit,
+			None => return Duration::ZERO,
+		};
+		bucket.refill(limit);
+		bucket.tokens -= bytes as f64;
+		if bucket.tokens >= 0.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64(-bucket.tokens / limit as f64)
+		}
+	}
+}
+
+impl Default for RateLimiter {
+	fn default() -> Self {
+		Self::unlimited()
+	}
+}
+
+impl NetClient {
+	/// Returns the bandwidth limiter shared by all downloads of this client and
+	/// its clones.
+	///
+	/// The limit can be changed at runtime with [`RateLimiter::set_limit`].
+	/// Downloads made with free functions of the [`net`](super::net) module
+	/// share it only if it's passed in their
+	/// [`DownloadOptions`](super::net::DownloadOptions), see
+	/// [`NetClient::download_options`].
+	#[inline]
+	pub fn rate_limiter(&self) -> &RateLimiter {
+		&self.rate_limiter
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rate_limiter() {
+		let limiter = RateLimiter::unlimited();
+		assert_eq!(limiter.reserve(u64::MAX), Duration::ZERO);
+
+		// Full bucket is available at once
+		limiter.set_limit(Some(1000));
+		assert_eq!(limiter.limit(), Some(1000));
+		assert_eq!(limiter.reserve(1000), Duration::ZERO);
+		let delay = limiter.reserve(500);
+		assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+
+		// Raising the limit doesn't forgive the debt
+		limiter.set_limit(Some(2000));
+		assert!(limiter.reserve(0) > Duration::from_millis(200));
+
+		limiter.set_limit(Some(0));
+		assert_eq!(limiter.limit(), None);
+		assert_eq!(limiter.reserve(1000), Duration::ZERO);
+	}
+}
@@ -0,0 +1,226 @@
+//! Retry policy with exponential backoff.
+//!
+//! [`RetryPolicy`] repeats a failed operation while its error is
+//! [`Retryable`], waiting exponentially longer between attempts. Random jitter
+//! is added to the delays, so many failed downloads don't retry at once.
+
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::net::NetClient;
+
+/// Error which may succeed if the operation is repeated.
+pub trait Retryable {
+	/// Check if the operation should be retried after this error.
+	///
+	/// Transient errors, like timeouts, server errors or corrupted transfers,
+	/// are retryable. Errors which would happen again, like `404 Not Found` or
+	/// local IO errors, are fatal.
+	fn is_retryable(&self) -> bool;
+}
+
+/// Retry policy with exponential backoff and jitter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+	/// Maximum number of attempts, including the first one.
+	pub max_attempts: u32,
+	/// Delay before the first retry.
+	pub initial_delay: Duration,
+	/// Maximum delay between attempts.
+	pub max_delay: Duration,
+	/// Factor by which the delay grows after every attempt.
+	///
+	/// Infinite or NaN multiplier is treated as `1.0`.
+	pub multiplier: f64,
+	/// Fraction of the delay which is randomized, from `0.0` to `1.0`.
+	///
+	/// For example, with `0.5` the actual delay is between 50% and 100% of
+	/// the calculated one. Infinite or NaN jitter is treated as `0.0`.
+	pub jitter: f64,
+}
+
+impl RetryPolicy {
+	/// Policy which never retries.
+	pub fn none() -> Self {
+		Self {
+			max_attempts: 1,
+			..Self::default()
+		}
+	}
+
+	/// Get delay before the given retry, starting from 1.
+	///
+	/// # Examples
+	///
+	/// ```
+	/// use firelaunch::utils::retry::RetryPolicy;
+	/// use std::time::Duration;
+	///
+	/// let policy = RetryPolicy {
+	///   jitter: 0.0,
+	///   ..RetryPolicy::default()
+	/// };
+	/// assert_eq!(policy.delay(1), policy.initial_delay);
+	/// assert_eq!(policy.delay(2), policy.initial_delay * 2);
+	/// assert_eq!(policy.delay(100), policy.max_delay);
+	/// ```
+	pub fn delay(&self, retry: u32) -> Duration {
+		let exponent = retry.saturating_sub(1).min(63) as i32;
+		let multiplier = match self.multiplier.is_finite() {
+			true => self.multiplier,
+			false => 1.0,
+		};
+		// Negative multiplier would give negative delays
+		let delay = (self.initial_delay.as_secs_f64() * multiplier.powi(exponent))
+			.min(self.max_delay.as_secs_f64())
+			.max(0.0);
+		let jitter = match self.jitter.is_finite() {
+			true => self.jitter.clamp(0.0, 1.0),
+			false => 0.0,
+		};
+		let factor = if jitter > 0.0 {
+			rand::thread_rng().gen_range(1.0 - jitter..=1.0)
+		} else {
+			1.0
+		};
+		Duration::from_secs_f64(delay * factor)
+	}
+
+	/// Run `operation` until it succeeds, fails with a fatal error or runs out
+	/// of attempts.
+	///
+	/// The last error is returned if all attempts fail.
+	pub async fn retry<F, Fut, T, E>(&self, mut operation: F) -> Result<T, E>
+	where
+		F: FnMut() -> Fut,
+		Fut: Future<Output = Result<T, E>>,
+		E: Retryable + std::fmt::Display,
+	{
+		let mut attempt = 1;
+		loop {
+			match operation().await {
+				Ok(value) => return Ok(value),
+				Err(e) if attempt < self.max_attempts && e.is_retryable() => {
+					let delay = self.delay(attempt);
+					debug!(
+						"Attempt {}/{} failed, retrying in {:?}: {}",
+						attempt, self.max_attempts, delay, e
+					);
+					tokio::time::sleep(delay).await;
+					attempt += 1;
+				}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			max_attempts: 5,
+			initial_delay: Duration::from_millis(250),
+			max_delay: Duration::from_secs(10),
+			multiplier: 2.0,
+			jitter: 0.5,
+		}
+	}
+}
+
+impl NetClient {
+	/// Sets the policy used to retry failed downloads.
+	///
+	/// It's also used by [`Storage`](crate::storage::Storage) to retry object
+	/// downloads, including the ones which failed hash verification.
+	pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+		self.retry_policy = policy;
+	}
+
+	/// Returns the policy used to retry failed downloads.
+	#[inline]
+	pub fn retry_policy(&self) -> &RetryPolicy {
+		&self.retry_policy
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(Debug)]
+	struct TestError(bool);
+
+	impl std::fmt::Display for TestError {
+		fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+			write!(f, "retryable: {}", self.0)
+		}
+	}
+
+	impl Retryable for TestError {
+		fn is_retryable(&self) -> bool {
+			self.0
+		}
+	}
+
+	#[test]
+	fn test_invalid_delay() {
+		let policy = RetryPolicy {
+			initial_delay: Duration::from_secs(1),
+			multiplier: f64::NAN,
+			jitter: f64::NAN,
+			..RetryPolicy::default()
+		};
+		assert_eq!(policy.delay(3), Duration::from_secs(1));
+		let policy = RetryPolicy {
+			multiplier: -2.0,
+			jitter: f64::INFINITY,
+			..policy
+		};
+		assert_eq!(policy.delay(2), Duration::ZERO);
+	}
+
+	#[tokio::test]
+	async fn test_retry() {
+		let policy = RetryPolicy {
+			max_attempts: 3,
+			initial_delay: Duration::from_millis(1),
+			..RetryPolicy::default()
+		};
+
+		let mut attempts = 0;
+		let result = policy
+			.retry(|| {
+				attempts += 1;
+				async move {
+					match attempts {
+						1 => Err(TestError(true)),
+						_ => Ok(attempts),
+					}
+				}
+			})
+			.await;
+		assert_eq!(result.unwrap(), 2);
+
+		let mut attempts = 0;
+		let result: Result<(), _> = policy
+			.retry(|| {
+				attempts += 1;
+				async { Err(TestError(true)) }
+			})
+			.await;
+		assert!(result.is_err());
+		assert_eq!(attempts, 3);
+
+		let mut attempts = 0;
+		let result: Result<(), _> = policy
+			.retry(|| {
+				attempts += 1;
+				async { Err(TestError(false)) }
+			})
+			.await;
+		assert!(result.is_err());
+		assert_eq!(attempts, 1);
+	}
+}
@@ -0,0 +1,225 @@
+//! URL rewrite rules.
+//!
+//! Rules redirect downloads from particular hosts or prefixes to other URLs,
+//! e.g. Mojang, Maven or IPFS URLs to internal mirrors, without editing
+//! manifests. They are set with [`NetClient::set_rewrite_rules`] or
+//! [`NetConfig::rewrite_rules`](super::net_config::NetConfig::rewrite_rules).
+//!
+//! IPFS paths, like artifact paths, are matched as `ipfs://{path}`. The
+//! rewritten URL of an IPFS path is tried before the local IPFS node and
+//! gateways, which are still used if it fails, and the file is verified
+//! against its CID as if it came from a gateway. CAR archives of
+//! [`Storage::download_car`](crate::storage::Storage::download_car) are
+//! requested from the rewritten URL with `?format=car`, but only by rules
+//! which don't need the SHA-1 hash, because it's not known for a DAG.
+//!
+//! Pinning on the local IPFS node, see
+//! [`NetClient::pin_ipfs`](super::net::NetClient::pin_ipfs), ignores the
+//! rules, because the node fetches content from the IPFS network itself.
+//!
+//! # Examples
+//!
+//! ```
+//! use firelaunch::utils::net::NetClient;
+//! use firelaunch::utils::rewrite::RewriteRule;
+//!
+//! let mut client = NetClient::new();
+//! client.set_rewrite_rules(vec![
+//!     RewriteRule::new("https://libraries.minecraft.net/", "https://maven.example.com/{path}"),
+//!     RewriteRule::new("ipfs://", "https://ipfs.example.com/ipfs/{path}").require_sha1(),
+//! ]);
+//! assert_eq!(
+//!     client.resolve("https://libraries.minecraft.net/a/b.jar", None),
+//!     "https://maven.example.com/a/b.jar"
+//! );
+//! // Mirror of IPFS content is used only for verified downloads
+//! assert_eq!(client.resolve("CID", None), "CID");
+//! assert_eq!(
+//!     client.resolve("CID", Some("0123")),
+//!     "https://ipfs.example.com/ipfs/CID"
+//! );
+//! ```
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::net::NetClient;
+
+/// Scheme of IPFS paths matched by rules.
+const IPFS_SCHEME: &str = "ipfs://";
+
+/// URL rewrite rule.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RewriteRule {
+	/// Prefix of URLs the rule applies to, like `https://libraries.minecraft.net/`.
+	pub prefix: String,
+	/// Replacement URL template.
+	///
+	/// `{path}` is replaced with the rest of the URL after the prefix, and
+	/// `{sha1}` with the expected SHA-1 hash of the file.
+	pub target: String,
+	/// Apply the rule only to downloads with known SHA-1 hash, which are
+	/// verified after downloading.
+	#[serde(default)]
+	pub require_sha1: bool,
+}
+
+impl RewriteRule {
+	/// Creates a rule replacing `prefix` with `target`.
+	pub fn new(prefix: &str, target: &str) -> Self {
+		Self {
+			prefix: prefix.to_string(),
+			target: target.to_string(),
+			require_sha1: false,
+		}
+	}
+
+	/// Apply the rule only to downloads with known SHA-1 hash.
+	pub fn require_sha1(mut self) -> Self {
+		self.require_sha1 = true;
+		self
+	}
+
+	/// Rewrite the URL, or return `None` if the rule doesn't apply to it.
+	///
+	/// Rules which use `{sha1}` also don't apply if the hash is unknown.
+	pub fn apply(&self, url: &str, sha1: Option<&str>) -> Option<String> {
+		let path = url.strip_prefix(&self.prefix)?;
+		let target = self.target.replace("{path}", path);
+		match sha1 {
+			Some(sha1) => Some(target.replace("{sha1}", sha1)),
+			None if self.require_sha1 || target.contains("{sha1}") => None,
+			None => Some(target),
+		}
+	}
+}
+
+impl NetClient {
+	/// Sets URL rewrite rules, in order of precedence.
+	///
+	/// Rules are applied to URLs of downloads made with methods of this
+	/// client, and to IPFS paths of objects downloaded by
+	/// [`Storage`](crate::storage::Storage). See [`NetClient::resolve`].
+	pub fn set_rewrite_rules(&mut self, rules: Vec<RewriteRule>) {
+		self.rewrite_rules = Arc::new(rules);
+	}
+
+	/// Returns URL rewrite rules.
+	#[inline]
+	pub fn rewrite_rules(&self) -> &[RewriteRule] {
+		&self.rewrite_rules
+	}
+
+	/// Resolve the URL or IPFS path of a download with rewrite rules.
+	///
+	/// The first rule which applies is used, see [`RewriteRule::apply`].
+	/// `path` is returned as is if there is no such rule.
+	pub fn resolve(&self, path: &str, sha1: Option<&str>) -> String {
+		let url = match path.contains("://") {
+			true => path.to_string(),
+			false => format!("{IPFS_SCHEME}{path}"),
+		};
+		match self
+			.rewrite_rules()
+			.iter()
+			.find_map(|rule| rule.apply(&url, sha1))
+		{
+			Some(target) => {
+				debug!("Rewrote {} to {}", path, target);
+				target
+			}
+			None => path.to_string(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::tests::temp_storage_with_client;
+	use crate::utils::car::tests::{car, cid};
+	use crate::utils::cid::RAW;
+	use crate::utils::crypto::{sha1_digest, ContentHash};
+	use crate::utils::test_server::{Route, TestServer};
+
+	#[test]
+	fn test_rewrite_rule() {
+		let rule = RewriteRule::new("https://example.com/", "https://mirror/{sha1}/{path}");
+		assert_eq!(
+			rule.apply("https://example.com/a/b", Some("01")).as_deref(),
+			Some("https://mirror/01/a/b")
+		);
+		assert_eq!(rule.apply("https://example.com/a/b", None), None);
+		assert_eq!(rule.apply("https://example.org/a/b", Some("01")), None);
+
+		let rule = RewriteRule::new("ipfs://", "https://mirror/ipfs/{path}");
+		assert_eq!(
+			rule.apply("ipfs://CID/file", None).as_deref(),
+			Some("https://mirror/ipfs/CID/file")
+		);
+		assert_eq!(rule.clone().require_sha1().apply("ipfs://CID", None), None);
+	}
+
+	#[tokio::test]
+	async fn test_rewrite_download() {
+		let gateway = TestServer::start().await;
+		let mirror = TestServer::start().await;
+		mirror.route("/ipfs/CID", Route::ok(b"mirrored"));
+		mirror.route("/maven/lib.jar", Route::ok(b"library"));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&gateway.url("/ipfs/"));
+		client.set_rewrite_rules(vec![
+			RewriteRule::new("ipfs://", &mirror.url("/ipfs/{path}")).require_sha1(),
+			RewriteRule::new("https://maven.invalid/", &mirror.url("/maven/{path}")),
+		]);
+		let (_dir, storage) = temp_storage_with_client(client);
+
+		let hash = sha1_digest(b"mirrored");
+		storage.download_asset(&hash, "CID", None).await.unwrap();
+		let hash = sha1_digest(b"library");
+		storage
+			.download_asset(&hash, "https://maven.invalid/lib.jar", Some(7))
+			.await
+			.unwrap();
+		assert_eq!(mirror.requests().len(), 2);
+		assert!(gateway.requests().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_rewrite_failover() {
+		let gateway = TestServer::start().await;
+		let mirror = TestServer::start().await;
+		let content = b"content".to_vec();
+		let root = cid(RAW, &content);
+		mirror.route(&format!("/ipfs/{root}"), Route::ok(b"corrupted"));
+		gateway.route(&format!("/ipfs/{root}"), Route::ok(&content));
+		let mut client = NetClient::new();
+		client.set_ipfs_gateway(&gateway.url("/ipfs/"));
+		client.set_rewrite_rules(vec![RewriteRule::new(
+			"ipfs://",
+			&mirror.url("/ipfs/{path}"),
+		)]);
+
+		// Mirror sent a wrong file, which doesn't match the CID
+		let data = client
+			.download_ipfs_bytes(&root.to_string(), None, None)
+			.await
+			.unwrap();
+		assert_eq!(data, content);
+		assert_eq!(mirror.requests().len(), 1);
+		assert_eq!(gateway.requests().len(), 1);
+
+		// CAR archives are requested from the mirror too
+		let archive = car(&root, &[(root.clone(), content.clone())]);
+		mirror.route(&format!("/ipfs/{root}?format=car"), Route::ok(&archive));
+		let (_dir, storage) = temp_storage_with_client(client);
+		let report = storage.download_car(&root.to_string()).await.unwrap();
+		assert_eq!(
+			report.imported,
+			vec![ContentHash::sha1(&sha1_digest(&content))]
+		);
+		assert_eq!(mirror.requests().len(), 2);
+		assert_eq!(gateway.requests().len(), 1);
+	}
+}
@@ -0,0 +1,179 @@
+//! Minimal HTTP server for tests.
+//!
+//! It serves static routes from memory, so network code can be tested
+//! without access to the Internet.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Route served by [`TestServer`].
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+	/// Response status code.
+	pub status: u16,
+	/// Additional response headers.
+	pub headers: Vec<(String, String)>,
+	/// Response body.
+	pub body: Vec<u8>,
+	/// Whether `Range` requests are supported.
+	pub ranges: bool,
+}
+
+impl Route {
+	/// Route which responds with `200 OK` and the given body.
+	pub fn ok(body: &[u8]) -> Self {
+		Self {
+			status: 200,
+			headers: Vec::new(),
+			body: body.to_vec(),
+			ranges: false,
+		}
+	}
+
+	/// Route which responds with the given status and empty body.
+	pub fn status(status: u16) -> Self {
+		Self {
+			status,
+			..Self::ok(b"")
+		}
+	}
+}
+
+/// Request received by [`TestServer`].
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+	/// Request method.
+	pub method: String,
+	/// Request path with query.
+	pub path: String,
+	/// Request headers with lowercase names.
+	pub headers: HashMap<String, String>,
+}
+
+/// Minimal HTTP/1.1 server for tests.
+pub(crate) struct TestServer {
+	addr: SocketAddr,
+	routes: Arc<Mutex<HashMap<String, Route>>>,
+	requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+	/// Start the server on a random local port.
+	pub async fn start() -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let server = Self {
+			addr: listener.local_addr().unwrap(),
+			routes: Arc::new(Mutex::new(HashMap::new())),
+			requests: Arc::new(Mutex::new(Vec::new())),
+		};
+		let routes = server.routes.clone();
+		let requests = server.requests.clone();
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				tokio::spawn(handle(stream, routes.clone(), requests.clone()));
+			}
+		});
+		server
+	}
+
+	/// Get URL of the given path on this server.
+	pub fn url(&self, path: &str) -> String {
+		format!("http://{}{path}", self.addr)
+	}
+
+	/// Add route.
+	pub fn route(&self, path: &str, route: Route) {
+		self.routes.lock().unwrap().insert(path.to_string(), route);
+	}
+
+	/// Get all received requests.
+	pub fn requests(&self) -> Vec<Request> {
+		self.requests.lock().unwrap().clone()
+	}
+}
+
+async fn handle(
+	mut stream: TcpStream,
+	routes: Arc<Mutex<HashMap<String, Route>>>,
+	requests: Arc<Mutex<Vec<Request>>>,
+) {
+	let mut buffer = Vec::new();
+	let mut chunk = [0; 1024];
+	let header_end = loop {
+		let n = match stream.read(&mut chunk).await {
+			Ok(0) | Err(_) => return,
+			Ok(n) => n,
+		};
+		buffer.extend_from_slice(&chunk[..n]);
+		if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+			break pos;
+		}
+	};
+	let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+	let mut lines = head.lines();
+	let mut request_line = lines.next().unwrap_or_default().split(' ');
+	let request = Request {
+		method: request_line.next().unwrap_or_default().to_string(),
+		path: request_line.next().unwrap_or_default().to_string(),
+		headers: lines
+			.filter_map(|line| line.split_once(':'))
+			.map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+			.collect(),
+	};
+	requests.lock().unwrap().push(request.clone());
+
+	let route = routes
+		.lock()
+		.unwrap()
+		.get(&request.path)
+		.cloned()
+		.unwrap_or_else(|| Route::status(404));
+
+	let mut status = route.status;
+	let mut headers = route.headers;
+	let mut body = route.body;
+	let etag = headers
+		.iter()
+		.find(|(name, _)| name.eq_ignore_ascii_case("etag"))
+		.map(|(_, value)| value.clone());
+	if etag.is_some() && request.headers.get("if-none-match") == etag.as_ref() {
+		status = 304;
+		body = Vec::new();
+	}
+	if let Some(range) = request.headers.get("range").filter(|_| route.ranges) {
+		let start: usize = range
+			.trim_start_matches("bytes=")
+			.trim_end_matches('-')
+			.parse()
+			.unwrap();
+		if start >= body.len() {
+			status = 416;
+			body = Vec::new();
+		} else {
+			status = 206;
+			headers.push((
+				"Content-Range".to_string(),
+				format!("bytes {start}-{}/{}", body.len() - 1, body.len()),
+			));
+			body = body[start..].to_vec();
+		}
+	}
+
+	let mut response = format!(
+		"HTTP/1.1 {status} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+		body.len()
+	);
+	for (name, value) in headers {
+		response.push_str(&format!("{name}: {value}\r\n"));
+	}
+	response.push_str("\r\n");
+	let _ = stream.write_all(response.as_bytes()).await;
+	let _ = stream.write_all(&body).await;
+	let _ = stream.shutdown().await;
+}